@@ -0,0 +1,42 @@
+use std::time::Duration;
+
+/// The outcome of a flush.
+#[derive(Clone, Debug, Default)]
+pub struct AckSummary {
+    pub emitted_events: usize,
+    pub deleted_messages: usize,
+    /// Messages whose delete failed, by message id
+    pub failed_messages: Vec<String>,
+    /// Identities that could not be stored in the cache after retrying, so their
+    /// events may be emitted again if redelivered
+    pub failed_cache_identities: Vec<Vec<u8>>,
+    /// The longest time since a flushed message was first received, for messages
+    /// received with the `ApproximateFirstReceiveTimestamp` attribute
+    pub max_time_since_first_receive: Option<Duration>,
+    /// The phase the flush was in when its ack deadline passed. The phases after it
+    /// were skipped, and whatever they hadn't done is retained for the next flush.
+    pub timed_out: Option<AckPhase>,
+}
+
+/// A phase of a flush, in the order they run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AckPhase {
+    /// Serializing and emitting the buffered events
+    Emit,
+    /// Storing identities in the cache
+    Cache,
+    /// Deleting the completed messages
+    Delete,
+}
+
+/// The outcome of the final flush performed by `shutdown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownSummary {
+    Flushed,
+    /// The flush did not complete within the shutdown timeout. What it hadn't
+    /// emitted or deleted by then is still buffered, and is counted here.
+    TimedOut {
+        lost_events: usize,
+        lost_messages: usize,
+    },
+}
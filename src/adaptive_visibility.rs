@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Learns how long messages take to process and picks a visibility timeout to
+/// match, so that slow messages aren't redelivered mid-processing and fast ones
+/// aren't held invisible for longer than necessary.
+///
+/// The target is the configured percentile of the most recent observations, clamped
+/// to `[min, max]`. Until `min_samples` durations have been observed `initial` is used.
+#[derive(Clone, Debug)]
+pub struct AdaptiveVisibility {
+    percentile: f64,
+    initial: Duration,
+    min: Duration,
+    max: Duration,
+    window: usize,
+    min_samples: usize,
+    observed: VecDeque<Duration>,
+}
+
+impl AdaptiveVisibility {
+    /// `percentile` is in `0.0..=100.0`, eg: `99.0` to cover all but the slowest 1%
+    /// of messages.
+    pub fn new(percentile: f64, initial: Duration) -> Self {
+        Self {
+            percentile: percentile.max(0.0).min(100.0),
+            initial,
+            min: Duration::from_secs(1),
+            // The longest visibility timeout SQS accepts
+            max: Duration::from_secs(12 * 60 * 60),
+            window: 1000,
+            min_samples: 10,
+            observed: VecDeque::new(),
+        }
+    }
+
+    pub fn bounds(mut self, min: Duration, max: Duration) -> Self {
+        self.min = min;
+        self.max = max.max(min);
+        self
+    }
+
+    /// How many of the most recent durations the percentile is computed over.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    pub fn min_samples(mut self, min_samples: usize) -> Self {
+        self.min_samples = min_samples;
+        self
+    }
+
+    /// Records that a message took `duration` from receipt to completion.
+    pub fn observe(&mut self, duration: Duration) {
+        if self.observed.len() == self.window {
+            self.observed.pop_front();
+        }
+        self.observed.push_back(duration);
+    }
+
+    /// The configured percentile of the observed durations, by nearest rank.
+    pub fn percentile(&self) -> Option<Duration> {
+        if self.observed.is_empty() || self.observed.len() < self.min_samples {
+            return None;
+        }
+
+        let mut observed: Vec<Duration> = self.observed.iter().copied().collect();
+        observed.sort();
+        let rank = ((self.percentile / 100.0) * observed.len() as f64).ceil() as usize;
+        Some(observed[rank.max(1) - 1])
+    }
+
+    /// The visibility timeout to extend messages to.
+    pub fn target(&self) -> Duration {
+        self.percentile()
+            .unwrap_or(self.initial)
+            .max(self.min)
+            .min(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observing(mut visibility: AdaptiveVisibility, secs: &[u64]) -> AdaptiveVisibility {
+        for secs in secs {
+            visibility.observe(Duration::from_secs(*secs));
+        }
+        visibility
+    }
+
+    #[test]
+    fn targets_the_percentile_of_observed_durations() {
+        let visibility = AdaptiveVisibility::new(90.0, Duration::from_secs(30)).min_samples(5);
+        let visibility = observing(visibility, &[7, 3, 10, 1, 5, 2, 9, 4, 8, 6]);
+
+        assert_eq!(visibility.percentile(), Some(Duration::from_secs(9)));
+        assert_eq!(visibility.target(), Duration::from_secs(9));
+    }
+
+    #[test]
+    fn the_initial_target_holds_until_enough_samples() {
+        let visibility = AdaptiveVisibility::new(50.0, Duration::from_secs(30)).min_samples(3);
+        let visibility = observing(visibility, &[5, 5]);
+        assert_eq!(visibility.percentile(), None);
+        assert_eq!(visibility.target(), Duration::from_secs(30));
+
+        let visibility = observing(visibility, &[5]);
+        assert_eq!(visibility.target(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn targets_are_clamped_and_windowed() {
+        let visibility = AdaptiveVisibility::new(100.0, Duration::from_secs(30))
+            .min_samples(1)
+            .bounds(Duration::from_secs(2), Duration::from_secs(60))
+            .window(2);
+
+        assert_eq!(observing(visibility.clone(), &[1]).target(), Duration::from_secs(2));
+        assert_eq!(observing(visibility.clone(), &[90]).target(), Duration::from_secs(60));
+        // The slowest observation falls out of the window
+        assert_eq!(observing(visibility, &[40, 10, 20]).target(), Duration::from_secs(20));
+    }
+}
@@ -0,0 +1,19 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+/// What happened to a message when the handler tried to acknowledge it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AckOutcome {
+    /// The message was deleted from its queue
+    Deleted,
+    /// The message could not be deleted, and will be redelivered
+    DeleteFailed,
+}
+
+/// Receives a record of every message a flush tries to acknowledge, successful or
+/// not, eg: to keep an audit trail for compliance. Unlike `HandlerStats` nothing is
+/// aggregated, each message is recorded exactly once per flush.
+#[async_trait]
+pub trait AuditSink {
+    async fn record(&self, message_id: &str, outcome: AckOutcome, timestamp: DateTime<Utc>);
+}
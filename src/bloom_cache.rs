@@ -0,0 +1,163 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::cache::{Cache, CacheResponse, Cacheable};
+
+/// A fixed-size, in-memory bloom filter cache.
+///
+/// Memory is bounded regardless of how many identities are stored, at the cost of
+/// false positives: an identity that was never stored may be reported as a `Hit`.
+/// When used for deduplication this means a small fraction of events will be
+/// dropped as duplicates when they are not. Size the filter with the number of
+/// identities you expect to store and the false positive rate you can tolerate.
+/// There are no false negatives.
+///
+/// Clones share the same underlying filter.
+#[derive(Clone)]
+pub struct BloomCache {
+    bits: Arc<Vec<AtomicU64>>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomCache {
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1) as f64;
+        let false_positive_rate = false_positive_rate.max(f64::MIN_POSITIVE).min(1.0);
+        let ln2 = std::f64::consts::LN_2;
+
+        let num_bits = (-expected_items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+
+        let words = ((num_bits + 63) / 64) as usize;
+        Self {
+            bits: Arc::new((0..words).map(|_| AtomicU64::new(0)).collect()),
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn contains(&self, identity: &[u8]) -> bool {
+        self.bit_indexes(identity).all(|bit| {
+            let word = self.bits[(bit / 64) as usize].load(Ordering::Relaxed);
+            word & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn insert(&self, identity: &[u8]) {
+        for bit in self.bit_indexes(identity) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    // Double hashing: the i'th index is h1 + i * h2, which behaves like k
+    // independent hash functions.
+    fn bit_indexes<'a>(&'a self, identity: &[u8]) -> impl Iterator<Item = u64> + 'a {
+        let h1 = seeded_hash(0, identity);
+        let h2 = seeded_hash(1, identity) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+fn seeded_hash(seed: u64, identity: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    identity.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[async_trait]
+impl Cache for BloomCache
+{
+    async fn get<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<CacheResponse, crate::error::Error> {
+        if self.contains(&cacheable.identity()) {
+            Ok(CacheResponse::Hit)
+        } else {
+            Ok(CacheResponse::Miss)
+        }
+    }
+
+    async fn store(&mut self, identity: Vec<u8>) -> Result<(), crate::error::Error> {
+        self.insert(&identity);
+        Ok(())
+    }
+
+    async fn exists<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<bool, crate::error::Error> {
+        Ok(self.contains(&cacheable.identity()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Identity;
+
+    fn identity(i: u32) -> Vec<u8> {
+        format!("identity-{}", i).into_bytes()
+    }
+
+    #[tokio::test]
+    async fn stored_identities_are_present() {
+        let mut cache = BloomCache::new(1000, 0.01);
+        for i in 0..1000 {
+            cache.store(identity(i)).await.unwrap();
+        }
+
+        for i in 0..1000 {
+            match cache.get(Identity(identity(i))).await.unwrap() {
+                CacheResponse::Hit => (),
+                CacheResponse::Miss => panic!("Stored identity {} reported missing", i),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stored_identities_exist() {
+        let mut cache = BloomCache::new(100, 0.01);
+        cache.store(identity(1)).await.unwrap();
+
+        assert!(cache.exists(Identity(identity(1))).await.unwrap());
+        assert!(!cache.exists(Identity(identity(2))).await.unwrap());
+    }
+
+    #[test]
+    fn false_positive_rate_stays_within_bounds() {
+        let cache = BloomCache::new(1000, 0.01);
+        for i in 0..1000 {
+            cache.insert(&identity(i));
+        }
+
+        let samples = 10_000;
+        let false_positives = (1000..1000 + samples)
+            .filter(|i| cache.contains(&identity(*i)))
+            .count();
+        assert!(
+            false_positives as f64 / samples as f64 <= 0.03,
+            "{} false positives in {} samples",
+            false_positives,
+            samples
+        );
+    }
+
+    #[test]
+    fn clones_share_the_filter() {
+        let cache = BloomCache::new(100, 0.01);
+        cache.clone().insert(b"shared");
+
+        assert!(cache.contains(b"shared"));
+    }
+}
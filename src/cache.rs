@@ -20,6 +20,18 @@ where
     }
 }
 
+/// An identity that has already been computed, eg: one taken from
+/// `OutputEvent::identities`. Looking it up uses the bytes as-is rather than hashing
+/// them a second time.
+#[derive(Clone, Debug)]
+pub struct Identity(pub Vec<u8>);
+
+impl Cacheable for Identity {
+    fn identity(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
 #[derive(Clone)]
 pub enum CacheResponse {
     Hit,
@@ -34,6 +46,21 @@ pub trait Cache: Clone
         cacheable: CA,
     ) -> Result<CacheResponse, crate::error::Error>;
     async fn store(&mut self, identity: Vec<u8>) -> Result<(), crate::error::Error>;
+
+    /// Whether `cacheable` has been stored. By default this is a `get`, caches with a
+    /// cheaper membership check should override it.
+    async fn exists<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<bool, crate::error::Error>
+    where
+        Self: Send,
+    {
+        match self.get(cacheable).await? {
+            CacheResponse::Hit => Ok(true),
+            CacheResponse::Miss => Ok(false),
+        }
+    }
 }
 
 #[async_trait]
@@ -43,6 +70,11 @@ pub trait ReadableCache
         &mut self,
         cacheable: CA,
     ) -> Result<CacheResponse, crate::error::Error>;
+
+    async fn exists<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<bool, crate::error::Error>;
 }
 
 #[async_trait]
@@ -56,6 +88,13 @@ where
     {
         Cache::get(self, cacheable).await
     }
+
+    async fn exists<CA>(&mut self, cacheable: CA) -> Result<bool, crate::error::Error>
+    where
+        CA: Cacheable + Send + Sync + 'static,
+    {
+        Cache::exists(self, cacheable).await
+    }
 }
 
 #[derive(Clone)]
@@ -1,3 +1,39 @@
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SerializeToError<E>
+where
+    E: Debug,
+{
+    #[error("SerializeError: {0:?}")]
+    Serialize(E),
+    #[error("IoError: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Names one of the serializers registered with a completion handler, see
+/// `SqsCompletionHandler::with_serializer`.
+pub type SerializerId = String;
+
+/// What is known about where a completed event came from.
+#[derive(Clone, Debug, Default)]
+pub struct EventMeta {
+    /// The id of the SQS message the event was processed from, None for `Partial`
+    /// completions
+    pub source_message_id: Option<String>,
+    /// The body of the source message, only retained when the handler is configured
+    /// with `retain_source_body`
+    pub source_body: Option<String>,
+    /// The handler's configured schema version, for serializers that embed it
+    pub schema_version: Option<u32>,
+}
+
+#[async_trait]
 pub trait CompletionEventSerializer {
     type CompletedEvent;
     type Output;
@@ -6,4 +42,110 @@ pub trait CompletionEventSerializer {
         &mut self,
         completed_events: &[Self::CompletedEvent],
     ) -> Result<Vec<Self::Output>, Self::Error>;
+
+    /// Like `serialize_completed_events`, where `meta[i]` describes
+    /// `completed_events[i]`. By default the metadata is ignored.
+    fn serialize_completed_events_with_meta(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+        meta: &[EventMeta],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        let _ = meta;
+        self.serialize_completed_events(completed_events)
+    }
+
+    /// Serializes a single event independently of the rest of its batch, letting the
+    /// handler serialize a batch's events in parallel. Serializers that support this
+    /// should override it, by default it returns None and batches are serialized
+    /// sequentially.
+    fn serialize_one(
+        &self,
+        completed_event: &Self::CompletedEvent,
+    ) -> Option<Result<Self::Output, Self::Error>> {
+        let _ = completed_event;
+        None
+    }
+
+    /// Combines the outputs of `serialize_one`, in the order of the events they were
+    /// serialized from, into the batch's output. By default each event's output is
+    /// emitted as is.
+    fn combine(&self, outputs: Vec<Self::Output>) -> Vec<Self::Output> {
+        outputs
+    }
+
+    /// The total size in bytes of `completed_events` once serialized. By default this
+    /// serializes the events and measures the output, serializers that can compute
+    /// the size more cheaply should override it.
+    fn serialized_size(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<usize, Self::Error>
+    where
+        Self::Output: AsRef<[u8]>,
+    {
+        let serialized = self.serialize_completed_events(completed_events)?;
+        Ok(serialized.iter().map(|output| output.as_ref().len()).sum())
+    }
+
+    /// Serializes `completed_events` into `writer`. By default the whole batch is
+    /// serialized in memory and then written, serializers that can produce their
+    /// output incrementally should override it to avoid the intermediate allocation.
+    async fn serialize_to<W>(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+        writer: &mut W,
+    ) -> Result<(), SerializeToError<Self::Error>>
+    where
+        W: AsyncWrite + Unpin + Send,
+        Self: Send + Sized,
+        Self::CompletedEvent: Sync,
+        Self::Output: AsRef<[u8]> + Send,
+        Self::Error: Debug,
+    {
+        let serialized = self
+            .serialize_completed_events(completed_events)
+            .map_err(SerializeToError::Serialize)?;
+
+        for output in serialized {
+            writer.write_all(output.as_ref()).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Serializes every batch to nothing, for handlers that never emit, see
+/// `SqsCompletionHandler::new_without_emitter`.
+pub struct NopSerializer<CE>(PhantomData<fn() -> CE>);
+
+impl<CE> Default for NopSerializer<CE> {
+    fn default() -> Self {
+        NopSerializer(PhantomData)
+    }
+}
+
+impl<CE> CompletionEventSerializer for NopSerializer<CE> {
+    type CompletedEvent = CE;
+    type Output = Vec<u8>;
+    type Error = Infallible;
+
+    fn serialize_completed_events(
+        &mut self,
+        _completed_events: &[Self::CompletedEvent],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::StringSerializer;
+
+    #[test]
+    fn serialized_size_measures_the_output_by_default() {
+        let events = vec!["abc".to_owned(), "de".to_owned()];
+
+        assert_eq!(StringSerializer.serialized_size(&events), Ok(5));
+    }
 }
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
 
 #[async_trait]
@@ -9,3 +11,112 @@ pub trait CompletionHandler {
     async fn ack_message(&self, msg: Self::Message);
     async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>);
 }
+
+/// A type-erased `CompletionHandler`, for storing handlers of different concrete
+/// types together or behind `dyn`. Clones share the same handler.
+pub struct DynCompletionHandler<M, CE> {
+    inner: Arc<dyn CompletionHandler<Message = M, CompletedEvent = CE> + Send + Sync>,
+}
+
+impl<M, CE> DynCompletionHandler<M, CE> {
+    pub fn new(
+        handler: impl CompletionHandler<Message = M, CompletedEvent = CE> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(handler),
+        }
+    }
+}
+
+impl<M, CE> Clone for DynCompletionHandler<M, CE> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<M, CE> CompletionHandler for DynCompletionHandler<M, CE>
+where
+    M: Send + 'static,
+    CE: Send + 'static,
+{
+    type Message = M;
+    type CompletedEvent = CE;
+
+    async fn mark_complete(&self, msg: Self::Message, completed_event: Self::CompletedEvent) {
+        self.inner.mark_complete(msg, completed_event).await
+    }
+
+    async fn ack_message(&self, msg: Self::Message) {
+        self.inner.ack_message(msg).await
+    }
+
+    async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
+        self.inner.ack_all(notify).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Counts the acks it is asked for.
+    #[derive(Clone, Default)]
+    struct CountingHandler {
+        acks: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl CompletionHandler for CountingHandler {
+        type Message = String;
+        type CompletedEvent = String;
+
+        async fn mark_complete(&self, _msg: String, _completed_event: String) {}
+
+        async fn ack_message(&self, _msg: String) {
+            self.acks.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
+            self.acks.fetch_add(1, Ordering::SeqCst);
+            if let Some(notify) = notify {
+                let _ = notify.send(());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_can_be_held_behind_dyn() {
+        let counting = CountingHandler::default();
+        let erased = CountingHandler::default();
+        let handlers: Vec<Box<dyn CompletionHandler<Message = String, CompletedEvent = String> + Send + Sync>> =
+            vec![
+                Box::new(counting.clone()),
+                Box::new(DynCompletionHandler::new(erased.clone())),
+            ];
+
+        for handler in &handlers {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            handler.ack_all(Some(tx)).await;
+            rx.await.unwrap();
+        }
+
+        assert_eq!(counting.acks.load(Ordering::SeqCst), 1);
+        assert_eq!(erased.acks.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_handler() {
+        let counting = CountingHandler::default();
+        let handler = DynCompletionHandler::new(counting.clone());
+
+        handler.clone().ack_message("1".to_owned()).await;
+        handler.ack_message("2".to_owned()).await;
+
+        assert_eq!(counting.acks.load(Ordering::SeqCst), 2);
+    }
+}
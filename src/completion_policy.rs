@@ -0,0 +1,353 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// When a `CompletionPolicy` flushes based on time.
+#[derive(Clone, Copy, Debug)]
+pub enum FlushSchedule {
+    /// Flush once this much time has passed since the last flush.
+    Interval(Duration),
+    /// Flush whenever the wall clock crosses a multiple of this duration since the
+    /// unix epoch, eg: `Duration::from_secs(60)` flushes at the top of every minute.
+    WallClockBoundary(Duration),
+}
+
+/// Bounds for a `CompletionPolicy` that sizes batches from downstream feedback.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveBatching {
+    min_messages: u16,
+    max_messages: u16,
+    target_latency: Duration,
+}
+
+impl AdaptiveBatching {
+    /// Emits that needed a retry, or took longer than `target_latency`, are treated as
+    /// backpressure.
+    pub fn new(min_messages: u16, max_messages: u16, target_latency: Duration) -> Self {
+        Self {
+            min_messages: min_messages.max(1),
+            max_messages: max_messages.max(min_messages.max(1)),
+            target_latency,
+        }
+    }
+}
+
+/// Ramps a `CompletionPolicy`'s batch size up from `initial`, multiplying it by
+/// `factor` after each flush until it reaches `max_messages`, so that a cold handler
+/// doesn't hit a freshly scaled downstream with full batches.
+#[derive(Clone, Copy, Debug)]
+pub struct WarmupConfig {
+    pub initial: u16,
+    pub factor: f64,
+}
+
+impl WarmupConfig {
+    pub fn new(initial: u16, factor: f64) -> Self {
+        Self {
+            initial: initial.max(1),
+            factor,
+        }
+    }
+}
+
+pub struct CompletionPolicy {
+    max_messages: u16,
+    schedule: FlushSchedule,
+    last_flush: Instant,
+    last_flush_wall: SystemTime,
+    adaptive: Option<AdaptiveBatching>,
+    min_interval_between_flushes: Option<Duration>,
+    warmup: Option<WarmupConfig>,
+    // The warmup's current batch size, None once warmed up
+    warmup_threshold: Option<u16>,
+}
+
+/// Whether a multiple of `boundary` since the unix epoch lies between `last_flush`
+/// and `now`.
+fn boundary_crossed(boundary: Duration, last_flush: SystemTime, now: SystemTime) -> bool {
+    let boundary = boundary.as_millis().max(1);
+    let since_epoch = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .expect("SystemTime before UNIX EPOCH!")
+            .as_millis()
+    };
+
+    since_epoch(now) / boundary > since_epoch(last_flush) / boundary
+}
+
+impl CompletionPolicy {
+    pub fn new(max_messages: u16, max_time_between_flushes: Duration) -> Self {
+        Self::with_schedule(max_messages, FlushSchedule::Interval(max_time_between_flushes))
+    }
+
+    pub fn with_schedule(max_messages: u16, schedule: FlushSchedule) -> Self {
+        Self {
+            max_messages,
+            schedule,
+            last_flush: Instant::now(),
+            last_flush_wall: SystemTime::now(),
+            adaptive: None,
+            min_interval_between_flushes: None,
+            warmup: None,
+            warmup_threshold: None,
+        }
+    }
+
+    /// Starts with batches of `warmup.initial` messages, growing to `max_messages`
+    /// over the first few flushes.
+    pub fn warmup(mut self, warmup: WarmupConfig) -> Self {
+        self.warmup = Some(warmup);
+        self.warmup_threshold = Some(warmup.initial.min(self.max_messages));
+        self
+    }
+
+    /// Holds off count-triggered flushes until `min_interval` has passed since the
+    /// last flush, so that a small `max_messages` can't flush on every completion.
+    pub fn min_interval_between_flushes(mut self, min_interval: Duration) -> Self {
+        self.min_interval_between_flushes = Some(min_interval);
+        self
+    }
+
+    /// Starts flushing at `adaptive`'s maximum batch size, halving it whenever the
+    /// downstream pushes back and growing it again while emits are healthy.
+    pub fn adaptive(adaptive: AdaptiveBatching, schedule: FlushSchedule) -> Self {
+        Self {
+            adaptive: Some(adaptive),
+            ..Self::with_schedule(adaptive.max_messages, schedule)
+        }
+    }
+
+    /// The current batch size, which moves within bounds for an adaptive policy and
+    /// is reduced while warming up.
+    pub fn max_messages(&self) -> u16 {
+        match self.warmup_threshold {
+            Some(warmup_threshold) => warmup_threshold.min(self.max_messages),
+            None => self.max_messages,
+        }
+    }
+
+    /// Feedback from an emit. `throttled` should be true if the emit failed or had
+    /// to be retried.
+    pub fn record_emit(&mut self, throttled: bool, latency: Duration) {
+        let adaptive = match self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+
+        if throttled || latency > adaptive.target_latency {
+            self.max_messages = (self.max_messages / 2).max(adaptive.min_messages);
+        } else {
+            let step = (self.max_messages / 10).max(1);
+            self.max_messages = self
+                .max_messages
+                .saturating_add(step)
+                .min(adaptive.max_messages);
+        }
+    }
+
+    pub fn should_flush(&self, cur_messages: u16) -> bool {
+        (cur_messages >= self.max_messages() && self.min_interval_elapsed())
+            || self.schedule_elapsed()
+    }
+
+    fn min_interval_elapsed(&self) -> bool {
+        match self.min_interval_between_flushes {
+            Some(min_interval) => self.last_flush.elapsed() >= min_interval,
+            None => true,
+        }
+    }
+
+    fn schedule_elapsed(&self) -> bool {
+        match self.schedule {
+            FlushSchedule::Interval(max_time_between_flushes) => {
+                Instant::now()
+                    .checked_duration_since(self.last_flush)
+                    .unwrap()
+                    >= max_time_between_flushes
+            }
+            FlushSchedule::WallClockBoundary(boundary) => {
+                boundary_crossed(boundary, self.last_flush_wall, SystemTime::now())
+            }
+        }
+    }
+
+    pub fn schedule(&self) -> FlushSchedule {
+        self.schedule
+    }
+
+    /// How long it has been since the last flush, or since the policy was created.
+    pub fn since_last_flush(&self) -> Duration {
+        self.last_flush.elapsed()
+    }
+
+    pub fn set_last_flush(&mut self) {
+        self.last_flush = Instant::now();
+        self.last_flush_wall = SystemTime::now();
+        self.advance_warmup();
+    }
+
+    /// Carries over when `previous` last flushed, so that replacing a policy doesn't
+    /// restart the flush interval.
+    pub(crate) fn inherit_last_flush(&mut self, previous: &CompletionPolicy) {
+        self.last_flush = previous.last_flush;
+        self.last_flush_wall = previous.last_flush_wall;
+    }
+
+    fn advance_warmup(&mut self) {
+        let (warmup, warmup_threshold) = match (self.warmup, self.warmup_threshold) {
+            (Some(warmup), Some(warmup_threshold)) => (warmup, warmup_threshold),
+            _ => return,
+        };
+
+        let next = (f64::from(warmup_threshold) * warmup.factor).ceil();
+        // Always grow by at least one message so that a factor <= 1 still warms up
+        let next = (next.min(f64::from(u16::MAX)) as u16).max(warmup_threshold.saturating_add(1));
+        self.warmup_threshold = if next >= self.max_messages {
+            None
+        } else {
+            Some(next)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn wall_clock_boundary_fires_when_crossed() {
+        let minute = Duration::from_secs(60);
+
+        assert!(boundary_crossed(minute, at(59_999), at(60_000)));
+        assert!(boundary_crossed(minute, at(119_999), at(120_001)));
+        assert!(!boundary_crossed(minute, at(60_000), at(119_999)));
+        assert!(!boundary_crossed(minute, at(60_000), at(60_000)));
+    }
+
+    #[test]
+    fn wall_clock_schedule_flushes_after_a_boundary() {
+        let mut policy =
+            CompletionPolicy::with_schedule(10, FlushSchedule::WallClockBoundary(Duration::from_secs(60)));
+        policy.last_flush_wall = SystemTime::now() - Duration::from_secs(60);
+
+        assert!(policy.should_flush(0));
+    }
+
+    #[test]
+    fn adaptive_batches_shrink_under_throttling() {
+        let mut policy = CompletionPolicy::adaptive(
+            AdaptiveBatching::new(4, 100, Duration::from_secs(1)),
+            FlushSchedule::Interval(Duration::from_secs(60)),
+        );
+        assert_eq!(policy.max_messages(), 100);
+
+        policy.record_emit(true, Duration::from_millis(10));
+        assert_eq!(policy.max_messages(), 50);
+        for _ in 0..10 {
+            policy.record_emit(true, Duration::from_millis(10));
+        }
+        assert_eq!(policy.max_messages(), 4);
+        assert!(policy.should_flush(4));
+        assert!(!policy.should_flush(3));
+    }
+
+    #[test]
+    fn adaptive_batches_treat_slow_emits_as_backpressure() {
+        let mut policy = CompletionPolicy::adaptive(
+            AdaptiveBatching::new(4, 100, Duration::from_millis(100)),
+            FlushSchedule::Interval(Duration::from_secs(60)),
+        );
+
+        policy.record_emit(false, Duration::from_secs(1));
+        assert_eq!(policy.max_messages(), 50);
+    }
+
+    #[test]
+    fn adaptive_batches_grow_back_while_healthy() {
+        let mut policy = CompletionPolicy::adaptive(
+            AdaptiveBatching::new(4, 20, Duration::from_secs(1)),
+            FlushSchedule::Interval(Duration::from_secs(60)),
+        );
+        for _ in 0..5 {
+            policy.record_emit(true, Duration::from_millis(10));
+        }
+        assert_eq!(policy.max_messages(), 4);
+
+        policy.record_emit(false, Duration::from_millis(10));
+        assert_eq!(policy.max_messages(), 5);
+        for _ in 0..100 {
+            policy.record_emit(false, Duration::from_millis(10));
+        }
+        assert_eq!(policy.max_messages(), 20);
+    }
+
+    #[test]
+    fn non_adaptive_policies_ignore_feedback() {
+        let mut policy = CompletionPolicy::new(10, Duration::from_secs(60));
+
+        policy.record_emit(true, Duration::from_secs(10));
+        assert_eq!(policy.max_messages(), 10);
+    }
+
+    #[test]
+    fn min_interval_holds_off_count_triggered_flushes() {
+        let mut policy = CompletionPolicy::new(1, Duration::from_secs(60))
+            .min_interval_between_flushes(Duration::from_millis(50));
+        policy.set_last_flush();
+
+        assert!(!policy.should_flush(5));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(policy.should_flush(1));
+        assert!(!policy.should_flush(0));
+    }
+
+    #[test]
+    fn min_interval_still_respects_the_schedule() {
+        let policy = CompletionPolicy::new(1, Duration::from_millis(10))
+            .min_interval_between_flushes(Duration::from_secs(60));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(policy.should_flush(0));
+    }
+
+    #[test]
+    fn warmup_ramps_up_to_max_messages() {
+        let mut policy =
+            CompletionPolicy::new(100, Duration::from_secs(60)).warmup(WarmupConfig::new(10, 2.0));
+
+        let mut thresholds = vec![policy.max_messages()];
+        for _ in 0..5 {
+            policy.set_last_flush();
+            thresholds.push(policy.max_messages());
+        }
+
+        assert_eq!(thresholds, vec![10, 20, 40, 80, 100, 100]);
+    }
+
+    #[test]
+    fn warmup_grows_with_a_factor_of_one() {
+        let mut policy =
+            CompletionPolicy::new(3, Duration::from_secs(60)).warmup(WarmupConfig::new(1, 1.0));
+
+        let mut thresholds = vec![policy.max_messages()];
+        for _ in 0..3 {
+            policy.set_last_flush();
+            thresholds.push(policy.max_messages());
+        }
+
+        assert_eq!(thresholds, vec![1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn replacement_policies_keep_the_last_flush() {
+        let previous = CompletionPolicy::new(10, Duration::from_secs(60));
+        std::thread::sleep(Duration::from_millis(20));
+
+        let mut updated = CompletionPolicy::new(2, Duration::from_secs(60));
+        updated.inherit_last_flush(&previous);
+
+        assert!(updated.since_last_flush() >= Duration::from_millis(20));
+    }
+}
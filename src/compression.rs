@@ -0,0 +1,221 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+
+use crate::event_emitter::{EmitMetadata, EmitReceipt, EventEmitter};
+
+/// An encoding a downstream may accept payloads in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// The encoding's `Content-Encoding` token.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Identity => Ok(payload.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+            ContentEncoding::Zstd => zstd::stream::encode_all(payload, 0),
+        }
+    }
+
+    // Higher compresses better
+    fn preference(&self) -> u8 {
+        match self {
+            ContentEncoding::Identity => 0,
+            ContentEncoding::Gzip => 1,
+            ContentEncoding::Zstd => 2,
+        }
+    }
+}
+
+/// Picks the best compression from the encodings a downstream accepts.
+#[derive(Clone, Debug)]
+pub struct CompressionNegotiator {
+    accepted: Vec<ContentEncoding>,
+}
+
+impl CompressionNegotiator {
+    pub fn new(accepted: impl Into<Vec<ContentEncoding>>) -> Self {
+        Self {
+            accepted: accepted.into(),
+        }
+    }
+
+    /// The best compressing accepted encoding, `Identity` if none are accepted.
+    pub fn choose(&self) -> ContentEncoding {
+        self.accepted
+            .iter()
+            .copied()
+            .max_by_key(ContentEncoding::preference)
+            .unwrap_or(ContentEncoding::Identity)
+    }
+}
+
+/// Compresses payloads with the negotiated encoding before handing them to an inner
+/// emitter, setting `EmitMetadata::content_encoding` so that the inner emitter can
+/// label them.
+pub struct CompressingEmitter<E>
+where
+    E: EventEmitter<Event = Vec<u8>> + Send + Sync + 'static,
+{
+    inner: E,
+    negotiator: CompressionNegotiator,
+}
+
+impl<E> CompressingEmitter<E>
+where
+    E: EventEmitter<Event = Vec<u8>> + Send + Sync + 'static,
+{
+    pub fn new(inner: E, negotiator: CompressionNegotiator) -> Self {
+        Self { inner, negotiator }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    fn compress(
+        &self,
+        events: Vec<Vec<u8>>,
+        metadata: &mut EmitMetadata,
+    ) -> Result<Vec<Vec<u8>>, CompressingEmitterError<E::Error>> {
+        let encoding = self.negotiator.choose();
+        if encoding == ContentEncoding::Identity {
+            return Ok(events);
+        }
+
+        metadata.content_encoding = Some(encoding.as_str().to_owned());
+        events
+            .iter()
+            .map(|event| encoding.compress(event))
+            .collect::<Result<_, _>>()
+            .map_err(CompressingEmitterError::Compress)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CompressingEmitterError<E>
+where
+    E: std::fmt::Debug,
+{
+    #[error("CompressError: {0}")]
+    Compress(std::io::Error),
+    #[error("EmitError: {0:?}")]
+    Emit(E),
+}
+
+#[async_trait]
+impl<E> EventEmitter for CompressingEmitter<E>
+where
+    E: EventEmitter<Event = Vec<u8>> + Send + Sync + 'static,
+    E::Error: Send,
+{
+    type Event = Vec<u8>;
+    type Error = CompressingEmitterError<E::Error>;
+
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        self.emit_event_with_metadata(events, EmitMetadata::default())
+            .await
+    }
+
+    async fn emit_event_with_metadata(
+        &mut self,
+        events: Vec<Self::Event>,
+        mut metadata: EmitMetadata,
+    ) -> Result<(), Self::Error> {
+        let events = self.compress(events, &mut metadata)?;
+        self.inner
+            .emit_event_with_metadata(events, metadata)
+            .await
+            .map_err(CompressingEmitterError::Emit)
+    }
+
+    async fn emit_event_with_receipt(
+        &mut self,
+        events: Vec<Self::Event>,
+        mut metadata: EmitMetadata,
+    ) -> Result<EmitReceipt, Self::Error> {
+        let events = self.compress(events, &mut metadata)?;
+        self.inner
+            .emit_event_with_receipt(events, metadata)
+            .await
+            .map_err(CompressingEmitterError::Emit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::test_support::MockEmitter;
+
+    #[test]
+    fn the_best_accepted_encoding_is_chosen() {
+        let choose = |accepted: Vec<ContentEncoding>| CompressionNegotiator::new(accepted).choose();
+
+        assert_eq!(
+            choose(vec![ContentEncoding::Identity, ContentEncoding::Gzip]),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            choose(vec![ContentEncoding::Gzip, ContentEncoding::Zstd]),
+            ContentEncoding::Zstd
+        );
+        assert_eq!(choose(vec![]), ContentEncoding::Identity);
+    }
+
+    #[tokio::test]
+    async fn payloads_are_compressed_with_the_negotiated_encoding() {
+        let inner = MockEmitter::new();
+        let mut emitter = CompressingEmitter::new(
+            inner.clone(),
+            CompressionNegotiator::new(vec![ContentEncoding::Gzip, ContentEncoding::Identity]),
+        );
+
+        emitter
+            .emit_event_with_receipt(vec![b"payload".to_vec()], EmitMetadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(inner.metadata()[0].content_encoding, Some("gzip".to_owned()));
+        let mut decompressed = String::new();
+        GzDecoder::new(&inner.batches()[0][0][..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "payload");
+    }
+
+    #[tokio::test]
+    async fn identity_payloads_are_passed_through() {
+        let inner = MockEmitter::new();
+        let mut emitter = CompressingEmitter::new(
+            inner.clone(),
+            CompressionNegotiator::new(vec![ContentEncoding::Identity]),
+        );
+
+        emitter.emit_event(vec![b"payload".to_vec()]).await.unwrap();
+
+        assert_eq!(inner.events(), vec!["payload".to_owned()]);
+        assert_eq!(inner.metadata()[0].content_encoding, None);
+    }
+}
@@ -0,0 +1,29 @@
+/// A completed event the handler gave up on, along with why.
+#[derive(Clone, Debug)]
+pub struct DeadLetter<CE> {
+    pub event: CE,
+    /// The SQS message the event came from, if it had one
+    pub message_id: Option<String>,
+    pub reason: String,
+}
+
+impl<CE> DeadLetter<CE> {
+    pub fn new(event: CE, message_id: Option<String>, reason: impl Into<String>) -> Self {
+        Self {
+            event,
+            message_id,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// What to do with a completed event that, serialized on its own, is larger than
+/// the handler's `max_event_bytes`.
+pub enum OversizedEventPolicy<CE> {
+    /// Send the event to the dead-letter sink and delete its message
+    DeadLetter,
+    /// Replace the event with a smaller one and emit that instead
+    Truncate(fn(CE) -> CE),
+    /// Drop the event without deleting its message, so that it is redelivered
+    Error,
+}
@@ -0,0 +1,188 @@
+use rusoto_sqs::Message as SqsMessage;
+
+/// How to derive an identity for completions whose `OutputEvent::identities` is
+/// empty, so that they are still deduplicated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityFallback {
+    /// The source message's id. Catches redeliveries of the same message.
+    MessageId,
+    /// An MD5 of the source message's body. Also catches the same payload sent as
+    /// distinct messages.
+    BodyHash,
+}
+
+impl IdentityFallback {
+    fn identity(&self, sqs_message: &SqsMessage) -> Option<Vec<u8>> {
+        match self {
+            IdentityFallback::MessageId => sqs_message
+                .message_id
+                .as_ref()
+                .map(|message_id| message_id.as_bytes().to_vec()),
+            IdentityFallback::BodyHash => sqs_message
+                .body
+                .as_ref()
+                .map(|body| md5::compute(body.as_bytes()).0.to_vec()),
+        }
+    }
+}
+
+/// What to do when an identity can't be stored in the cache after retrying.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheFailurePolicy {
+    /// Delete the message anyway, so its event may be emitted again if the message
+    /// is redelivered
+    Proceed,
+    /// Keep the identity's messages buffered, and retry storing it next flush
+    RetainMessages,
+}
+
+impl Default for CacheFailurePolicy {
+    fn default() -> Self {
+        CacheFailurePolicy::Proceed
+    }
+}
+
+/// How a completion handler identifies and drops duplicate events. Each option
+/// has a matching `with_*` method on `SqsCompletionHandler`.
+#[derive(Clone, Debug, Default)]
+pub struct DedupConfig {
+    pub(crate) dedup_on_complete: bool,
+    pub(crate) coalesce_duplicates: bool,
+    pub(crate) identity_from_attribute: Option<String>,
+    pub(crate) identity_fallback: Option<IdentityFallback>,
+    pub(crate) cache_failure_policy: CacheFailurePolicy,
+    pub(crate) skip_cache_on_emit_success: bool,
+}
+
+impl DedupConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `SqsCompletionHandler::with_dedup_on_complete`. Defaults to false.
+    pub fn dedup_on_complete(mut self, dedup_on_complete: bool) -> Self {
+        self.dedup_on_complete = dedup_on_complete;
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_coalesce_duplicates`. Defaults to false.
+    pub fn coalesce_duplicates(mut self, coalesce_duplicates: bool) -> Self {
+        self.coalesce_duplicates = coalesce_duplicates;
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_identity_from_attribute`.
+    pub fn identity_from_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.identity_from_attribute = Some(attribute.into());
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_identity_fallback`.
+    pub fn identity_fallback(mut self, identity_fallback: IdentityFallback) -> Self {
+        self.identity_fallback = Some(identity_fallback);
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_cache_failure_policy`.
+    pub fn cache_failure_policy(mut self, cache_failure_policy: CacheFailurePolicy) -> Self {
+        self.cache_failure_policy = cache_failure_policy;
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_skip_cache_on_emit_success`. Defaults to false.
+    pub fn skip_cache_on_emit_success(mut self, skip_cache_on_emit_success: bool) -> Self {
+        self.skip_cache_on_emit_success = skip_cache_on_emit_success;
+        self
+    }
+
+    /// Adds the identities derived from `sqs_message` to a completion's
+    /// `identities`: the value of the `identity_from_attribute` attribute, and the
+    /// fallback identity if the completion has no other.
+    pub(crate) fn derive_identities(
+        &self,
+        sqs_message: &SqsMessage,
+        identities: &mut Vec<Vec<u8>>,
+    ) {
+        if let Some(identity) = self.attribute_identity(sqs_message) {
+            identities.push(identity);
+        }
+        if identities.is_empty() {
+            if let Some(identity) = self
+                .identity_fallback
+                .and_then(|identity_fallback| identity_fallback.identity(sqs_message))
+            {
+                identities.push(identity);
+            }
+        }
+    }
+
+    /// The value of the `identity_from_attribute` message attribute, if configured and
+    /// present.
+    fn attribute_identity(&self, sqs_message: &SqsMessage) -> Option<Vec<u8>> {
+        let attribute = self.identity_from_attribute.as_ref()?;
+        let value = sqs_message.message_attributes.as_ref()?.get(attribute)?;
+        match (&value.string_value, &value.binary_value) {
+            (Some(string_value), _) => Some(string_value.as_bytes().to_vec()),
+            (None, Some(binary_value)) => Some(binary_value.to_vec()),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rusoto_sqs::MessageAttributeValue;
+
+    use super::*;
+    use crate::test_support::message;
+
+    fn derived(
+        dedup: &DedupConfig,
+        sqs_message: &SqsMessage,
+        mut identities: Vec<Vec<u8>>,
+    ) -> Vec<Vec<u8>> {
+        dedup.derive_identities(sqs_message, &mut identities);
+        identities
+    }
+
+    #[test]
+    fn fallback_identities_are_opt_in() {
+        assert!(derived(&DedupConfig::new(), &message("1"), vec![]).is_empty());
+    }
+
+    #[test]
+    fn fallback_identities_fill_in_for_empty_identities() {
+        let by_id = DedupConfig::new().identity_fallback(IdentityFallback::MessageId);
+        let by_body = DedupConfig::new().identity_fallback(IdentityFallback::BodyHash);
+
+        assert_eq!(derived(&by_id, &message("1"), vec![]), vec![b"1".to_vec()]);
+        assert_eq!(
+            derived(&by_body, &message("1"), vec![]),
+            vec![md5::compute(b"body-1").0.to_vec()]
+        );
+        // Explicit identities take precedence
+        assert_eq!(derived(&by_id, &message("1"), vec![b"x".to_vec()]), vec![b"x".to_vec()]);
+    }
+
+    #[test]
+    fn identities_are_read_from_message_attributes() {
+        let dedup = DedupConfig::new()
+            .identity_from_attribute("event-id")
+            .identity_fallback(IdentityFallback::MessageId);
+        let mut sqs_message = message("1");
+        let mut attributes = HashMap::new();
+        attributes.insert(
+            "event-id".to_owned(),
+            MessageAttributeValue {
+                data_type: "String".to_owned(),
+                string_value: Some("e-1".to_owned()),
+                ..MessageAttributeValue::default()
+            },
+        );
+        sqs_message.message_attributes = Some(attributes);
+
+        assert_eq!(derived(&dedup, &sqs_message, vec![]), vec![b"e-1".to_vec()]);
+    }
+}
@@ -0,0 +1,114 @@
+use std::time::Duration;
+
+/// Paces delete requests while SQS is throttling them, AIMD-style: each round of
+/// requests that is throttled halves how many are sent at once and doubles the
+/// delay before the next round, and each round that isn't adds one back and
+/// shortens the delay by `min_delay`, until the handler is back to full speed.
+///
+/// Throttled requests are resent up to `max_attempts` times, independently of the
+/// handler's delete retries, which only cover timeouts.
+#[derive(Clone, Debug)]
+pub struct DeleteThrottle {
+    max_concurrency: usize,
+    concurrency: usize,
+    min_delay: Duration,
+    max_delay: Duration,
+    delay: Duration,
+    max_attempts: u32,
+}
+
+impl DeleteThrottle {
+    /// Sends up to `max_concurrency` delete requests at once while unthrottled.
+    pub fn new(max_concurrency: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            max_concurrency,
+            concurrency: max_concurrency,
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(20),
+            delay: Duration::from_secs(0),
+            max_attempts: 5,
+        }
+    }
+
+    pub fn delays(mut self, min_delay: Duration, max_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.max_delay = max_delay.max(min_delay);
+        self
+    }
+
+    /// Sends each throttled request at most `max_attempts` times, defaults to 5.
+    pub fn retry_throttled(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// How many delete requests to send at once.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// How long to wait before sending the next round of requests.
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn on_throttle(&mut self) {
+        self.concurrency = (self.concurrency / 2).max(1);
+        self.delay = (self.delay * 2).max(self.min_delay).min(self.max_delay);
+    }
+
+    pub fn on_success(&mut self) {
+        self.concurrency = (self.concurrency + 1).min(self.max_concurrency);
+        self.delay = self
+            .delay
+            .checked_sub(self.min_delay)
+            .unwrap_or_default();
+    }
+}
+
+impl Default for DeleteThrottle {
+    /// One request at a time, as without a throttle.
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_throttles_back_off_multiplicatively() {
+        let mut throttle =
+            DeleteThrottle::new(8).delays(Duration::from_millis(100), Duration::from_millis(500));
+        assert_eq!((throttle.concurrency(), throttle.delay()), (8, Duration::from_secs(0)));
+
+        let mut rounds = vec![];
+        for _ in 0..4 {
+            throttle.on_throttle();
+            rounds.push((throttle.concurrency(), throttle.delay().as_millis()));
+        }
+        assert_eq!(rounds, vec![(4, 100), (2, 200), (1, 400), (1, 500)]);
+    }
+
+    #[test]
+    fn successes_recover_additively() {
+        let mut throttle =
+            DeleteThrottle::new(4).delays(Duration::from_millis(100), Duration::from_secs(1));
+        throttle.on_throttle();
+        throttle.on_throttle();
+        assert_eq!((throttle.concurrency(), throttle.delay().as_millis()), (1, 200));
+
+        let mut rounds = vec![];
+        for _ in 0..4 {
+            throttle.on_success();
+            rounds.push((throttle.concurrency(), throttle.delay().as_millis()));
+        }
+        assert_eq!(rounds, vec![(2, 100), (3, 0), (4, 0), (4, 0)]);
+    }
+}
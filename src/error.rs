@@ -15,4 +15,65 @@ pub enum Error
     EncodeError(String),
     #[error("DecodeError: {0}")]
     DecodeError(String),
+    #[error("SqsError: {0}")]
+    SqsError(String),
+}
+
+impl Error {
+    /// Whether SQS rejected the request for exceeding a rate or request limit.
+    pub fn is_throttling(&self) -> bool {
+        match self {
+            Error::SqsError(e) => ["OverLimit", "Throttling", "RequestThrottled"]
+                .iter()
+                .any(|code| e.contains(code)),
+            _ => false,
+        }
+    }
+}
+
+/// The actor's router has shut down and can no longer accept messages.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+#[error("Receiver has failed, actor is gone")]
+pub struct ActorGone;
+
+/// Why a message could not be placed in an actor's mailbox.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxError {
+    #[error("Actor mailbox is full")]
+    Full,
+    #[error("Timed out waiting for space in the actor mailbox")]
+    TimedOut,
+    #[error("{0}")]
+    Gone(#[from] ActorGone),
+}
+
+/// Why a message was rejected by a handler's validator.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid message: {0}")]
+pub struct ValidationError(pub String);
+
+/// Why a health check against SQS failed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum HealthError {
+    /// SQS was reachable but reported that the queue does not exist
+    #[error("Queue not found: {0}")]
+    QueueNotFound(String),
+    /// The request never got a response, eg: DNS, TLS or network failures
+    #[error("Failed to reach SQS: {0}")]
+    Unreachable(String),
+    #[error("SqsError: {0}")]
+    Other(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttling_codes_are_recognized() {
+        assert!(Error::SqsError("OverLimit: too many requests".to_owned()).is_throttling());
+        assert!(Error::SqsError("RequestThrottled".to_owned()).is_throttling());
+        assert!(!Error::SqsError("InternalError".to_owned()).is_throttling());
+        assert!(!Error::CacheError("Throttling".to_owned()).is_throttling());
+    }
 }
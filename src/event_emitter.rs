@@ -1,8 +1,184 @@
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::time::Duration;
+
 use async_trait::async_trait;
 
+/// Context forwarded alongside a batch of emitted events.
+#[derive(Clone, Debug, Default)]
+pub struct EmitMetadata {
+    /// W3C traceparent of the span active when the batch was flushed
+    pub traceparent: Option<String>,
+    /// Name of the SQS queue the events were consumed from
+    pub source_queue: Option<String>,
+    /// The batch was produced by a fallback serializer after the primary failed
+    pub degraded: bool,
+    /// How long downstream delivery of the batch should be delayed
+    pub delay: Option<Duration>,
+    /// The `Content-Encoding` the payloads were compressed with, if any
+    pub content_encoding: Option<String>,
+    /// The partition key shared by every event in the batch, for emitters writing to
+    /// partitioned streams
+    pub partition_key: Option<String>,
+    /// The shard, in `0..shard_count`, every event in the batch was assigned to
+    pub shard_id: Option<u32>,
+    /// The FIFO `MessageGroupId` to emit the batch under, preserving its order
+    pub message_group_id: Option<String>,
+    /// Identical for every attempt to emit the same batch, for downstreams that
+    /// deduplicate requests
+    pub idempotency_token: Option<String>,
+    /// The version of the completed event schema the batch was serialized from
+    pub schema_version: Option<u32>,
+}
+
+/// SQS rejects a DelaySeconds greater than 15 minutes
+const MAX_SQS_DELAY_SECONDS: i64 = 900;
+
+impl EmitMetadata {
+    /// `delay` as an SQS `DelaySeconds`, clamped to the 900 second maximum.
+    pub fn delay_seconds(&self) -> Option<i64> {
+        self.delay
+            .map(|delay| (delay.as_secs() as i64).min(MAX_SQS_DELAY_SECONDS))
+    }
+}
+
+/// Which events of an emitted batch were accepted downstream, by index into the
+/// batch passed to the emitter.
+#[derive(Clone, Debug, Default)]
+pub struct EmitReceipt {
+    rejected: Vec<usize>,
+}
+
+impl EmitReceipt {
+    /// Every event in the batch was accepted
+    pub fn accepted() -> Self {
+        Self::default()
+    }
+
+    pub fn reject(&mut self, index: usize) {
+        if !self.rejected.contains(&index) {
+            self.rejected.push(index);
+        }
+    }
+
+    pub fn is_accepted(&self, index: usize) -> bool {
+        !self.rejected.contains(&index)
+    }
+
+    pub fn rejected(&self) -> &[usize] {
+        &self.rejected
+    }
+}
+
+/// An emitter of bytes whose error type has been erased, for auxiliary emitters that
+/// shouldn't add a type parameter to the handler.
+pub(crate) type BoxedEmitter =
+    Box<dyn EventEmitter<Event = Vec<u8>, Error = String> + Send + Sync>;
+
+/// Erases an emitter's error type by formatting it, see `BoxedEmitter`.
+pub(crate) struct ErasedEmitter<E>(pub(crate) E);
+
+#[async_trait]
+impl<E> EventEmitter for ErasedEmitter<E>
+where
+    E: EventEmitter<Event = Vec<u8>> + Send + Sync,
+    E::Error: Send,
+{
+    type Event = Vec<u8>;
+    type Error = String;
+
+    async fn emit_event(&mut self, completed_events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        self.0
+            .emit_event(completed_events)
+            .await
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
 #[async_trait]
 pub trait EventEmitter {
     type Event;
     type Error: std::fmt::Debug;
     async fn emit_event(&mut self, completed_events: Vec<Self::Event>) -> Result<(), Self::Error>;
+
+    /// Emitters that can carry metadata downstream should override this, by default
+    /// the metadata is discarded.
+    async fn emit_event_with_metadata(
+        &mut self,
+        completed_events: Vec<Self::Event>,
+        _metadata: EmitMetadata,
+    ) -> Result<(), Self::Error>
+    where
+        Self: Send,
+        Self::Event: Send + 'static,
+    {
+        self.emit_event(completed_events).await
+    }
+
+    /// Emitters that can accept or reject individual events should override this. By
+    /// default every event is accepted once the batch has been emitted.
+    async fn emit_event_with_receipt(
+        &mut self,
+        completed_events: Vec<Self::Event>,
+        metadata: EmitMetadata,
+    ) -> Result<EmitReceipt, Self::Error>
+    where
+        Self: Send,
+        Self::Event: Send + 'static,
+    {
+        self.emit_event_with_metadata(completed_events, metadata)
+            .await?;
+        Ok(EmitReceipt::accepted())
+    }
+}
+
+/// Discards every event, for handlers that never emit, see
+/// `SqsCompletionHandler::new_without_emitter`.
+pub struct NopEmitter<E>(PhantomData<fn() -> E>);
+
+impl<E> Default for NopEmitter<E> {
+    fn default() -> Self {
+        NopEmitter(PhantomData)
+    }
+}
+
+#[async_trait]
+impl<E> EventEmitter for NopEmitter<E>
+where
+    E: Send + 'static,
+{
+    type Event = E;
+    type Error = Infallible;
+
+    async fn emit_event(&mut self, _completed_events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receipts_track_rejected_indexes() {
+        let mut receipt = EmitReceipt::accepted();
+        receipt.reject(1);
+        receipt.reject(1);
+
+        assert!(receipt.is_accepted(0));
+        assert!(!receipt.is_accepted(1));
+        assert_eq!(receipt.rejected(), &[1]);
+    }
+
+    #[test]
+    fn delays_are_clamped_to_the_sqs_maximum() {
+        let delayed = |delay| EmitMetadata {
+            delay,
+            ..EmitMetadata::default()
+        };
+
+        assert_eq!(delayed(None).delay_seconds(), None);
+        assert_eq!(delayed(Some(Duration::from_secs(30))).delay_seconds(), Some(30));
+        assert_eq!(delayed(Some(Duration::from_secs(3600))).delay_seconds(), Some(900));
+    }
 }
@@ -20,6 +20,8 @@ where
 {
     pub completed_event: Completion<T, E>,
     pub identities: Vec<Vec<u8>>,
+    // Not public, so that adding it didn't break struct literals, see `side_output`
+    pub(crate) side_output: Option<Vec<u8>>,
 }
 
 impl<T, E> OutputEvent<T, E>
@@ -31,12 +33,31 @@ where
         Self {
             completed_event,
             identities: Vec::new(),
+            side_output: None,
         }
     }
 
     pub fn add_identity(&mut self, identity: impl Cacheable) {
         self.identities.push(identity.identity())
     }
+
+    /// Attaches a secondary artifact of processing the message, eg: a derived record,
+    /// emitted to the completion handler's side emitter rather than with the
+    /// completed events.
+    pub fn set_side_output(&mut self, side_output: impl Into<Vec<u8>>) {
+        self.side_output = Some(side_output.into());
+    }
+
+    /// Like `set_side_output`, for building an `OutputEvent` in one expression.
+    pub fn with_side_output(mut self, side_output: impl Into<Vec<u8>>) -> Self {
+        self.set_side_output(side_output);
+        self
+    }
+
+    /// The side output set by `set_side_output`, if any.
+    pub fn side_output(&self) -> Option<&[u8]> {
+        self.side_output.as_deref()
+    }
 }
 
 #[async_trait]
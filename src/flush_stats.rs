@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// A record of one flush, emitted as data for downstream aggregation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlushStats {
+    pub flush_id: u64,
+    pub emitted_events: usize,
+    /// The total size of the payloads emitted
+    pub emitted_bytes: usize,
+    pub deleted_messages: usize,
+    pub latency: Duration,
+    /// Events rejected downstream, retained for the next flush
+    pub rejected_events: usize,
+    pub failed_deletes: usize,
+    pub failed_cache_identities: usize,
+}
+
+impl FlushStats {
+    /// The record as a JSON object, with the latency in milliseconds.
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::json!({
+            "flush_id": self.flush_id,
+            "emitted_events": self.emitted_events,
+            "emitted_bytes": self.emitted_bytes,
+            "deleted_messages": self.deleted_messages,
+            "latency_ms": self.latency.as_millis() as u64,
+            "errors": {
+                "rejected_events": self.rejected_events,
+                "failed_deletes": self.failed_deletes,
+                "failed_cache_identities": self.failed_cache_identities,
+            },
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_grouped_in_the_json() {
+        let flush_stats = FlushStats {
+            flush_id: 3,
+            emitted_events: 5,
+            latency: Duration::from_micros(2_500),
+            rejected_events: 1,
+            failed_deletes: 2,
+            ..FlushStats::default()
+        };
+        let json: serde_json::Value = serde_json::from_slice(&flush_stats.to_json()).unwrap();
+
+        assert_eq!(json["flush_id"], 3);
+        assert_eq!(json["emitted_events"], 5);
+        assert_eq!(json["latency_ms"], 2);
+        assert_eq!(json["errors"]["rejected_events"], 1);
+        assert_eq!(json["errors"]["failed_deletes"], 2);
+        assert_eq!(json["errors"]["failed_cache_identities"], 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use crate::handler_stats::CompletionCounts;
+use crate::completion_policy::FlushSchedule;
+
+/// A buffered message, as recorded in a `HandlerSnapshot`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BufferedMessage {
+    pub message_id: Option<String>,
+    pub receipt_handle: Option<String>,
+}
+
+/// The completion policy's configuration and state, as recorded in a `HandlerSnapshot`.
+#[derive(Clone, Copy, Debug)]
+pub struct PolicySnapshot {
+    /// The current batch size, which may be below the configured one while warming up
+    pub max_messages: u16,
+    pub schedule: FlushSchedule,
+    pub since_last_flush: Duration,
+}
+
+/// A dump of a handler's internal state, for postmortem debugging.
+#[derive(Clone, Debug)]
+pub struct HandlerSnapshot<CE> {
+    pub events: Vec<CE>,
+    /// The message each event came from, by index into `events`. None for partial
+    /// completions and compacted events.
+    pub event_sources: Vec<Option<String>>,
+    pub messages: Vec<BufferedMessage>,
+    /// Messages awaiting their delete grace period
+    pub pending_deletes: Vec<BufferedMessage>,
+    pub identities: Vec<Vec<u8>>,
+    pub policy: PolicySnapshot,
+    pub paused: bool,
+    pub flush_count: u64,
+    pub events_emitted: u64,
+    pub messages_deleted: u64,
+    pub delete_failures: u64,
+    pub completion_counts: CompletionCounts,
+}
+
+fn messages_json(messages: &[BufferedMessage]) -> serde_json::Value {
+    messages
+        .iter()
+        .map(|msg| {
+            serde_json::json!({
+                "message_id": msg.message_id,
+                "receipt_handle": msg.receipt_handle,
+            })
+        })
+        .collect()
+}
+
+impl<CE> HandlerSnapshot<CE>
+where
+    CE: serde::Serialize,
+{
+    /// The snapshot as a JSON object, with identities hex encoded and durations in
+    /// milliseconds.
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let events = self
+            .events
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let identities: Vec<String> = self.identities.iter().map(hex::encode).collect();
+
+        serde_json::to_vec(&serde_json::json!({
+            "events": events,
+            "event_sources": self.event_sources,
+            "messages": messages_json(&self.messages),
+            "pending_deletes": messages_json(&self.pending_deletes),
+            "identities": identities,
+            "policy": {
+                "max_messages": self.policy.max_messages,
+                "schedule": format!("{:?}", self.policy.schedule),
+                "since_last_flush_ms": self.policy.since_last_flush.as_millis() as u64,
+            },
+            "paused": self.paused,
+            "flush_count": self.flush_count,
+            "stats": {
+                "events_emitted": self.events_emitted,
+                "messages_deleted": self.messages_deleted,
+                "delete_failures": self.delete_failures,
+                "completions": {
+                    "total": self.completion_counts.total,
+                    "partial": self.completion_counts.partial,
+                    "error": self.completion_counts.error,
+                },
+            },
+        }))
+    }
+}
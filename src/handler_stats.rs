@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+/// How many completions of each variant were marked complete.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompletionCounts {
+    pub total: u64,
+    pub partial: u64,
+    pub error: u64,
+}
+
+/// Lifetime counters for a completion handler. Callers holding an
+/// `Arc<HandlerStats>` can read them without going through the actor's channel.
+#[derive(Debug, Default)]
+pub struct HandlerStats {
+    events_emitted: AtomicU64,
+    messages_deleted: AtomicU64,
+    delete_failures: AtomicU64,
+    events_without_messages: AtomicU64,
+    events_expired: AtomicU64,
+    wal_failures: AtomicU64,
+    completions_total: AtomicU64,
+    completions_partial: AtomicU64,
+    completions_error: AtomicU64,
+    last_flush_millis: AtomicI64,
+    // Keyed by error, which isn't known up front, so it can't be a fixed set of
+    // atomics. Only locked when recording a processing error or reading the counts.
+    proc_errors: Mutex<HashMap<String, u64>>,
+}
+
+impl HandlerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events_emitted(&self) -> u64 {
+        self.events_emitted.load(Ordering::SeqCst)
+    }
+
+    pub fn messages_deleted(&self) -> u64 {
+        self.messages_deleted.load(Ordering::SeqCst)
+    }
+
+    pub fn delete_failures(&self) -> u64 {
+        self.delete_failures.load(Ordering::SeqCst)
+    }
+
+    /// Completed events buffered without a source message to delete, ie: `Partial`
+    /// completions.
+    pub fn events_without_messages(&self) -> u64 {
+        self.events_without_messages.load(Ordering::SeqCst)
+    }
+
+    /// Completed events dropped because their message's deadline had passed.
+    pub fn events_expired(&self) -> u64 {
+        self.events_expired.load(Ordering::SeqCst)
+    }
+
+    /// WAL appends and truncations that failed. Events buffered while the WAL is
+    /// failing would be lost if the handler crashed before flushing them.
+    pub fn wal_failures(&self) -> u64 {
+        self.wal_failures.load(Ordering::SeqCst)
+    }
+
+    /// Completions of each variant since the handler started, or since the last
+    /// `take_completion_counts` if it has been called. Reading them doesn't reset them.
+    pub fn completion_counts(&self) -> CompletionCounts {
+        CompletionCounts {
+            total: self.completions_total.load(Ordering::SeqCst),
+            partial: self.completions_partial.load(Ordering::SeqCst),
+            error: self.completions_error.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Returns the completion counts and resets them, starting a new window.
+    pub fn take_completion_counts(&self) -> CompletionCounts {
+        CompletionCounts {
+            total: self.completions_total.swap(0, Ordering::SeqCst),
+            partial: self.completions_partial.swap(0, Ordering::SeqCst),
+            error: self.completions_error.swap(0, Ordering::SeqCst),
+        }
+    }
+
+    /// Milliseconds since the unix epoch of the last completed flush, or `None`
+    /// if no flush has completed yet.
+    pub fn last_flush_millis(&self) -> Option<i64> {
+        match self.last_flush_millis.load(Ordering::SeqCst) {
+            0 => None,
+            millis => Some(millis),
+        }
+    }
+
+    /// Counts of each distinct processing error seen, keyed by its `Debug` output.
+    pub fn proc_error_counts(&self) -> HashMap<String, u64> {
+        self.proc_errors.lock().unwrap().clone()
+    }
+
+    pub(crate) fn add_events_emitted(&self, count: u64) {
+        self.events_emitted.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_messages_deleted(&self, count: u64) {
+        self.messages_deleted.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_delete_failures(&self, count: u64) {
+        self.delete_failures.fetch_add(count, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_event_without_message(&self) {
+        self.events_without_messages.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_event_expired(&self) {
+        self.events_expired.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_wal_failure(&self) {
+        self.wal_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_completion_total(&self) {
+        self.completions_total.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_completion_partial(&self) {
+        self.completions_partial.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn add_completion_error(&self) {
+        self.completions_error.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_flush(&self) {
+        self.last_flush_millis
+            .store(Utc::now().timestamp_millis(), Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_proc_error(&self, err: &impl Debug) {
+        *self
+            .proc_errors
+            .lock()
+            .unwrap()
+            .entry(format!("{:?}", err))
+            .or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_accumulate() {
+        let stats = HandlerStats::new();
+        stats.add_events_emitted(2);
+        stats.add_events_emitted(3);
+        stats.add_messages_deleted(4);
+        stats.add_delete_failures(1);
+
+        assert_eq!(stats.events_emitted(), 5);
+        assert_eq!(stats.messages_deleted(), 4);
+        assert_eq!(stats.delete_failures(), 1);
+        assert_eq!(stats.last_flush_millis(), None);
+
+        stats.record_flush();
+        assert!(stats.last_flush_millis().is_some());
+    }
+
+    #[test]
+    fn taking_completion_counts_starts_a_new_window() {
+        let stats = HandlerStats::new();
+        stats.add_completion_total();
+        stats.add_completion_total();
+        stats.add_completion_error();
+
+        assert_eq!(
+            stats.take_completion_counts(),
+            CompletionCounts {
+                total: 2,
+                partial: 0,
+                error: 1
+            }
+        );
+        stats.add_completion_partial();
+        assert_eq!(
+            stats.completion_counts(),
+            CompletionCounts {
+                total: 0,
+                partial: 1,
+                error: 0
+            }
+        );
+    }
+}
@@ -1,18 +1,46 @@
+pub mod ack_summary;
+pub mod adaptive_visibility;
+pub mod audit;
+pub mod bloom_cache;
 pub mod cache;
 pub mod completion_event_serializer;
 pub mod completion_handler;
+pub mod completion_policy;
+pub mod compression;
 pub mod consumer;
+pub mod dead_letter;
+pub mod dedup;
+pub mod delete_throttle;
 pub mod error;
 pub mod event_decoder;
 pub mod event_emitter;
 pub mod event_handler;
 pub mod event_processor;
 pub mod event_retriever;
+pub mod flush_stats;
+pub mod handler_snapshot;
+pub mod handler_stats;
 pub mod local_sqs_service;
+pub mod message_checks;
+pub mod metrics;
+mod ordering;
+#[cfg(feature = "parquet")]
+pub mod parquet_serializer;
+#[cfg(feature = "prost")]
+pub mod prost_serializer;
 pub mod redis_cache;
 pub mod retry;
 pub mod s3_event_emitter;
+pub mod sequence_cursor_cache;
 pub mod sqs_completion_handler;
 pub mod sqs_consumer;
+pub mod sqs_event_emitter;
+pub mod sqs_ops;
 pub mod sqs_service;
-pub mod service_builder;
\ No newline at end of file
+pub mod tiered_cache;
+#[cfg(test)]
+mod test_support;
+pub mod wal;
+pub mod service_builder;
+pub mod sink_event_emitter;
+pub mod writer_event_emitter;
\ No newline at end of file
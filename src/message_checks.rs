@@ -0,0 +1,185 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusoto_sqs::Message as SqsMessage;
+
+use crate::error::ValidationError;
+
+/// Checks `mark_complete` runs on each message before buffering its event. Events
+/// from messages failing any of them are dead-lettered and their messages are
+/// deleted, as redelivering an untrustworthy message won't fix it. Each check has a
+/// matching `with_*` method on `SqsCompletionHandler`.
+#[derive(Default)]
+pub struct MessageChecks {
+    pub(crate) verify_md5: bool,
+    pub(crate) validator: Option<Box<dyn Fn(&SqsMessage) -> Result<(), ValidationError> + Send + Sync>>,
+    pub(crate) max_message_age: Option<Duration>,
+}
+
+impl MessageChecks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `SqsCompletionHandler::with_verify_md5`. Defaults to false.
+    pub fn verify_md5(mut self, verify_md5: bool) -> Self {
+        self.verify_md5 = verify_md5;
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_validator`.
+    pub fn validator(
+        mut self,
+        validator: impl Fn(&SqsMessage) -> Result<(), ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.validator = Some(Box::new(validator));
+        self
+    }
+
+    /// See `SqsCompletionHandler::with_max_message_age`.
+    pub fn max_message_age(mut self, max_message_age: Duration) -> Self {
+        self.max_message_age = Some(max_message_age);
+        self
+    }
+
+    /// Why `sqs_message` should not be trusted, if it fails MD5 verification, the
+    /// validator or the max message age.
+    pub(crate) fn rejection(&self, sqs_message: &SqsMessage) -> Option<String> {
+        if self.verify_md5 && !body_md5_matches(sqs_message) {
+            return Some("Message body does not match its MD5".to_owned());
+        }
+
+        if let Some(Err(e)) = self.validator.as_ref().map(|validator| validator(sqs_message)) {
+            return Some(format!("Message failed validation: {}", e));
+        }
+
+        let max_message_age = self.max_message_age?;
+        match message_age(sqs_message, SystemTime::now()) {
+            Some(age) if age > max_message_age => Some(format!(
+                "Message was sent {:?} ago, older than {:?}",
+                age, max_message_age
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// How long ago `sqs_message` was first received, from its
+/// `ApproximateFirstReceiveTimestamp` attribute. None if the attribute wasn't
+/// requested or can't be parsed.
+pub fn time_since_first_receive(sqs_message: &SqsMessage, now: SystemTime) -> Option<Duration> {
+    time_since_attribute(sqs_message, "ApproximateFirstReceiveTimestamp", now)
+}
+
+/// How long ago `sqs_message` was sent, from its `SentTimestamp` attribute. None if
+/// the attribute wasn't requested or can't be parsed.
+pub fn message_age(sqs_message: &SqsMessage, now: SystemTime) -> Option<Duration> {
+    time_since_attribute(sqs_message, "SentTimestamp", now)
+}
+
+/// Time elapsed since the epoch millisecond timestamp in the attribute `name`.
+fn time_since_attribute(sqs_message: &SqsMessage, name: &str, now: SystemTime) -> Option<Duration> {
+    let millis: u64 = sqs_message
+        .attributes
+        .as_ref()?
+        .get(name)?
+        .parse()
+        .ok()?;
+    let timestamp = UNIX_EPOCH + Duration::from_millis(millis);
+    // Clock skew can put the timestamp in the future
+    Some(now.duration_since(timestamp).unwrap_or_default())
+}
+
+/// Whether the body of `sqs_message` matches the MD5 SQS computed when it was sent.
+/// Messages without a body or an MD5 to compare against are trusted.
+fn body_md5_matches(sqs_message: &SqsMessage) -> bool {
+    match (&sqs_message.body, &sqs_message.md5_of_body) {
+        (Some(body), Some(md5_of_body)) => {
+            format!("{:x}", md5::compute(body.as_bytes())).eq_ignore_ascii_case(md5_of_body)
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_support::message;
+
+    fn with_md5(mut sqs_message: SqsMessage, md5_of_body: &str) -> SqsMessage {
+        sqs_message.md5_of_body = Some(md5_of_body.to_owned());
+        sqs_message
+    }
+
+    fn with_timestamp(mut sqs_message: SqsMessage, name: &str, at: SystemTime) -> SqsMessage {
+        let millis = at.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut attributes = HashMap::new();
+        attributes.insert(name.to_owned(), millis.to_string());
+        sqs_message.attributes = Some(attributes);
+        sqs_message
+    }
+
+    fn sent_at(sqs_message: SqsMessage, sent: SystemTime) -> SqsMessage {
+        with_timestamp(sqs_message, "SentTimestamp", sent)
+    }
+
+    #[test]
+    fn md5_mismatches_are_rejected() {
+        let checks = MessageChecks::new().verify_md5(true);
+        let md5_of_body = format!("{:x}", md5::compute(b"body-1"));
+
+        assert_eq!(checks.rejection(&with_md5(message("1"), &md5_of_body)), None);
+        assert_eq!(
+            checks.rejection(&with_md5(message("1"), &md5_of_body.to_uppercase())),
+            None
+        );
+        assert!(checks.rejection(&with_md5(message("1"), "0000")).is_some());
+        // Nothing to compare against
+        assert_eq!(checks.rejection(&message("1")), None);
+        // Not verified unless asked to
+        assert_eq!(MessageChecks::new().rejection(&with_md5(message("1"), "0000")), None);
+    }
+
+    #[test]
+    fn old_messages_are_rejected() {
+        let checks = MessageChecks::new().max_message_age(Duration::from_secs(60));
+        let now = SystemTime::now();
+
+        assert_eq!(checks.rejection(&sent_at(message("1"), now)), None);
+        assert!(checks
+            .rejection(&sent_at(message("1"), now - Duration::from_secs(120)))
+            .is_some());
+        // The age isn't known without the attribute
+        assert_eq!(checks.rejection(&message("1")), None);
+    }
+
+    #[test]
+    fn message_age_tolerates_clock_skew() {
+        let now = SystemTime::now();
+        let sqs_message = sent_at(message("1"), now + Duration::from_secs(5));
+
+        assert_eq!(message_age(&sqs_message, now), Some(Duration::from_secs(0)));
+        assert_eq!(time_since_first_receive(&sqs_message, now), None);
+    }
+
+    #[test]
+    fn time_since_first_receive_is_read_from_attributes() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_600_000_005_000);
+        let first_received = UNIX_EPOCH + Duration::from_millis(1_600_000_000_250);
+        let sqs_message =
+            with_timestamp(message("1"), "ApproximateFirstReceiveTimestamp", first_received);
+
+        assert_eq!(
+            time_since_first_receive(&sqs_message, now),
+            Some(Duration::from_millis(4_750))
+        );
+        assert_eq!(time_since_first_receive(&message("1"), now), None);
+
+        let mut unparseable = message("1");
+        let mut attributes = HashMap::new();
+        attributes.insert("ApproximateFirstReceiveTimestamp".to_owned(), "soon".to_owned());
+        unparseable.attributes = Some(attributes);
+        assert_eq!(time_since_first_receive(&unparseable, now), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+/// Receives measurements of each flush, eg: to export them to a metrics system.
+/// Unlike `HandlerStats`, which only counts, implementations can record
+/// distributions.
+pub trait CompletionMetrics {
+    /// A flush completed after `latency`, emitting `batch_size` events.
+    fn record_flush(&self, latency: Duration, batch_size: usize);
+
+    /// `count` messages failed to delete during a flush.
+    fn record_delete_failures(&self, count: usize);
+
+    /// A message is being deleted `latency` after it was first received. Only called
+    /// for messages received with the `ApproximateFirstReceiveTimestamp` attribute.
+    fn record_receive_latency(&self, latency: Duration) {
+        let _ = latency;
+    }
+}
+
+/// Exports flush metrics through the `prometheus` crate.
+#[cfg(feature = "prometheus")]
+pub struct PrometheusMetrics {
+    registry: prometheus::Registry,
+    flushes: prometheus::IntCounter,
+    flush_latency: prometheus::Histogram,
+    batch_size: prometheus::Histogram,
+    delete_failures: prometheus::IntCounter,
+    receive_latency: prometheus::Histogram,
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    /// Registers the handler's metrics in a new registry, prefixing their names with
+    /// `namespace`.
+    pub fn new(namespace: &str) -> prometheus::Result<Self> {
+        use prometheus::{Histogram, HistogramOpts, IntCounter, Opts, Registry};
+
+        let registry = Registry::new();
+
+        let flushes = IntCounter::with_opts(
+            Opts::new("flushes_total", "Completed flushes").namespace(namespace),
+        )?;
+        let flush_latency = Histogram::with_opts(
+            HistogramOpts::new("flush_latency_seconds", "Time taken by each flush")
+                .namespace(namespace),
+        )?;
+        let batch_size = Histogram::with_opts(
+            HistogramOpts::new("flush_batch_size", "Events emitted by each flush")
+                .namespace(namespace)
+                .buckets(prometheus::exponential_buckets(1.0, 2.0, 12)?),
+        )?;
+        let delete_failures = IntCounter::with_opts(
+            Opts::new("delete_failures_total", "Messages that failed to delete")
+                .namespace(namespace),
+        )?;
+
+        let receive_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "receive_latency_seconds",
+                "Time from a message's first receipt to its deletion",
+            )
+            .namespace(namespace),
+        )?;
+
+        registry.register(Box::new(flushes.clone()))?;
+        registry.register(Box::new(flush_latency.clone()))?;
+        registry.register(Box::new(batch_size.clone()))?;
+        registry.register(Box::new(delete_failures.clone()))?;
+        registry.register(Box::new(receive_latency.clone()))?;
+
+        Ok(Self {
+            registry,
+            flushes,
+            flush_latency,
+            batch_size,
+            delete_failures,
+            receive_latency,
+        })
+    }
+
+    /// The current value of every metric, eg: to serve from a `/metrics` endpoint
+    /// with a `prometheus::TextEncoder`.
+    pub fn gather(&self) -> Vec<prometheus::proto::MetricFamily> {
+        self.registry.gather()
+    }
+
+    pub fn registry(&self) -> &prometheus::Registry {
+        &self.registry
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl CompletionMetrics for PrometheusMetrics {
+    fn record_flush(&self, latency: Duration, batch_size: usize) {
+        self.flushes.inc();
+        self.flush_latency.observe(latency.as_secs_f64());
+        self.batch_size.observe(batch_size as f64);
+    }
+
+    fn record_delete_failures(&self, count: usize) {
+        self.delete_failures.inc_by(count as i64);
+    }
+
+    fn record_receive_latency(&self, latency: Duration) {
+        self.receive_latency.observe(latency.as_secs_f64());
+    }
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+    use super::*;
+
+    fn metric<'a>(
+        families: &'a [prometheus::proto::MetricFamily],
+        name: &str,
+    ) -> &'a prometheus::proto::Metric {
+        let family = families
+            .iter()
+            .find(|family| family.get_name() == name)
+            .unwrap_or_else(|| panic!("No metric named {}", name));
+        &family.get_metric()[0]
+    }
+
+    #[test]
+    fn flushes_are_counted_and_batch_sizes_recorded() {
+        let metrics = PrometheusMetrics::new("test").unwrap();
+
+        metrics.record_flush(Duration::from_millis(10), 3);
+        metrics.record_flush(Duration::from_millis(20), 5);
+        metrics.record_delete_failures(2);
+
+        let families = metrics.gather();
+        assert_eq!(metric(&families, "test_flushes_total").get_counter().get_value(), 2.0);
+        let batch_size = metric(&families, "test_flush_batch_size").get_histogram();
+        assert_eq!(batch_size.get_sample_count(), 2);
+        assert_eq!(batch_size.get_sample_sum(), 8.0);
+        assert_eq!(
+            metric(&families, "test_delete_failures_total").get_counter().get_value(),
+            2.0
+        );
+    }
+
+    #[test]
+    fn receive_latency_is_recorded() {
+        let metrics = PrometheusMetrics::new("test").unwrap();
+
+        metrics.record_receive_latency(Duration::from_secs(2));
+
+        let families = metrics.gather();
+        let receive_latency = metric(&families, "test_receive_latency_seconds").get_histogram();
+        assert_eq!(receive_latency.get_sample_count(), 1);
+        assert_eq!(receive_latency.get_sample_sum(), 2.0);
+    }
+}
@@ -0,0 +1,55 @@
+use rusoto_sqs::Message as SqsMessage;
+
+/// What was buffered after the items an earlier flush retained, set aside by a
+/// strictly ordered flush until the retained items have gone through.
+pub(crate) struct HeldBack<CE> {
+    pub(crate) events: Vec<CE>,
+    pub(crate) event_sources: Vec<Option<String>>,
+    pub(crate) event_identities: Vec<Vec<Vec<u8>>>,
+    pub(crate) messages: Vec<SqsMessage>,
+    pub(crate) identities: Vec<Vec<u8>>,
+}
+
+/// Tracks what the last flush retained, so that with strict ordering the next flush
+/// only retries those items, holding back everything buffered since.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct StrictOrdering {
+    pub(crate) enabled: bool,
+    // How many events, messages and identities at the front of the buffer the last
+    // flush retained
+    retained_len: (usize, usize, usize),
+}
+
+impl StrictOrdering {
+    /// Records how much of the buffer a flush left behind. Everything buffered after
+    /// it is held back by the next flush.
+    pub(crate) fn set_retained(&mut self, events: usize, messages: usize, identities: usize) {
+        self.retained_len = (events, messages, identities);
+    }
+
+    /// When enabled, splits off everything buffered after what the last flush
+    /// retained, if it retained anything.
+    pub(crate) fn hold_back<CE>(
+        &self,
+        events: &mut Vec<CE>,
+        event_sources: &mut Vec<Option<String>>,
+        event_identities: &mut Vec<Vec<Vec<u8>>>,
+        messages: &mut Vec<SqsMessage>,
+        identities: &mut Vec<Vec<u8>>,
+    ) -> Option<HeldBack<CE>> {
+        if !self.enabled || self.retained_len == (0, 0, 0) {
+            return None;
+        }
+
+        // The buffer may have been cleared since, eg: by abandon_buffer
+        let (retained_events, retained_messages, retained_identities) = self.retained_len;
+        let retained_events = retained_events.min(events.len());
+        Some(HeldBack {
+            events: events.split_off(retained_events),
+            event_sources: event_sources.split_off(retained_events),
+            event_identities: event_identities.split_off(retained_events),
+            messages: messages.split_off(retained_messages.min(messages.len())),
+            identities: identities.split_off(retained_identities.min(identities.len())),
+        })
+    }
+}
@@ -0,0 +1,242 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, InMemoryWriteableCursor, RowGroupWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+
+use crate::completion_event_serializer::CompletionEventSerializer;
+
+/// The values of one column across a batch, in row order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnValues {
+    Boolean(Vec<bool>),
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    /// For `BYTE_ARRAY` columns, including `UTF8` strings
+    ByteArray(Vec<Vec<u8>>),
+}
+
+impl ColumnValues {
+    fn physical_type(&self) -> &'static str {
+        match self {
+            ColumnValues::Boolean(_) => "BOOLEAN",
+            ColumnValues::Int32(_) => "INT32",
+            ColumnValues::Int64(_) => "INT64",
+            ColumnValues::Float(_) => "FLOAT",
+            ColumnValues::Double(_) => "DOUBLE",
+            ColumnValues::ByteArray(_) => "BYTE_ARRAY",
+        }
+    }
+}
+
+/// Maps an event type onto a flat Parquet schema of required columns.
+pub trait ParquetRecord: Sized {
+    /// The schema in Parquet's message type syntax, eg:
+    /// `message event { REQUIRED INT64 id; REQUIRED BYTE_ARRAY name (UTF8); }`
+    fn schema() -> &'static str;
+
+    /// The values of the `column`th column of the schema across `records`. Their
+    /// type must match the column's physical type.
+    fn column(records: &[Self], column: usize) -> ColumnValues;
+}
+
+/// Serializes a batch into a single Parquet file with one row group.
+#[derive(Clone, Debug)]
+pub struct ParquetSerializer<CE>
+where
+    CE: ParquetRecord,
+{
+    properties: Arc<WriterProperties>,
+    _p: PhantomData<fn(&CE)>,
+}
+
+impl<CE> ParquetSerializer<CE>
+where
+    CE: ParquetRecord,
+{
+    pub fn new() -> Self {
+        Self::with_properties(WriterProperties::builder().build())
+    }
+
+    /// Writes files with `properties`, eg: to set the compression codec.
+    pub fn with_properties(properties: WriterProperties) -> Self {
+        Self {
+            properties: Arc::new(properties),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<CE> Default for ParquetSerializer<CE>
+where
+    CE: ParquetRecord,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_column(
+    column_writer: &mut ColumnWriter,
+    values: ColumnValues,
+    column: usize,
+) -> Result<(), ParquetError> {
+    match (column_writer, values) {
+        (ColumnWriter::BoolColumnWriter(writer), ColumnValues::Boolean(values)) => {
+            writer.write_batch(&values, None, None)?;
+        }
+        (ColumnWriter::Int32ColumnWriter(writer), ColumnValues::Int32(values)) => {
+            writer.write_batch(&values, None, None)?;
+        }
+        (ColumnWriter::Int64ColumnWriter(writer), ColumnValues::Int64(values)) => {
+            writer.write_batch(&values, None, None)?;
+        }
+        (ColumnWriter::FloatColumnWriter(writer), ColumnValues::Float(values)) => {
+            writer.write_batch(&values, None, None)?;
+        }
+        (ColumnWriter::DoubleColumnWriter(writer), ColumnValues::Double(values)) => {
+            writer.write_batch(&values, None, None)?;
+        }
+        (ColumnWriter::ByteArrayColumnWriter(writer), ColumnValues::ByteArray(values)) => {
+            let values: Vec<ByteArray> = values.into_iter().map(ByteArray::from).collect();
+            writer.write_batch(&values, None, None)?;
+        }
+        (_, values) => {
+            return Err(ParquetError::General(format!(
+                "Column {} does not accept {} values",
+                column,
+                values.physical_type(),
+            )))
+        }
+    }
+    Ok(())
+}
+
+impl<CE> CompletionEventSerializer for ParquetSerializer<CE>
+where
+    CE: ParquetRecord,
+{
+    type CompletedEvent = CE;
+    type Output = Vec<u8>;
+    type Error = ParquetError;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        // A file without rows is of no use downstream
+        if completed_events.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let schema = Arc::new(parse_message_type(CE::schema())?);
+        let cursor = InMemoryWriteableCursor::default();
+        let mut file_writer =
+            SerializedFileWriter::new(cursor.clone(), schema, self.properties.clone())?;
+
+        let mut row_group_writer = file_writer.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group_writer.next_column()? {
+            write_column(&mut column_writer, CE::column(completed_events, column), column)?;
+            row_group_writer.close_column(column_writer)?;
+            column += 1;
+        }
+        file_writer.close_row_group(row_group_writer)?;
+        file_writer.close()?;
+
+        Ok(vec![cursor.data()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+    use parquet::util::cursor::SliceableCursor;
+
+    use super::*;
+
+    struct Event {
+        id: i64,
+        name: String,
+    }
+
+    impl ParquetRecord for Event {
+        fn schema() -> &'static str {
+            "message event { REQUIRED INT64 id; REQUIRED BYTE_ARRAY name (UTF8); }"
+        }
+
+        fn column(records: &[Self], column: usize) -> ColumnValues {
+            match column {
+                0 => ColumnValues::Int64(records.iter().map(|record| record.id).collect()),
+                _ => ColumnValues::ByteArray(
+                    records
+                        .iter()
+                        .map(|record| record.name.clone().into_bytes())
+                        .collect(),
+                ),
+            }
+        }
+    }
+
+    /// Mistypes its only column.
+    struct Mistyped;
+
+    impl ParquetRecord for Mistyped {
+        fn schema() -> &'static str {
+            "message event { REQUIRED INT64 id; }"
+        }
+
+        fn column(records: &[Self], _column: usize) -> ColumnValues {
+            ColumnValues::Boolean(vec![true; records.len()])
+        }
+    }
+
+    #[test]
+    fn batches_are_written_as_one_parquet_file() {
+        let events = vec![
+            Event {
+                id: 1,
+                name: "a".to_owned(),
+            },
+            Event {
+                id: 2,
+                name: "bb".to_owned(),
+            },
+        ];
+        let serialized = ParquetSerializer::new()
+            .serialize_completed_events(&events)
+            .unwrap();
+        assert_eq!(serialized.len(), 1);
+
+        let reader =
+            SerializedFileReader::new(SliceableCursor::new(serialized[0].clone())).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().collect();
+        assert_eq!(rows[1].get_long(0).unwrap(), 2);
+        assert_eq!(rows[1].get_string(1).unwrap(), "bb");
+    }
+
+    #[test]
+    fn empty_batches_produce_no_file() {
+        let serialized = ParquetSerializer::<Event>::new()
+            .serialize_completed_events(&[])
+            .unwrap();
+
+        assert!(serialized.is_empty());
+    }
+
+    #[test]
+    fn mistyped_columns_are_rejected() {
+        let serialized =
+            ParquetSerializer::<Mistyped>::new().serialize_completed_events(&[Mistyped]);
+
+        assert!(serialized.is_err());
+    }
+}
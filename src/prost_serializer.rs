@@ -0,0 +1,111 @@
+use std::marker::PhantomData;
+
+use prost::Message;
+
+use crate::completion_event_serializer::CompletionEventSerializer;
+
+/// Serializes a batch into a single payload of length-delimited protobuf messages,
+/// readable with `prost::Message::decode_length_delimited`.
+#[derive(Clone, Debug, Default)]
+pub struct ProstSerializer<CE>
+where
+    CE: Message,
+{
+    _p: PhantomData<fn(&CE)>,
+}
+
+impl<CE> ProstSerializer<CE>
+where
+    CE: Message,
+{
+    pub fn new() -> Self {
+        Self { _p: PhantomData }
+    }
+}
+
+fn delimited_len(event: &impl Message) -> usize {
+    let len = event.encoded_len();
+    prost::length_delimiter_len(len) + len
+}
+
+impl<CE> CompletionEventSerializer for ProstSerializer<CE>
+where
+    CE: Message,
+{
+    type CompletedEvent = CE;
+    type Output = Vec<u8>;
+    type Error = prost::EncodeError;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        if completed_events.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut buf = Vec::with_capacity(completed_events.iter().map(delimited_len).sum());
+        for event in completed_events {
+            event.encode_length_delimited(&mut buf)?;
+        }
+
+        Ok(vec![buf])
+    }
+
+    fn serialized_size(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<usize, Self::Error> {
+        Ok(completed_events.iter().map(delimited_len).sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Event {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(uint64, tag = "2")]
+        count: u64,
+    }
+
+    fn events() -> Vec<Event> {
+        vec![
+            Event {
+                name: "a".to_owned(),
+                count: 1,
+            },
+            Event {
+                name: "bb".to_owned(),
+                count: 300,
+            },
+        ]
+    }
+
+    #[test]
+    fn batches_round_trip_through_length_delimited_framing() {
+        let events = events();
+        let serialized = ProstSerializer::new().serialize_completed_events(&events).unwrap();
+        assert_eq!(serialized.len(), 1);
+
+        let mut buf = &serialized[0][..];
+        let mut decoded = vec![];
+        while !buf.is_empty() {
+            decoded.push(Event::decode_length_delimited(&mut buf).unwrap());
+        }
+        assert_eq!(decoded, events);
+    }
+
+    #[test]
+    fn serialized_size_matches_the_output() {
+        let events = events();
+        let mut serializer = ProstSerializer::new();
+        let serialized = serializer.serialize_completed_events(&events).unwrap();
+
+        assert_eq!(serializer.serialized_size(&events).unwrap(), serialized[0].len());
+        assert!(serializer.serialize_completed_events(&[]).unwrap().is_empty());
+    }
+}
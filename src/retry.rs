@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use color_eyre::Help;
 use futures_retry::{ErrorHandler, RetryPolicy};
 use rand::{Rng, SeedableRng};
 use rand_xorshift::XorShiftRng;
@@ -43,3 +44,139 @@ impl<E> ErrorHandler<RusotoError<E>> for RetryHandler {
         self.attempt = 0;
     }
 }
+
+/// Bounds how many times an operation is attempted and how long to wait between
+/// attempts. The wait starts at `backoff` and doubles after each failed attempt, up
+/// to `max_backoff`.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryConfig {
+    max_attempts: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryConfig {
+    pub fn new(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    /// Caps the wait between attempts. Defaults to 10 seconds.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// A single attempt, no retries.
+    pub fn no_retry() -> Self {
+        Self::new(1, Duration::from_millis(0))
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn backoff(&self) -> Duration {
+        self.backoff
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    /// How long to wait after the `attempt`th failed attempt, counting from 1, before
+    /// the next one.
+    pub fn backoff_after(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        self.backoff
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self::new(10, Duration::from_millis(2))
+    }
+}
+
+/// Runs `f` until it succeeds, making at most `config.max_attempts()` attempts and
+/// waiting `config.backoff_after` between them. Returns every attempt's error if
+/// none succeeded.
+pub(crate) async fn retry<F, T, E>(config: &RetryConfig, f: impl Fn() -> F) -> color_eyre::Result<T>
+where
+    T: Send,
+    F: std::future::Future<Output = Result<T, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut errs: Result<T, _> = Err(eyre::eyre!("wait_loop failed"));
+    for attempt in 1..=config.max_attempts() {
+        match (f)().await {
+            Ok(t) => return Ok(t),
+            Err(e) => {
+                errs = errs.error(e);
+            }
+        };
+
+        if attempt < config.max_attempts() {
+            tokio::time::delay_for(config.backoff_after(attempt)).await;
+        }
+    }
+
+    errs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn retry_stops_after_max_attempts() {
+        let config = RetryConfig::new(3, Duration::from_millis(1));
+        let attempts = AtomicU32::new(0);
+
+        let result: color_eyre::Result<()> = retry(&config, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "failed"))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_returns_first_success() {
+        let config = RetryConfig::new(3, Duration::from_millis(1));
+        let attempts = AtomicU32::new(0);
+
+        let result = retry(&config, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(std::io::Error::new(std::io::ErrorKind::Other, "failed")),
+                attempt => Ok(attempt),
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_backoff() {
+        let config = RetryConfig::new(10, Duration::from_millis(10))
+            .with_max_backoff(Duration::from_millis(50));
+
+        assert_eq!(config.backoff_after(1), Duration::from_millis(10));
+        assert_eq!(config.backoff_after(2), Duration::from_millis(20));
+        assert_eq!(config.backoff_after(3), Duration::from_millis(40));
+        assert_eq!(config.backoff_after(4), Duration::from_millis(50));
+        assert_eq!(config.backoff_after(40), Duration::from_millis(50));
+    }
+}
@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::time::Duration;
 
-use crate::event_emitter::EventEmitter;
+use crate::event_emitter::{EmitMetadata, EventEmitter};
 use async_trait::async_trait;
 use futures::compat::Future01CompatExt;
 use futures::future::FutureExt;
@@ -64,6 +65,35 @@ where
 
     #[tracing::instrument(skip(self, events))]
     async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        self.emit_event_with_metadata(events, EmitMetadata::default())
+            .await
+    }
+
+    #[tracing::instrument(skip(self, events, metadata))]
+    async fn emit_event_with_metadata(
+        &mut self,
+        events: Vec<Self::Event>,
+        metadata: EmitMetadata,
+    ) -> Result<(), Self::Error> {
+        let mut object_metadata = HashMap::new();
+        if let Some(traceparent) = metadata.traceparent {
+            object_metadata.insert("traceparent".to_owned(), traceparent);
+        }
+        if let Some(source_queue) = metadata.source_queue {
+            object_metadata.insert("source-queue".to_owned(), source_queue);
+        }
+        if metadata.degraded {
+            object_metadata.insert("degraded".to_owned(), "true".to_owned());
+        }
+        if let Some(schema_version) = metadata.schema_version {
+            object_metadata.insert("schema-version".to_owned(), schema_version.to_string());
+        }
+        let object_metadata = if object_metadata.is_empty() {
+            None
+        } else {
+            Some(object_metadata)
+        };
+
         for event in events {
             let key = (self.key_fn)(&event);
             self.s3
@@ -71,6 +101,8 @@ where
                     body: Some(event.into()),
                     bucket: self.output_bucket.clone(),
                     key: key.clone(),
+                    metadata: object_metadata.clone(),
+                    content_encoding: metadata.content_encoding.clone(),
                     ..Default::default()
                 })
                 .await?;
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusoto_sqs::Message as SqsMessage;
+
+use crate::cache::{Cache, CacheResponse, Cacheable};
+
+/// Deduplicates messages from a FIFO queue by tracking the highest sequence number
+/// stored for each message group, rather than every identity. Anything at or below
+/// a group's high-water mark is a `Hit`.
+///
+/// Identities must be built with `SequenceCursorCache::identity` or
+/// `SequenceCursorCache::message_identity`. Any other identity is always a `Miss`.
+///
+/// Clones share the same cursors.
+#[derive(Clone, Default)]
+pub struct SequenceCursorCache {
+    cursors: Arc<Mutex<HashMap<String, u128>>>,
+}
+
+const SEQUENCE_BYTES: usize = 16;
+
+impl SequenceCursorCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encodes a message group and sequence number as an identity.
+    pub fn identity(message_group_id: &str, sequence_number: u128) -> Vec<u8> {
+        let mut identity = Vec::with_capacity(SEQUENCE_BYTES + message_group_id.len());
+        identity.extend_from_slice(&sequence_number.to_be_bytes());
+        identity.extend_from_slice(message_group_id.as_bytes());
+        identity
+    }
+
+    /// The identity of a message received with the `MessageGroupId` and
+    /// `SequenceNumber` attributes, or None if either is missing.
+    pub fn message_identity(sqs_message: &SqsMessage) -> Option<Vec<u8>> {
+        let attributes = sqs_message.attributes.as_ref()?;
+        let message_group_id = attributes.get("MessageGroupId")?;
+        let sequence_number = attributes.get("SequenceNumber")?.parse().ok()?;
+        Some(Self::identity(message_group_id, sequence_number))
+    }
+
+    /// The highest sequence number stored for `message_group_id`.
+    pub fn cursor(&self, message_group_id: &str) -> Option<u128> {
+        self.cursors.lock().unwrap().get(message_group_id).copied()
+    }
+
+    fn decode(identity: &[u8]) -> Option<(String, u128)> {
+        if identity.len() < SEQUENCE_BYTES {
+            return None;
+        }
+        let (sequence_number, message_group_id) = identity.split_at(SEQUENCE_BYTES);
+        let sequence_number = u128::from_be_bytes(sequence_number.try_into().ok()?);
+        let message_group_id = String::from_utf8(message_group_id.to_vec()).ok()?;
+        Some((message_group_id, sequence_number))
+    }
+}
+
+#[async_trait]
+impl Cache for SequenceCursorCache {
+    async fn get<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<CacheResponse, crate::error::Error> {
+        let (message_group_id, sequence_number) = match Self::decode(&cacheable.identity()) {
+            Some(decoded) => decoded,
+            None => return Ok(CacheResponse::Miss),
+        };
+
+        match self.cursor(&message_group_id) {
+            Some(cursor) if sequence_number <= cursor => Ok(CacheResponse::Hit),
+            _ => Ok(CacheResponse::Miss),
+        }
+    }
+
+    async fn store(&mut self, identity: Vec<u8>) -> Result<(), crate::error::Error> {
+        let (message_group_id, sequence_number) = Self::decode(&identity).ok_or_else(|| {
+            crate::error::Error::CacheError("Identity is not a sequence cursor".to_owned())
+        })?;
+
+        let mut cursors = self.cursors.lock().unwrap();
+        let cursor = cursors.entry(message_group_id).or_insert(sequence_number);
+        *cursor = (*cursor).max(sequence_number);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Identity;
+    use crate::test_support::message;
+
+    async fn is_duplicate(cache: &mut SequenceCursorCache, identity: Vec<u8>) -> bool {
+        match cache.get(Identity(identity)).await.unwrap() {
+            CacheResponse::Hit => true,
+            CacheResponse::Miss => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_at_or_below_the_cursor_are_duplicates() {
+        let mut cache = SequenceCursorCache::new();
+        cache.store(SequenceCursorCache::identity("group", 10)).await.unwrap();
+        cache.store(SequenceCursorCache::identity("group", 20)).await.unwrap();
+        // Stored out of order, doesn't move the cursor back
+        cache.store(SequenceCursorCache::identity("group", 15)).await.unwrap();
+
+        assert_eq!(cache.cursor("group"), Some(20));
+        assert!(is_duplicate(&mut cache, SequenceCursorCache::identity("group", 5)).await);
+        assert!(is_duplicate(&mut cache, SequenceCursorCache::identity("group", 20)).await);
+        assert!(!is_duplicate(&mut cache, SequenceCursorCache::identity("group", 21)).await);
+    }
+
+    #[tokio::test]
+    async fn message_groups_have_their_own_cursors() {
+        let mut cache = SequenceCursorCache::new();
+        cache.store(SequenceCursorCache::identity("a", 20)).await.unwrap();
+
+        assert!(!is_duplicate(&mut cache, SequenceCursorCache::identity("b", 10)).await);
+        assert_eq!(cache.cursor("b"), None);
+    }
+
+    #[tokio::test]
+    async fn other_identities_are_never_duplicates() {
+        let mut cache = SequenceCursorCache::new();
+
+        assert!(!is_duplicate(&mut cache, b"short".to_vec()).await);
+        assert!(cache.store(b"short".to_vec()).await.is_err());
+    }
+
+    #[test]
+    fn identities_are_read_from_fifo_attributes() {
+        let mut sqs_message = message("1");
+        assert_eq!(SequenceCursorCache::message_identity(&sqs_message), None);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("MessageGroupId".to_owned(), "group".to_owned());
+        attributes.insert("SequenceNumber".to_owned(), "18849496460467696128".to_owned());
+        sqs_message.attributes = Some(attributes);
+
+        assert_eq!(
+            SequenceCursorCache::message_identity(&sqs_message),
+            Some(SequenceCursorCache::identity("group", 18_849_496_460_467_696_128))
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use futures::{Sink, SinkExt};
+
+use crate::event_emitter::EventEmitter;
+
+/// Forwards serialized payloads into any `Sink`, eg: a channel or a framed writer.
+/// The sink is flushed after every batch.
+pub struct SinkEmitter<S, Payload>
+where
+    S: Sink<Payload> + Unpin + Send + Sync + 'static,
+    S::Error: Debug,
+    Payload: Send + Sync + 'static,
+{
+    sink: S,
+    _p: PhantomData<Payload>,
+}
+
+impl<S, Payload> SinkEmitter<S, Payload>
+where
+    S: Sink<Payload> + Unpin + Send + Sync + 'static,
+    S::Error: Debug,
+    Payload: Send + Sync + 'static,
+{
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink,
+            _p: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+#[async_trait]
+impl<S, Payload> EventEmitter for SinkEmitter<S, Payload>
+where
+    S: Sink<Payload> + Unpin + Send + Sync + 'static,
+    S::Error: Debug,
+    Payload: Send + Sync + 'static,
+{
+    type Event = Payload;
+    type Error = S::Error;
+
+    #[tracing::instrument(skip(self, events))]
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        for event in events {
+            self.sink.feed(event).await?;
+        }
+
+        self.sink.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn emitted_payloads_arrive_on_the_receiver() {
+        let (sender, receiver) = mpsc::channel(10);
+        let mut emitter = SinkEmitter::new(sender);
+
+        emitter
+            .emit_event(vec![b"a".to_vec(), b"b".to_vec()])
+            .await
+            .unwrap();
+        emitter.emit_event(vec![b"c".to_vec()]).await.unwrap();
+        drop(emitter);
+
+        let received: Vec<Vec<u8>> = receiver.collect().await;
+        assert_eq!(received, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn sink_errors_are_surfaced() {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>(10);
+        let mut emitter = SinkEmitter::new(sender);
+        drop(receiver);
+
+        assert!(emitter.emit_event(vec![b"a".to_vec()]).await.is_err());
+    }
+}
@@ -1,573 +1,2953 @@
+use std::any::Any;
 use std::fmt::Debug;
-use std::time::{Duration, Instant};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use futures::compat::Future01CompatExt;
+use futures::future::FutureExt;
 use log::*;
-use rusoto_sqs::{Message as SqsMessage, DeleteMessageBatchError};
-use rusoto_sqs::{DeleteMessageBatchRequest, DeleteMessageBatchRequestEntry, Sqs, SqsClient};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-
-use crate::cache::Cache;
-use crate::completion_event_serializer::CompletionEventSerializer;
-use crate::event_emitter::EventEmitter;
+use rusoto_sqs::Message as SqsMessage;
+use rusoto_sqs::{DeleteMessageBatchRequest, DeleteMessageBatchRequestEntry, DeleteMessageBatchResult};
+use rusoto_sqs::{ChangeMessageVisibilityBatchRequest, ChangeMessageVisibilityBatchRequestEntry};
+use rusoto_sqs::GetQueueAttributesRequest;
+use tokio::sync::watch;
+
+use crate::adaptive_visibility::AdaptiveVisibility;
+use crate::audit::{AckOutcome, AuditSink};
+use crate::cache::{Cache, CacheResponse, Identity};
+use crate::completion_event_serializer::{CompletionEventSerializer, EventMeta, SerializerId};
+use crate::event_emitter::{BoxedEmitter, EmitMetadata, EmitReceipt, EventEmitter};
 use crate::event_handler::{Completion, OutputEvent};
-use aktors::actor::Actor;
-use async_trait::async_trait;
+use crate::flush_stats::FlushStats;
+
+pub use crate::ack_summary::{AckPhase, AckSummary, ShutdownSummary};
+pub use crate::completion_policy::{AdaptiveBatching, CompletionPolicy, FlushSchedule, WarmupConfig};
+pub use crate::dedup::{CacheFailurePolicy, DedupConfig, IdentityFallback};
+use crate::dead_letter::DeadLetter;
+pub use crate::dead_letter::OversizedEventPolicy;
+use crate::delete_throttle::DeleteThrottle;
+use crate::error::HealthError;
+pub use crate::message_checks::{message_age, time_since_first_receive, MessageChecks};
+use crate::handler_snapshot::{BufferedMessage, HandlerSnapshot, PolicySnapshot};
+use crate::handler_stats::HandlerStats;
+use crate::metrics::CompletionMetrics;
+use crate::ordering::{HeldBack, StrictOrdering};
+use crate::retry::{retry, RetryConfig};
+use crate::sqs_ops::SqsOps;
+use crate::wal::{Wal, WalEntry};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod actor;
+mod builder;
+#[cfg(test)]
+mod tests;
+
+pub use actor::{SqsCompletionHandlerActor, SqsCompletionHandlerMessage};
+
+/// One `DeleteMessageBatch` request of a flush.
+struct DeleteChunk {
+    // Index into completed_messages of the chunk's first message
+    start: usize,
+    // 0 for the handler's own client, otherwise an index into delete_clients + 1
+    client: usize,
+    queue_url: String,
+    msg_ids: Vec<String>,
+    entries: Vec<DeleteMessageBatchRequestEntry>,
+    // Times the request has been throttled
+    attempts: u32,
+}
 
-use crate::completion_handler::CompletionHandler;
-use color_eyre::Help;
-use rusoto_core::RusotoError;
+/// The events of a flush that are serialized and emitted together.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+struct FlushGroup {
+    partition_key: Option<String>,
+    shard: Option<u32>,
+    // None for the primary serializer
+    serializer_id: Option<SerializerId>,
+}
 
-pub struct CompletionPolicy {
-    max_messages: u16,
-    max_time_between_flushes: Duration,
-    last_flush: Instant,
+/// Emits each completed event as soon as it is marked complete, as a batch of one,
+/// rather than waiting for a flush. Deletes and cache stores are still batched by
+/// the `CompletionPolicy`, which counts buffered messages instead of events.
+/// This trades throughput for latency.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamingConfig {
+    emit_partial: bool,
 }
 
-impl CompletionPolicy {
-    pub fn new(max_messages: u16, max_time_between_flushes: Duration) -> Self {
-        Self {
-            max_messages,
-            max_time_between_flushes,
-            last_flush: Instant::now(),
-        }
+impl StreamingConfig {
+    pub fn new() -> Self {
+        Self { emit_partial: true }
+    }
+
+    /// Whether `Completion::Partial` events are streamed. When false they are
+    /// dropped, as their messages are never deleted. Defaults to true.
+    pub fn emit_partial(mut self, emit_partial: bool) -> Self {
+        self.emit_partial = emit_partial;
+        self
     }
+}
 
-    pub fn should_flush(&self, cur_messages: u16) -> bool {
-        cur_messages >= self.max_messages
-            || Instant::now()
-                .checked_duration_since(self.last_flush)
-                .unwrap()
-                >= self.max_time_between_flushes
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+/// What to do with messages that still failed to delete after retries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeleteFailurePolicy {
+    /// Leave the message, SQS redelivers it once its visibility timeout expires
+    WaitTimeout,
+    /// Set the message's visibility timeout, eg: to zero for immediate redelivery
+    ResetVisibility(Duration),
+}
 
-    pub fn set_last_flush(&mut self) {
-        self.last_flush = Instant::now();
+impl Default for DeleteFailurePolicy {
+    fn default() -> Self {
+        DeleteFailurePolicy::WaitTimeout
     }
 }
 
-pub struct SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
+/// What to do with a message registered with `begin_processing` that is not completed
+/// within the handler's in-flight deadline.
+pub enum InFlightTimeoutAction {
+    /// Log the message id and leave the message to be redelivered
+    Log,
+    /// Hand the message to a callback, eg: to forward it to a dead-letter queue
+    Handle(Box<dyn Fn(SqsMessage) + Send + Sync>),
+}
+
+enum SizeCheck<CE> {
+    /// The event, with its serialized size if it was measured
+    Fits(CE, Option<usize>),
+    DeadLettered,
+    Rejected,
+}
+
+/// How many of the most recently cached identities are kept for debugging
+const RECENTLY_CACHED_CAPACITY: usize = 128;
+
+/// Called with the outcome of each message's deletion, `Ok` with the id of a deleted
+/// message and `Err` with the id of one that wasn't. Held behind an `Arc` so that it
+/// can be replaced at runtime with `update_on_ack`.
+pub type OnAck<CE, ProcErr, SqsT> = Arc<
+    dyn Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>) + Send + Sync,
+>;
+
+pub struct SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
 where
-    SqsT: Sqs + Clone + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
     CPE: Debug + Send + Sync + 'static,
     CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
         + Send
         + Sync
         + 'static,
-    Payload: Send + Sync + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
     CE: Send + Sync + Clone + 'static,
     EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
-    OA: Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
-        + Send
-        + Sync
-        + 'static,
     CacheT: Cache + Send + Sync + Clone + 'static,
     ProcErr: Debug + Send + Sync + 'static,
 {
     sqs_client: SqsT,
+    // Share deletes with sqs_client, round-robin
+    delete_clients: Vec<SqsT>,
+    next_delete_client: usize,
+    delete_throttle: DeleteThrottle,
     queue_url: String,
+    queue_name: String,
     completed_events: Vec<CE>,
+    // The message id each completed event came from, None for Partial completions
+    completed_event_sources: Vec<Option<String>>,
+    // The identities each completed event was buffered with, so that the identities
+    // of events rejected downstream aren't cached
+    completed_event_identities: Vec<Vec<Vec<u8>>>,
     identities: Vec<Vec<u8>>,
+    recently_cached: std::collections::VecDeque<Vec<u8>>,
+    // The ids of the messages that contributed to each identity, indexing into
+    // source_messages, which holds each contributing message once
+    identity_sources: HashMap<Vec<u8>, Vec<String>>,
+    source_messages: HashMap<String, SqsMessage>,
+    // Index into completed_events of the partial event buffered for each identity,
+    // replaced if a total event arrives for the identity before the next flush
+    buffered_partials: HashMap<Vec<u8>, usize>,
     completed_messages: Vec<SqsMessage>,
-    completion_serializer: CP,
-    event_emitter: EE,
+    // The queue each buffered message was received from, by message id, for messages
+    // not from queue_url
+    message_queues: HashMap<String, String>,
+    // Locked for reading by parallel serialization workers, see exclusive()
+    completion_serializer: Arc<RwLock<CP>>,
+    // None in dedup-only mode
+    event_emitter: Option<EE>,
     completion_policy: CompletionPolicy,
-    on_ack: OA,
+    on_ack: OnAck<CE, ProcErr, SqsT>,
     self_actor: Option<SqsCompletionHandlerActor<CE, ProcErr, SqsT>>,
     cache: CacheT,
+    stats: Arc<HandlerStats>,
+    emit_retry: RetryConfig,
+    delete_retry: RetryConfig,
+    cache_retry: RetryConfig,
+    proc_err_report_interval: Option<Duration>,
+    last_proc_err_report: Instant,
+    fail_fast_on_delete: bool,
+    strict_ordering: StrictOrdering,
+    message_checks: MessageChecks,
+    on_first_buffered: Option<Box<dyn Fn() + Send + Sync>>,
+    schema_version: Option<u32>,
+    deadline_attribute: Option<String>,
+    receipt_handle_transform: Option<Box<dyn Fn(&str) -> String + Send + Sync>>,
+    retain_source_body: bool,
+    emit_empty: bool,
+    flush_debounce: Option<Duration>,
+    idle_heartbeat: Option<(Duration, Box<dyn Fn() -> Payload + Send + Sync>)>,
+    // When the last flush started or heartbeat was emitted
+    idle_since: Instant,
+    // Callers waiting on a debounced or paused flush, Some while one is outstanding
+    pending_flush: Option<Vec<tokio::sync::oneshot::Sender<()>>>,
+    paused: bool,
+    // When the first attempt to shut down was routed, while draining the mailbox
+    shutdown_started: Option<Instant>,
+    events_without_messages: usize,
+    divergence_threshold: f64,
+    streaming: Option<StreamingConfig>,
+    trace_context: Option<Box<dyn Fn() -> Option<String> + Send + Sync>>,
+    shutdown_timeout: Duration,
+    mailbox_capacity: usize,
+    request_timeout: Duration,
+    ack_deadline: Option<Duration>,
+    max_event_bytes: Option<(usize, OversizedEventPolicy<CE>)>,
+    max_buffer_bytes: Option<usize>,
+    // Some while in dry-run mode, whether to clear the buffer after each flush
+    dry_run: Option<bool>,
+    // The serialized size of the events buffered since the last flush
+    buffered_bytes: usize,
+    dead_letter: Option<Box<dyn Fn(DeadLetter<CE>) + Send + Sync>>,
+    isolate_serialization_failures: bool,
+    wal: Option<Box<dyn Wal<CE> + Send + Sync>>,
+    delay_fn: Option<Box<dyn Fn(&CE) -> Option<Duration> + Send + Sync>>,
+    in_flight_timeout: Option<(Duration, InFlightTimeoutAction)>,
+    // Messages registered with begin_processing, by message id
+    in_flight: HashMap<String, (Instant, SqsMessage)>,
+    adaptive_visibility: Option<AdaptiveVisibility>,
+    // Whether an extend_visibility message is due, so that only one is ever pending
+    visibility_extension_scheduled: bool,
+    on_router_exit: Option<Box<dyn Fn(Vec<CE>, Vec<SqsMessage>) + Send + Sync>>,
+    redactor: Box<dyn Fn(&Payload) -> String + Send + Sync>,
+    flush_gate: Option<(Box<dyn Fn() -> bool + Send + Sync>, usize)>,
+    redelivery_limit: Option<(u32, Box<dyn Fn(SqsMessage) + Send + Sync>)>,
+    parallel_serialize: Option<usize>,
+    delete_failure_policy: DeleteFailurePolicy,
+    audit_sink: Option<Box<dyn AuditSink + Send + Sync>>,
+    compactor: Option<Box<dyn Fn(Vec<CE>) -> Vec<CE> + Send + Sync>>,
+    metrics: Option<Arc<dyn CompletionMetrics + Send + Sync>>,
+    stats_emitter: Option<BoxedEmitter>,
+    side_emitter: Option<BoxedEmitter>,
+    // Side outputs of the completions buffered since the last flush
+    side_outputs: Vec<Vec<u8>>,
+    // Bytes emitted by the flush in progress
+    emitted_bytes: usize,
+    flush_semaphore: Option<Arc<tokio::sync::Semaphore>>,
+    sqs_semaphore: Option<Arc<tokio::sync::Semaphore>>,
+    partition_key_fn: Option<Box<dyn Fn(&CE) -> String + Send + Sync>>,
+    // The shard count, and the key events are sharded by
+    shards: Option<(u32, Box<dyn Fn(&CE) -> u64 + Send + Sync>)>,
+    message_group_id_fn: Option<Box<dyn Fn(&[CE]) -> String + Send + Sync>>,
+    idempotency_tokens: bool,
+    dedup: DedupConfig,
+    delete_priority: Option<Box<dyn Fn(&SqsMessage, &SqsMessage) -> std::cmp::Ordering + Send + Sync>>,
+    delete_grace_period: Option<Duration>,
+    // Emitted messages waiting out the grace period, with when to delete them
+    pending_deletes: Vec<(Instant, SqsMessage)>,
+    max_pending_delete_batches: Option<usize>,
+    // Ids of the messages each flush deleted that are still awaiting deletion, as
+    // their delete requests are in flight or were retained after failing or timing out
+    undeleted_batches: Vec<HashSet<String>>,
+    // Publishes pending_delete_batches() to handles, which hold back completions while
+    // it exceeds max_pending_delete_batches
+    pending_deletes_watch: (watch::Sender<usize>, watch::Receiver<usize>),
+    // Ids of messages that have waited out the grace period and are being deleted
+    graced: HashSet<String>,
+    flush_count: u64,
+    // The id of the flush in progress, if any
+    flush_id: Option<u64>,
+    fallback_serializer: Option<
+        Box<
+            dyn CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+                + Send
+                + Sync,
+        >,
+    >,
+    serializers: HashMap<
+        SerializerId,
+        Box<
+            dyn CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+                + Send
+                + Sync,
+        >,
+    >,
+    serializer_selector: Option<Box<dyn Fn(&CE) -> SerializerId + Send + Sync>>,
     _p: std::marker::PhantomData<(ProcErr)>,
 }
 
-impl<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
-    SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
-where
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-    CPE: Debug + Send + Sync + 'static,
-    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
-        + Send
-        + Sync
-        + 'static,
-    Payload: Send + Sync + 'static,
-    CE: Send + Sync + Clone + 'static,
-    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
-    OA: Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
-        + Send
-        + Sync
-        + 'static,
-    CacheT: Cache + Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-{
-    pub fn new(
-        sqs_client: SqsT,
-        queue_url: String,
-        completion_serializer: CP,
-        event_emitter: EE,
-        completion_policy: CompletionPolicy,
-        on_ack: OA,
-        cache: CacheT,
-    ) -> Self {
-        Self {
-            sqs_client,
-            queue_url,
-            completed_events: Vec::with_capacity(completion_policy.max_messages as usize),
-            identities: Vec::with_capacity(completion_policy.max_messages as usize),
-            completed_messages: Vec::with_capacity(completion_policy.max_messages as usize),
-            completion_serializer,
-            event_emitter,
-            completion_policy,
-            on_ack,
-            self_actor: None,
-            cache,
-            _p: std::marker::PhantomData,
-        }
+/// The queue name is the last path segment of its url, eg:
+/// "https://sqs.us-east-1.amazonaws.com/123456789012/my-queue" is "my-queue". Falls
+/// back to the whole url if it has no such segment.
+fn queue_name_from_url(queue_url: &str) -> String {
+    match queue_url.trim_end_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() && name.len() < queue_url.len() => name.to_owned(),
+        _ => queue_url.to_owned(),
     }
 }
 
-async fn retry<F, T, E>(max_tries: u32, f: impl Fn() -> F) -> color_eyre::Result<T>
+/// A token identifying a batch of payloads, passed to emitters as
+/// `EmitMetadata::idempotency_token` so that they can drop re-emitted batches.
+/// Derived from the payloads alone, so the same batch always gets the same token,
+/// whether it is retried within a flush or re-emitted by a later one.
+fn idempotency_token(payloads: &[impl AsRef<[u8]>]) -> String {
+    let mut context = md5::Context::new();
+    for payload in payloads {
+        let payload = payload.as_ref();
+        // Length prefixed, so that payload boundaries contribute to the token
+        context.consume((payload.len() as u64).to_le_bytes());
+        context.consume(payload);
+    }
+    format!("{:x}", context.compute())
+}
+
+/// The serializer is shared with blocking tasks during a parallel serialization.
+/// Workers left running by a flush that passed its ack deadline may still hold it, in
+/// which case this waits for them to finish. A serializer that panicked while locked
+/// is still used, as panics in user code are otherwise survived by the router.
+fn exclusive<CP>(completion_serializer: &RwLock<CP>) -> RwLockWriteGuard<'_, CP> {
+    completion_serializer
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The message a panic was raised with, for logging.
+fn panic_reason(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic")
+}
+
+/// Shared access to the serializer, for `serialize_one` and `combine`.
+fn shared<CP>(completion_serializer: &RwLock<CP>) -> RwLockReadGuard<'_, CP> {
+    completion_serializer
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Given a batch that failed to serialize, finds the events that fail to serialize
+/// on their own. Halves that serialize are not searched further, so a batch that
+/// only fails as a whole yields no events.
+fn bisect_failures<S>(serializer: &mut S, events: &[S::CompletedEvent], meta: &[EventMeta]) -> Vec<usize>
 where
-    T: Send,
-    F: std::future::Future<Output = Result<T, E>>,
-    E: std::error::Error + Send + Sync + 'static,
+    S: CompletionEventSerializer + ?Sized,
 {
-    let mut backoff = 2;
-    let mut errs: Result<T, _> = Err(eyre::eyre!("wait_loop failed"));
-    for i in 0..max_tries {
-        match (f)().await {
-            Ok(t) => return Ok(t),
-            Err(e) => {
-                errs = errs.error(e);
-            }
-        };
-
-        tokio::time::delay_for(Duration::from_millis(backoff)).await;
-        backoff *= i as u64;
+    if events.len() <= 1 {
+        return (0..events.len()).collect();
     }
 
-    errs
+    let mid = events.len() / 2;
+    let mut failing = vec![];
+    for &(start, end) in &[(0, mid), (mid, events.len())] {
+        let half = &events[start..end];
+        let half_meta = &meta[start..end];
+        if serializer
+            .serialize_completed_events_with_meta(half, half_meta)
+            .is_err()
+        {
+            failing.extend(
+                bisect_failures(serializer, half, half_meta)
+                    .into_iter()
+                    .map(|index| index + start),
+            );
+        }
+    }
+    failing
 }
 
+/// Runs `f` to completion, or until `deadline` passes.
+async fn before_deadline<T>(deadline: Option<Instant>, f: impl std::future::Future<Output = T>) -> Option<T> {
+    match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            tokio::time::timeout(remaining, f).await.ok()
+        }
+        None => Some(f.await),
+    }
+}
 
-impl<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
-    SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
+impl<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+    SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
 where
-    SqsT: Sqs + Clone + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
     CPE: Debug + Send + Sync + 'static,
     CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
         + Send
         + Sync
         + 'static,
-    Payload: Send + Sync + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
     CE: Send + Sync + Clone + 'static,
     EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
-    OA: Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
-        + Send
-        + Sync
-        + 'static,
     CacheT: Cache + Send + Sync + Clone + 'static,
     ProcErr: Debug + Send + Sync + 'static,
 {
+    /// Replays the WAL into the buffer. Call this before starting the actor. Returns
+    /// the number of recovered events.
+    pub fn recover(&mut self) -> Result<usize, crate::error::Error> {
+        let entries = match &mut self.wal {
+            Some(wal) => wal.replay()?,
+            None => return Ok(0),
+        };
+
+        let recovered = entries.len();
+        for entry in entries {
+            let source = entry.message.as_ref().and_then(|message| message.message_id.clone());
+            if source.is_none() {
+                self.events_without_messages += 1;
+            }
+            self.completed_event_sources.push(source);
+            self.completed_messages.extend(entry.message);
+            // The WAL doesn't record identities, recovered events are not cached
+            self.completed_event_identities.push(vec![]);
+            self.record_buffered_size(&entry.event, None);
+            self.completed_events.push(entry.event);
+        }
+
+        info!("Recovered {} events from the WAL", recovered);
+        Ok(recovered)
+    }
+
+    pub fn stats(&self) -> Arc<HandlerStats> {
+        self.stats.clone()
+    }
+
+    fn report_proc_errors(&mut self, flushing: bool) {
+        let due = match self.proc_err_report_interval {
+            Some(interval) => self.last_proc_err_report.elapsed() >= interval,
+            None => flushing,
+        };
+
+        if !due {
+            return;
+        }
+
+        let counts = self.stats.proc_error_counts();
+        if !counts.is_empty() {
+            info!("ProcErr summary: {:?}", counts);
+        }
+        self.last_proc_err_report = Instant::now();
+    }
 
     #[tracing::instrument(skip(self))]
     pub async fn ack_message(
         &mut self,
         sqs_message: SqsMessage,
     ) {
+        self.end_processing(&sqs_message);
         self.completed_messages.push(sqs_message);
-        if self
-            .completion_policy
-            .should_flush(self.completed_events.len() as u16)
-        {
-            self.ack_all(None).await;
-            self.completion_policy.set_last_flush();
-        }
+        self.flush_if_due().await;
     }
 
 
-    #[tracing::instrument(skip(self, completed))]
-    pub async fn mark_complete(
+    /// Like `ack_message`, for a message received from `queue_url` rather than the
+    /// handler's own queue.
+    pub async fn ack_message_from(&mut self, queue_url: String, sqs_message: SqsMessage) {
+        self.record_source_queue(queue_url, &sqs_message);
+        self.ack_message(sqs_message).await
+    }
+
+    /// Like `mark_complete`, for a message received from `queue_url` rather than the
+    /// handler's own queue. Its deletion is sent to that queue.
+    pub async fn mark_complete_from(
         &mut self,
+        queue_url: String,
         sqs_message: SqsMessage,
         completed: OutputEvent<CE, ProcErr>,
-    ) {
-        match completed.completed_event {
-            Completion::Total(ce) => {
-                info!("Marking all events complete - total success");
-                self.completed_events.push(ce);
-                self.completed_messages.push(sqs_message);
-                self.identities.extend(completed.identities);
-            }
-            Completion::Partial((ce, err)) => {
-                warn!("EventHandler was only partially successful: {:?}", err);
-                self.completed_events.push(ce);
-                self.identities.extend(completed.identities);
-            }
-            Completion::Error(e) => {
-                warn!("Event handler failed: {:?}", e);
-            }
-        };
-
-        info!(
-            "Marked event complete. {} completed events, {} completed messages",
-            self.completed_events.len(),
-            self.completed_messages.len(),
-        );
-
-        if self
-            .completion_policy
-            .should_flush(self.completed_events.len() as u16)
-        {
-            self.ack_all(None).await;
-            self.completion_policy.set_last_flush();
-        }
+    ) -> Option<AckSummary> {
+        self.record_source_queue(queue_url, &sqs_message);
+        self.mark_complete(sqs_message, completed).await
     }
 
-    #[tracing::instrument(skip(self, notify))]
-    pub async fn ack_all(&mut self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
-        debug!("Flushing completed events");
-
-        let serialized_event = self
-            .completion_serializer
-            .serialize_completed_events(&self.completed_events[..]);
-
-        let serialized_event = match serialized_event {
-            Ok(serialized_event) => serialized_event,
-            Err(e) => {
-                // We should emit a failure, but ultimately we just have to not ack these messages
-                self.completed_events.clear();
-                self.completed_messages.clear();
-
-                panic!("Serializing events failed: {:?}", e);
-            }
+    /// Records that `sqs_message` has entered processing, so that it can be reported
+    /// if it is never completed and its processing time learned. Does nothing without
+    /// an in-flight timeout or adaptive visibility.
+    pub fn begin_processing(&mut self, sqs_message: SqsMessage) {
+        if self.in_flight_timeout.is_none() && self.adaptive_visibility.is_none() {
+            return;
+        }
+        let message_id = match &sqs_message.message_id {
+            Some(message_id) => message_id.clone(),
+            None => return,
         };
+        self.in_flight.insert(message_id, (Instant::now(), sqs_message));
+        self.schedule_visibility_extension();
 
-        debug!("Emitting events");
-        self.event_emitter
-            .emit_event(serialized_event)
-            .await
-            .expect("Failed to emit event");
+        let deadline = match &self.in_flight_timeout {
+            Some((deadline, _)) => *deadline,
+            None => return,
+        };
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(deadline).await;
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::check_in_flight {}).await {
+                debug!("Failed to check in-flight messages: {}", e);
+            }
+        });
+    }
 
-        for identity in self.identities.drain(..) {
-            if let Err(e) = self.cache.store(identity).await {
-                warn!("Failed to cache with: {:?}", e);
+    fn end_processing(&mut self, sqs_message: &SqsMessage) {
+        let started = sqs_message
+            .message_id
+            .as_ref()
+            .and_then(|message_id| self.in_flight.remove(message_id))
+            .map(|(started, _)| started);
+
+        if let Some(adaptive_visibility) = &mut self.adaptive_visibility {
+            let processing_time = match started {
+                Some(started) => Some(started.elapsed()),
+                None => time_since_first_receive(sqs_message, SystemTime::now()),
+            };
+            if let Some(processing_time) = processing_time {
+                adaptive_visibility.observe(processing_time);
             }
+            self.schedule_visibility_extension();
         }
+    }
 
-        let mut acks = vec![];
-
-        for chunk in self.completed_messages.chunks(10) {
-            let msg_ids: Vec<String> = chunk
-                .iter()
-                .map(|msg| msg.message_id.clone().unwrap())
-                .collect();
-
-            let entries: Vec<_> = chunk
-                .iter()
-                .map(|msg| DeleteMessageBatchRequestEntry {
-                    id: msg.message_id.clone().unwrap(),
-                    receipt_handle: msg.receipt_handle.clone().expect("Message missing receipt"),
-                })
-                .collect();
+    fn schedule_visibility_extension(&mut self) {
+        if self.adaptive_visibility.is_none() || self.visibility_extension_scheduled {
+            return;
+        }
+        self.visibility_extension_scheduled = true;
+        // Extend immediately, the queue's own visibility timeout may be shorter than
+        // the learned one. Sent from a task, as the router can't wait on its own mailbox.
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::extend_visibility {}).await {
+                debug!("Failed to schedule visibility extension: {}", e);
+            }
+        });
+    }
 
-            match retry(10, || async {
+    /// Extends the visibility of every in-flight and buffered message to the adaptive
+    /// target, rescheduling itself at half the target while any remain.
+    async fn extend_visibility(&mut self) {
+        self.visibility_extension_scheduled = false;
+        let target = match &self.adaptive_visibility {
+            Some(adaptive_visibility) => adaptive_visibility.target(),
+            None => return,
+        };
 
-                let dmb = self.sqs_client
-                    .delete_message_batch(DeleteMessageBatchRequest {
-                        entries: entries.clone(),
-                        queue_url: self.queue_url.clone(),
-                    });
+        let messages: Vec<SqsMessage> = self
+            .in_flight
+            .values()
+            .map(|(_, sqs_message)| sqs_message)
+            .chain(self.completed_messages.iter())
+            .filter(|sqs_message| sqs_message.receipt_handle.is_some())
+            .cloned()
+            .collect();
+        if messages.is_empty() {
+            return;
+        }
 
-                tokio::time::timeout(Duration::from_millis(250), dmb).await
-            }).await {
-                Ok(dmb) => acks.push((dmb, msg_ids)),
-                Err(e) => warn!("Failed to delete message, timed out: {:?}", e),
-            };
+        debug!(
+            "Extending visibility of {} messages to {:?}",
+            messages.len(),
+            target
+        );
+        let failed = self
+            .change_visibility_batch(&messages, target.as_secs() as i64)
+            .await;
+        if !failed.is_empty() {
+            warn!("Failed to extend visibility of {} messages", failed.len());
         }
 
-        debug!("Acking all messages");
+        self.visibility_extension_scheduled = true;
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(target / 2).await;
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::extend_visibility {}).await {
+                debug!("Failed to extend visibility: {}", e);
+            }
+        });
+    }
 
-        for (result, msg_ids) in acks {
-            match result {
-                Ok(batch_result) => {
-                    for success in batch_result.successful {
-                        (self.on_ack)(self.self_actor.clone().unwrap(), Ok(success.id))
-                    }
+    /// Applies the in-flight timeout action to every message past its deadline.
+    fn check_in_flight(&mut self) {
+        let (deadline, action) = match &self.in_flight_timeout {
+            Some((deadline, action)) => (*deadline, action),
+            None => return,
+        };
 
-                    for failure in batch_result.failed {
-                        (self.on_ack)(self.self_actor.clone().unwrap(), Err(failure.id))
-                    }
-                }
-                Err(e) => {
-                    for msg_id in msg_ids {
-                        (self.on_ack)(self.self_actor.clone().unwrap(), Err(msg_id))
-                    }
-                    warn!("Failed to acknowledge event: {:?}", e);
-                }
+        let expired: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (started, _))| started.elapsed() >= deadline)
+            .map(|(message_id, _)| message_id.clone())
+            .collect();
+
+        for message_id in expired {
+            let (_, sqs_message) = self.in_flight.remove(&message_id).unwrap();
+            match action {
+                InFlightTimeoutAction::Log => warn!(
+                    "Message {} was not completed within {:?} of entering processing",
+                    message_id, deadline,
+                ),
+                InFlightTimeoutAction::Handle(handle) => handle(sqs_message),
             }
-            // (self.on_ack)(result, message_id);
         }
-        debug!("Acked");
-
-        self.completed_events.clear();
-        self.completed_messages.clear();
+    }
 
-        if let Some(notify) = notify {
-            let _ = notify.send(());
+    fn record_source_queue(&mut self, queue_url: String, sqs_message: &SqsMessage) {
+        if queue_url == self.queue_url {
+            return;
+        }
+        if let Some(message_id) = &sqs_message.message_id {
+            self.message_queues.insert(message_id.clone(), queue_url);
         }
     }
-}
-
-#[allow(non_camel_case_types)]
-pub enum SqsCompletionHandlerMessage<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    mark_complete {
-        msg: SqsMessage,
-        completed: OutputEvent<CE, ProcErr>,
-    },
-    ack_message {
-        msg: SqsMessage,
-    },
-    ack_all {
-        notify: Option<tokio::sync::oneshot::Sender<()>>,
-    },
-    _p {
-        _p: std::marker::PhantomData<(SqsT)>,
-    },
-}
 
-#[async_trait]
-impl<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
-    Actor<SqsCompletionHandlerMessage<CE, ProcErr, SqsT>>
-    for SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>
-where
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-    CPE: Debug + Send + Sync + 'static,
-    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
-        + Send
-        + Sync
-        + 'static,
-    Payload: Send + Sync + 'static,
-    CE: Send + Sync + Clone + 'static,
-    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
-    OA: Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
-        + Send
-        + Sync
-        + 'static,
-    CacheT: Cache + Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-{
-    #[tracing::instrument(skip(self, msg))]
-    async fn route_message(&mut self, msg: SqsCompletionHandlerMessage<CE, ProcErr, SqsT>) {
-        match msg {
-            SqsCompletionHandlerMessage::mark_complete { msg, completed } => {
-                self.mark_complete(msg, completed).await
-            }
-            SqsCompletionHandlerMessage::ack_all { notify } => self.ack_all(notify).await,
-            SqsCompletionHandlerMessage::ack_message { msg } => self.ack_message(msg).await,
-            SqsCompletionHandlerMessage::_p { .. } => (),
-        };
+    fn source_queue(&self, sqs_message: &SqsMessage) -> &str {
+        sqs_message
+            .message_id
+            .as_ref()
+            .and_then(|message_id| self.message_queues.get(message_id))
+            .unwrap_or(&self.queue_url)
     }
 
-    fn close(&mut self) {
-        self.self_actor = None;
+    /// Orders `messages` so that those from the same queue are contiguous, keeping
+    /// their relative order otherwise.
+    fn group_by_queue(&self, messages: &mut [SqsMessage]) {
+        messages.sort_by(|a, b| self.source_queue(a).cmp(self.source_queue(b)));
     }
 
-    fn get_actor_name(&self) -> &str {
-        &self.self_actor.as_ref().unwrap().actor_name
+    /// Splits `messages`, grouped by queue, into batches of at most 10 messages from
+    /// the same queue, the most SQS accepts per request. Returns each batch's range
+    /// and queue url.
+    fn queue_batches(&self, messages: &[SqsMessage]) -> Vec<(std::ops::Range<usize>, String)> {
+        let mut batches = vec![];
+        let mut start = 0;
+        while start < messages.len() {
+            let queue_url = self.source_queue(&messages[start]);
+            let end = messages[start..]
+                .iter()
+                .take(10)
+                .take_while(|msg| self.source_queue(msg) == queue_url)
+                .count()
+                + start;
+            batches.push((start..end, queue_url.to_owned()));
+            start = end;
+        }
+        batches
     }
-}
-
-pub struct SqsCompletionHandlerActor<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    sender: Sender<SqsCompletionHandlerMessage<CE, ProcErr, SqsT>>,
-    inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
-    queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
-    actor_name: String,
-    actor_uuid: uuid::Uuid,
-    actor_num: u32,
-}
 
-impl<CE, ProcErr, SqsT> Clone for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    fn clone(&self) -> Self {
-        self.inner_rc
-            .clone()
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-        Self {
-            sender: self.sender.clone(),
-            inner_rc: self.inner_rc.clone(),
-            queue_len: self.queue_len.clone(),
-            actor_name: format!(
-                "{} {} {}",
-                stringify!(SqsCompletionHandlerActor),
-                self.actor_uuid,
-                self.actor_num + 1,
-            ),
-            actor_uuid: self.actor_uuid,
-            actor_num: self.actor_num + 1,
+    /// Drops queue urls recorded for messages that are no longer buffered.
+    fn prune_message_queues(&mut self) {
+        if self.message_queues.is_empty() {
+            return;
         }
+        let buffered: HashSet<&String> = self
+            .completed_messages
+            .iter()
+            .filter_map(|msg| msg.message_id.as_ref())
+            .collect();
+        let message_queues = std::mem::replace(&mut self.message_queues, HashMap::new());
+        self.message_queues = message_queues
+            .into_iter()
+            .filter(|(message_id, _)| buffered.contains(message_id))
+            .collect();
     }
-}
 
-impl<CE, ProcErr, SqsT> SqsCompletionHandlerActor<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    pub fn new<CPE, CP, Payload, EE, OA, CacheT>(
-        mut actor_impl: SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, OA, CacheT, ProcErr>,
-    ) -> (Self, tokio::task::JoinHandle<()>)
-    where
-        SqsT: Sqs + Clone + Send + Sync + 'static,
-        CPE: Debug + Send + Sync + 'static,
-        CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
-            + Send
-            + Sync
-            + 'static,
-        Payload: Send + Sync + 'static,
-        EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
-        OA: Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
-            + Send
-            + Sync
-            + 'static,
-        CacheT: Cache + Send + Sync + Clone + 'static,
-    {
-        let (sender, receiver) = channel(1);
-        let inner_rc = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(1));
-
-        let queue_len = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
-
-        let actor_uuid = uuid::Uuid::new_v4();
-        let actor_name = format!("{} {} {}", stringify!(#actor_ty), actor_uuid, 0,);
-        let mut self_actor = Self {
-            sender,
-            inner_rc: inner_rc.clone(),
-            queue_len: queue_len.clone(),
-            actor_name,
-            actor_uuid,
-            actor_num: 0,
-        };
-
-        actor_impl.self_actor = Some(self_actor.clone());
-
-        let handle = tokio::task::spawn(aktors::actor::route_wrapper(aktors::actor::Router::new(
-            actor_impl, receiver, inner_rc, queue_len,
-        )));
-
-        (self_actor, handle)
-    }
-
-    pub async fn mark_complete(&self, msg: SqsMessage, completed: OutputEvent<CE, ProcErr>) {
-        let msg = SqsCompletionHandlerMessage::mark_complete { msg, completed };
-        let mut sender = self.sender.clone();
-
-        let queue_len = self.queue_len.clone();
-        queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    #[tracing::instrument(skip(self, completed))]
+    pub async fn mark_complete(
+        &mut self,
+        sqs_message: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    ) -> Option<AckSummary> {
+        self.end_processing(&sqs_message);
+        match &completed.completed_event {
+            Completion::Total(_) => self.stats.add_completion_total(),
+            Completion::Partial(_) => self.stats.add_completion_partial(),
+            Completion::Error(_) => self.stats.add_completion_error(),
+        }
 
-        tokio::task::spawn(async move {
-            if let Err(e) = sender.send(msg).await {
-                panic!(
-                        "Receiver has failed with {}, propagating error. SqsCompletionHandler",
-                    e
-                )
+        if let Some(reason) = self.message_checks.rejection(&sqs_message) {
+            self.reject_completion(sqs_message, completed.completed_event, reason);
+            return self.flush_if_due().await;
+        }
+
+        if self.deadline_passed(&sqs_message) {
+            if let Completion::Total(_) | Completion::Partial(_) = completed.completed_event {
+                info!(
+                    "Deadline of message {:?} has passed, dropping its event",
+                    sqs_message.message_id
+                );
+                self.stats.add_event_expired();
             }
-        });
-    }
+            self.completed_messages.push(sqs_message);
+            return self.flush_if_due().await;
+        }
 
-    pub async fn ack_message(&self, msg: SqsMessage) {
-        let msg = SqsCompletionHandlerMessage::ack_message { msg  };
-        let mut sender = self.sender.clone();
+        let mut completed = completed;
+        self.dedup.derive_identities(&sqs_message, &mut completed.identities);
 
-        let queue_len = self.queue_len.clone();
-        queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let is_duplicate = match &completed.completed_event {
+            Completion::Error(_) => false,
+            _ => self.ack_if_duplicate(&sqs_message, &completed.identities).await,
+        };
 
-        tokio::task::spawn(async move {
-            if let Err(e) = sender.send(msg).await {
-                panic!(
-                    concat!(
-                    "Receiver has failed with {}, propagating error. ",
-                    "SqsCompletionHandler"
-                    ),
-                    e
-                )
+        if is_duplicate {
+            info!("Dropping duplicate event, contributing messages will be acked");
+        } else {
+            let was_empty = self.buffered_len() == 0;
+            self.buffer_completed(sqs_message, completed).await;
+            if was_empty && self.buffered_len() > 0 {
+                if let Some(on_first_buffered) = &self.on_first_buffered {
+                    on_first_buffered();
+                }
             }
-        });
-    }
-
-    async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
-        let msg = SqsCompletionHandlerMessage::ack_all { notify };
-        let mut sender = self.sender.clone();
+        }
 
-        let queue_len = self.queue_len.clone();
-        queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        info!(
+            "Marked event complete. {} completed events, {} completed messages",
+            self.completed_events.len(),
+            self.completed_messages.len(),
+        );
 
-        tokio::task::spawn(async move {
-            if let Err(e) = sender.send(msg).await {
-                panic!(
-                        "Receiver has failed with {}, propagating error. SqsCompletionHandler",
-                    e
-                )
-            }
-        });
+        self.flush_if_due().await
     }
 
-    async fn _p(&self, _p: std::marker::PhantomData<(SqsT)>) {
-        panic!("Invalid to call p");
-        let msg = SqsCompletionHandlerMessage::_p { _p };
-        if let Err(_e) = self.sender.clone().send(msg).await {
-            panic!("Receiver has failed, propagating error. _p")
+    /// Flushes if the completion policy calls for it, returning the flush's summary.
+    async fn flush_if_due(&mut self) -> Option<AckSummary> {
+        if !self.flush_due() {
+            return None;
         }
-        self.queue_len
-            .clone()
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let summary = self.ack_all(None).await;
+        self.completion_policy.set_last_flush();
+        Some(summary)
     }
-}
 
-impl<CE, ProcErr, SqsT> Drop for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    fn drop(&mut self) {
-        self.inner_rc
-            .clone()
-            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    /// Dead-letters the event of an untrusted message and queues the message for
+    /// deletion with the next flush, as redelivering it won't make it trustworthy.
+    fn reject_completion(
+        &mut self,
+        sqs_message: SqsMessage,
+        completed_event: Completion<CE, ProcErr>,
+        reason: String,
+    ) {
+        warn!("Rejecting message {:?}: {}", sqs_message.message_id, reason);
+        let ce = match completed_event {
+            Completion::Total(ce) | Completion::Partial((ce, _)) => Some(ce),
+            Completion::Error(_) => None,
+        };
+        match (ce, &self.dead_letter) {
+            (Some(ce), Some(dead_letter)) => {
+                dead_letter(DeadLetter::new(ce, sqs_message.message_id.clone(), reason))
+            }
+            (Some(_), None) => warn!("No dead-letter sink configured, dropping event"),
+            (None, _) => (),
+        }
+        self.completed_messages.push(sqs_message);
+    }
+
+    async fn buffer_completed(&mut self, sqs_message: SqsMessage, completed: OutputEvent<CE, ProcErr>) {
+        // A total event supersedes a partial one buffered for the same identity, eg:
+        // when a retry of the partially processed message succeeds
+        let superseded = match &completed.completed_event {
+            Completion::Total(_) if self.streaming.is_none() => completed
+                .identities
+                .iter()
+                .find_map(|identity| self.buffered_partials.get(identity).copied()),
+            _ => None,
+        };
+
+        let already_buffered = superseded.is_none()
+            && self.dedup.coalesce_duplicates
+            && completed
+                .identities
+                .iter()
+                .any(|identity| self.identities.contains(identity));
+
+        if already_buffered {
+            match completed.completed_event {
+                Completion::Total(_) => {
+                    info!("Coalescing event already buffered in this window");
+                    self.completed_messages.push(sqs_message);
+                    return;
+                }
+                Completion::Partial(_) => {
+                    info!("Coalescing partial event already buffered in this window");
+                    return;
+                }
+                Completion::Error(_) => (),
+            }
+        }
+
+        if let (Some(side_output), Some(_)) = (completed.side_output, &self.side_emitter) {
+            self.side_outputs.push(side_output);
+        }
+
+        match completed.completed_event {
+            Completion::Total(ce) => {
+                info!("Marking all events complete - total success");
+                let (ce, size) = match self.check_event_size(ce, &sqs_message.message_id) {
+                    SizeCheck::Fits(ce, size) => (ce, size),
+                    SizeCheck::DeadLettered => {
+                        self.completed_messages.push(sqs_message);
+                        return;
+                    }
+                    SizeCheck::Rejected => return,
+                };
+                if self.streaming.is_some() {
+                    let meta = self.source_meta(&sqs_message);
+                    if !self.stream_event(ce, meta).await {
+                        return;
+                    }
+                } else if let Some(index) = superseded {
+                    info!("Replacing buffered partial event with a total one");
+                    self.buffered_partials.retain(|_, partial| *partial != index);
+                    self.record_buffered_size(&ce, size);
+                    self.completed_events[index] = ce;
+                    if self.completed_event_sources[index].is_none() {
+                        self.events_without_messages -= 1;
+                    }
+                    self.completed_event_sources[index] = sqs_message.message_id.clone();
+                } else {
+                    self.append_to_wal(&ce, Some(&sqs_message));
+                    self.record_buffered_size(&ce, size);
+                    self.completed_events.push(ce);
+                    self.completed_event_sources.push(sqs_message.message_id.clone());
+                    self.completed_event_identities.push(completed.identities.clone());
+                }
+                self.completed_messages.push(sqs_message);
+                if let Some(index) = superseded {
+                    // The partial's identities are already buffered
+                    let identities: Vec<Vec<u8>> = completed
+                        .identities
+                        .into_iter()
+                        .filter(|identity| !self.identities.contains(identity))
+                        .collect();
+                    self.completed_event_identities[index].extend(identities.iter().cloned());
+                    self.identities.extend(identities);
+                    // The WAL still holds the partial event
+                    self.rewrite_wal();
+                } else {
+                    self.identities.extend(completed.identities);
+                }
+            }
+            Completion::Partial((ce, err)) => {
+                warn!("EventHandler was only partially successful: {:?}", err);
+                self.stats.record_proc_error(&err);
+                let (ce, size) = match self.check_event_size(ce, &None) {
+                    SizeCheck::Fits(ce, size) => (ce, size),
+                    SizeCheck::DeadLettered | SizeCheck::Rejected => return,
+                };
+                match self.streaming {
+                    Some(streaming) => {
+                        if !streaming.emit_partial
+                            || !self
+                                .stream_event(
+                                    ce,
+                                    EventMeta {
+                                        schema_version: self.schema_version,
+                                        ..EventMeta::default()
+                                    },
+                                )
+                                .await
+                        {
+                            return;
+                        }
+                    }
+                    None => {
+                        self.stats.add_event_without_message();
+                        self.events_without_messages += 1;
+                        self.append_to_wal(&ce, None);
+                        self.record_buffered_size(&ce, size);
+                        for identity in &completed.identities {
+                            self.buffered_partials
+                                .insert(identity.clone(), self.completed_events.len());
+                        }
+                        self.completed_events.push(ce);
+                        self.completed_event_sources.push(None);
+                        self.completed_event_identities.push(completed.identities.clone());
+                    }
+                }
+                self.identities.extend(completed.identities);
+            }
+            Completion::Error(e) => {
+                warn!("Event handler failed: {:?}", e);
+                self.stats.record_proc_error(&e);
+                if self.redelivery_limit_reached(&sqs_message).await {
+                    if let Some((_, on_poison)) = &self.redelivery_limit {
+                        on_poison(sqs_message.clone());
+                    }
+                    // Deleting the message is what breaks the loop
+                    self.completed_messages.push(sqs_message);
+                }
+            }
+        };
+
+        self.report_proc_errors(false);
+        self.check_divergence();
+    }
+
+    /// Whether the deadline in the `deadline_attribute` message attribute, if
+    /// configured and present, has passed.
+    fn deadline_passed(&self, sqs_message: &SqsMessage) -> bool {
+        let deadline_millis = self
+            .deadline_attribute
+            .as_ref()
+            .and_then(|attribute| sqs_message.message_attributes.as_ref()?.get(attribute))
+            .and_then(|value| value.string_value.as_ref()?.parse::<u64>().ok());
+        match deadline_millis {
+            Some(deadline_millis) => {
+                SystemTime::now() > UNIX_EPOCH + Duration::from_millis(deadline_millis)
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed completion of `sqs_message` in the cache, returning true once
+    /// it has failed more than the redelivery limit allows. Failures are stored as
+    /// one identity per attempt, so any `Cache` can hold the count.
+    async fn redelivery_limit_reached(&mut self, sqs_message: &SqsMessage) -> bool {
+        let (limit, message_id) = match (&self.redelivery_limit, &sqs_message.message_id) {
+            (Some((limit, _)), Some(message_id)) => (*limit, message_id.clone()),
+            _ => return false,
+        };
+        let failure_identity =
+            |attempt: u32| format!("redelivery:{}:{}", message_id, attempt).into_bytes();
+
+        let mut failures = 0;
+        while failures < limit {
+            match self.cache.get(Identity(failure_identity(failures + 1))).await {
+                Ok(CacheResponse::Hit) => failures += 1,
+                Ok(CacheResponse::Miss) => break,
+                Err(e) => {
+                    warn!("Failed to read redelivery count with: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        if failures >= limit {
+            warn!(
+                "Message {} failed {} times, giving up on redelivering it",
+                message_id,
+                failures + 1,
+            );
+            return true;
+        }
+
+        if let Err(e) = self.cache.store(failure_identity(failures + 1)).await {
+            warn!("Failed to record redelivery count with: {:?}", e);
+        }
+        false
+    }
+
+    fn append_to_wal(&mut self, event: &CE, message: Option<&SqsMessage>) {
+        if let Some(wal) = &mut self.wal {
+            let entry = WalEntry {
+                event: event.clone(),
+                message: message.cloned(),
+            };
+            if let Err(e) = wal.append(&entry) {
+                warn!("Failed to append to WAL: {:?}", e);
+                self.stats.add_wal_failure();
+            }
+        }
+    }
+
+    /// Called once a flush completes, so the WAL holds only what is still buffered.
+    fn rewrite_wal(&mut self) {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return,
+        };
+
+        if let Err(e) = wal.truncate() {
+            warn!("Failed to truncate WAL: {:?}", e);
+            self.stats.add_wal_failure();
+            return;
+        }
+
+        let messages: HashMap<&String, &SqsMessage> = self
+            .completed_messages
+            .iter()
+            .filter_map(|msg| Some((msg.message_id.as_ref()?, msg)))
+            .collect();
+        for (event, source) in self.completed_events.iter().zip(&self.completed_event_sources) {
+            let entry = WalEntry {
+                event: event.clone(),
+                message: source
+                    .as_ref()
+                    .and_then(|source| messages.get(source))
+                    .map(|msg| (*msg).clone()),
+            };
+            if let Err(e) = wal.append(&entry) {
+                warn!("Failed to append to WAL: {:?}", e);
+                self.stats.add_wal_failure();
+            }
+        }
+    }
+
+    /// Adds `ce` to the running size of the buffer, serializing it on its own if its
+    /// size wasn't already measured. Does nothing without `max_buffer_bytes`.
+    fn record_buffered_size(&mut self, ce: &CE, size: Option<usize>) {
+        if self.max_buffer_bytes.is_none() {
+            return;
+        }
+        let size = match size {
+            Some(size) => size,
+            None => match exclusive(&self.completion_serializer)
+                .serialized_size(std::slice::from_ref(ce))
+            {
+                Ok(size) => size,
+                // Serialization errors are surfaced when the batch is flushed
+                Err(_) => return,
+            },
+        };
+        self.buffered_bytes += size;
+    }
+
+    fn check_event_size(&mut self, ce: CE, message_id: &Option<String>) -> SizeCheck<CE> {
+        let (max_event_bytes, policy) = match &self.max_event_bytes {
+            Some((max_event_bytes, policy)) => (*max_event_bytes, policy),
+            None => return SizeCheck::Fits(ce, None),
+        };
+
+        let size = match exclusive(&self.completion_serializer)
+            .serialized_size(std::slice::from_ref(&ce))
+        {
+            Ok(size) => size,
+            // Serialization errors are surfaced when the batch is flushed
+            Err(_) => return SizeCheck::Fits(ce, None),
+        };
+
+        if size <= max_event_bytes {
+            return SizeCheck::Fits(ce, Some(size));
+        }
+
+        warn!("Event of {} bytes exceeds max_event_bytes {}", size, max_event_bytes);
+        match policy {
+            OversizedEventPolicy::DeadLetter => {
+                let reason = format!("Event of {} bytes exceeds {} bytes", size, max_event_bytes);
+                match &self.dead_letter {
+                    Some(dead_letter) => dead_letter(DeadLetter::new(ce, message_id.clone(), reason)),
+                    None => warn!("No dead-letter sink configured, dropping oversized event"),
+                }
+                SizeCheck::DeadLettered
+            }
+            OversizedEventPolicy::Truncate(truncate) => SizeCheck::Fits(truncate(ce), None),
+            OversizedEventPolicy::Error => {
+                error!("Dropping oversized event, its message will be redelivered");
+                SizeCheck::Rejected
+            }
+        }
+    }
+
+    /// Serializes and emits a single event. Returns false if it could not be
+    /// serialized, in which case its message should not be acked.
+    async fn stream_event(&mut self, ce: CE, meta: EventMeta) -> bool {
+        let metadata = self.emit_metadata(std::slice::from_ref(&ce), false);
+        let serialized_event = match exclusive(&self.completion_serializer)
+            .serialize_completed_events_with_meta(&[ce], &[meta])
+        {
+            Ok(serialized_event) => serialized_event,
+            Err(e) => {
+                warn!("Serializing streamed event failed: {:?}", e);
+                return false;
+            }
+        };
+
+        let receipt = match self.emit(serialized_event, metadata).await {
+            Ok(receipt) => receipt,
+            // Already logged by emit
+            Err(_) => return false,
+        };
+        if !receipt.rejected().is_empty() {
+            warn!("Streamed event was rejected downstream");
+            return false;
+        }
+        self.stats.add_events_emitted(1);
+        true
+    }
+
+    fn source_meta(&self, sqs_message: &SqsMessage) -> EventMeta {
+        EventMeta {
+            source_message_id: sqs_message.message_id.clone(),
+            source_body: if self.retain_source_body {
+                sqs_message.body.clone()
+            } else {
+                None
+            },
+            schema_version: self.schema_version,
+        }
+    }
+
+    /// Metadata for each buffered event. Source bodies are looked up from the
+    /// buffered messages rather than stored a second time.
+    fn buffered_meta(&self) -> Vec<EventMeta> {
+        let bodies: HashMap<&String, &String> = if self.retain_source_body {
+            self.completed_messages
+                .iter()
+                .filter_map(|msg| Some((msg.message_id.as_ref()?, msg.body.as_ref()?)))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+
+        self.completed_event_sources
+            .iter()
+            .map(|source| EventMeta {
+                source_message_id: source.clone(),
+                source_body: source
+                    .as_ref()
+                    .and_then(|source| bodies.get(source))
+                    .map(|body| (*body).clone()),
+                schema_version: self.schema_version,
+            })
+            .collect()
+    }
+
+    /// Whether the completion policy calls for a flush and the flush gate, if any,
+    /// allows it. Never while paused.
+    fn flush_due(&self) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        let buffered_len = self.buffered_len();
+        let buffer_full = match self.max_buffer_bytes {
+            Some(max_buffer_bytes) => self.buffered_bytes >= max_buffer_bytes,
+            None => false,
+        };
+        if !buffer_full && !self.completion_policy.should_flush(buffered_len as u16) {
+            return false;
+        }
+
+        match &self.flush_gate {
+            Some((flush_gate, max_deferred)) if !flush_gate() => {
+                if buffered_len >= *max_deferred {
+                    warn!(
+                        "Flush gate is closed but {} items are buffered, flushing anyway",
+                        buffered_len
+                    );
+                    true
+                } else {
+                    debug!("Flush gate is closed, deferring flush");
+                    false
+                }
+            }
+            _ => true,
+        }
+    }
+
+    /// In streaming mode events are emitted immediately, so only messages are
+    /// buffered.
+    fn buffered_len(&self) -> usize {
+        if self.streaming.is_some() {
+            self.completed_messages.len()
+        } else {
+            self.completed_events.len()
+        }
+    }
+
+    fn emit_metadata(&self, events: &[CE], degraded: bool) -> EmitMetadata {
+        // A batch is delayed by the longest delay of any of its events, so that none
+        // are delivered early
+        let delay = self
+            .delay_fn
+            .as_ref()
+            .and_then(|delay_fn| events.iter().filter_map(|event| delay_fn(event)).max());
+
+        EmitMetadata {
+            traceparent: self.trace_context.as_ref().and_then(|trace_context| trace_context()),
+            source_queue: Some(self.queue_name.clone()),
+            degraded,
+            delay,
+            content_encoding: None,
+            partition_key: None,
+            shard_id: None,
+            idempotency_token: None,
+            schema_version: self.schema_version,
+            message_group_id: self
+                .message_group_id_fn
+                .as_ref()
+                .map(|message_group_id_fn| message_group_id_fn(events)),
+        }
+    }
+
+    /// Merges the buffered events with the compactor, if any. Compacted events no
+    /// longer have a single source message, so their sources are cleared and
+    /// returned, and each is given the identities of the whole batch.
+    fn compact_buffer(&mut self) -> Vec<String> {
+        let compactor = match &self.compactor {
+            Some(compactor) => compactor,
+            None => return vec![],
+        };
+
+        let events = std::mem::replace(&mut self.completed_events, Vec::new());
+        let event_count = events.len();
+        self.completed_events = compactor(events);
+        debug!(
+            "{}Compacted {} events into {}",
+            self.flush_tag(),
+            event_count,
+            self.completed_events.len()
+        );
+
+        let sources = std::mem::replace(
+            &mut self.completed_event_sources,
+            vec![None; self.completed_events.len()],
+        );
+        let identities: Vec<Vec<u8>> = self.completed_event_identities.drain(..).flatten().collect();
+        self.completed_event_identities = vec![identities; self.completed_events.len()];
+        sources.into_iter().flatten().collect()
+    }
+
+    /// Serializes `events` across `parallel_serialize` blocking workers,
+    /// each taking a contiguous chunk, and combines their outputs in order. Returns
+    /// None if parallel serialization is disabled, the serializer doesn't support
+    /// `serialize_one` or a worker panicked, in which case the batch should be
+    /// serialized sequentially. Event metadata is not available to `serialize_one`.
+    async fn serialize_parallel(&mut self, events: &[CE]) -> Option<Result<Vec<Payload>, CPE>> {
+        let workers = self.parallel_serialize?;
+        let first = events.first()?;
+        // Probe whether the serializer supports serialize_one before spawning workers
+        if shared(&self.completion_serializer).serialize_one(first).is_none() {
+            return None;
+        }
+
+        let chunk_size = (events.len() + workers - 1) / workers;
+        let chunks: Vec<_> = events
+            .chunks(chunk_size.max(1))
+            .map(|chunk| {
+                let chunk = chunk.to_vec();
+                let serializer = self.completion_serializer.clone();
+                tokio::task::spawn_blocking(move || {
+                    let serializer = shared(&serializer);
+                    chunk
+                        .iter()
+                        .map(|event| serializer.serialize_one(event))
+                        .collect::<Option<Result<Vec<_>, _>>>()
+                })
+            })
+            .collect();
+
+        let mut outputs = Vec::with_capacity(events.len());
+        for chunk in futures::future::join_all(chunks).await {
+            match chunk {
+                Ok(Some(Ok(chunk))) => outputs.extend(chunk),
+                Ok(Some(Err(e))) => return Some(Err(e)),
+                // The serializer declined an event after accepting the probe
+                Ok(None) => return None,
+                Err(e) => {
+                    warn!("Serialization worker failed, serializing sequentially: {:?}", e);
+                    return None;
+                }
+            }
+        }
+
+        Some(Ok(shared(&self.completion_serializer).combine(outputs)))
+    }
+
+    /// Prefix for log lines written during a flush, see `ack_all`.
+    fn flush_tag(&self) -> String {
+        match self.flush_id {
+            Some(flush_id) => format!("[flush {}] ", flush_id),
+            None => String::new(),
+        }
+    }
+
+    fn describe_payloads(&self, payloads: &[Payload]) -> String {
+        let described: Vec<String> = payloads.iter().map(|payload| (self.redactor)(payload)).collect();
+        described.join(", ")
+    }
+
+    /// Emits `serialized_event`, retrying as configured by `emit_retry`. Returns the
+    /// last error once every attempt has failed, in which case none of the events
+    /// should be considered emitted.
+    async fn emit(
+        &mut self,
+        serialized_event: Vec<Payload>,
+        mut metadata: EmitMetadata,
+    ) -> Result<EmitReceipt, EE::Error> {
+        // Dedup-only handlers accept everything without emitting it
+        if self.event_emitter.is_none() {
+            return Ok(EmitReceipt::accepted());
+        }
+        if self.dry_run.is_some() {
+            info!(
+                "{}Dry run, would emit: [{}]",
+                self.flush_tag(),
+                self.describe_payloads(&serialized_event)
+            );
+            return Ok(EmitReceipt::accepted());
+        }
+
+        if self.idempotency_tokens {
+            metadata.idempotency_token = Some(idempotency_token(&serialized_event));
+        }
+
+        debug!(
+            "{}Emitting events: [{}]",
+            self.flush_tag(),
+            self.describe_payloads(&serialized_event)
+        );
+
+        let started = Instant::now();
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let emitted = self
+                .event_emitter
+                .as_mut()
+                .expect("Checked above")
+                .emit_event_with_receipt(serialized_event.clone(), metadata.clone())
+                .await;
+            match emitted {
+                Ok(receipt) => {
+                    self.completion_policy
+                        .record_emit(attempt > 1, started.elapsed());
+                    self.emitted_bytes += serialized_event
+                        .iter()
+                        .map(|payload| payload.as_ref().len())
+                        .sum::<usize>();
+                    return Ok(receipt);
+                }
+                Err(e) if attempt < self.emit_retry.max_attempts() => {
+                    warn!("{}Failed to emit event, attempt {}: {:?}", self.flush_tag(), attempt, e);
+                    tokio::time::delay_for(self.emit_retry.backoff_after(attempt)).await;
+                }
+                Err(e) => {
+                    self.completion_policy.record_emit(true, started.elapsed());
+                    error!(
+                        "{}Failed to emit event after {} attempts: {:?}, payloads: [{}]",
+                        self.flush_tag(),
+                        attempt,
+                        e,
+                        self.describe_payloads(&serialized_event),
+                    );
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Whether more of the buffered events than the divergence threshold allows have
+    /// no source message.
+    fn diverged(&self) -> bool {
+        if self.completed_events.is_empty() {
+            return false;
+        }
+
+        let ratio = self.events_without_messages as f64 / self.completed_events.len() as f64;
+        ratio > self.divergence_threshold
+    }
+
+    fn check_divergence(&self) {
+        if self.diverged() {
+            warn!(
+                "{} of {} buffered events have no source message, this may indicate a bug in the EventHandler",
+                self.events_without_messages,
+                self.completed_events.len(),
+            );
+        }
+    }
+
+    /// Records `sqs_message` as a source of each of `identities`. With
+    /// `dedup_on_complete`, if any identity has already been cached, every message that
+    /// contributed to it is queued for deletion and `true` is returned.
+    async fn ack_if_duplicate(&mut self, sqs_message: &SqsMessage, identities: &[Vec<u8>]) -> bool {
+        if let Some(message_id) = &sqs_message.message_id {
+            if !identities.is_empty() {
+                self.source_messages
+                    .entry(message_id.clone())
+                    .or_insert_with(|| sqs_message.clone());
+            }
+            for identity in identities {
+                self.identity_sources
+                    .entry(identity.clone())
+                    .or_default()
+                    .push(message_id.clone());
+            }
+        }
+
+        if !self.dedup.dedup_on_complete {
+            return false;
+        }
+
+        let mut is_duplicate = false;
+        for identity in identities {
+            match self.cache.exists(Identity(identity.clone())).await {
+                Ok(true) => {
+                    is_duplicate = true;
+                    self.ack_identity_sources(identity);
+                }
+                Ok(false) => (),
+                Err(e) => warn!("Failed to check cache with: {:?}", e),
+            }
+        }
+        is_duplicate
+    }
+
+    fn ack_identity_sources(&mut self, identity: &[u8]) {
+        let sources = match self.identity_sources.remove(identity) {
+            Some(sources) => sources,
+            None => return,
+        };
+
+        for source in sources {
+            let already_acked = self
+                .completed_messages
+                .iter()
+                .any(|msg| msg.message_id.as_ref() == Some(&source));
+            if already_acked {
+                continue;
+            }
+            if let Some(msg) = self.source_messages.get(&source) {
+                self.completed_messages.push(msg.clone());
+            }
+        }
+    }
+
+    /// Drops contributing messages that no identity refers to any more.
+    fn prune_source_messages(&mut self) {
+        let referenced: HashSet<&String> = self.identity_sources.values().flatten().collect();
+        let source_messages = std::mem::replace(&mut self.source_messages, HashMap::new());
+        self.source_messages = source_messages
+            .into_iter()
+            .filter(|(message_id, _)| referenced.contains(message_id))
+            .collect();
+    }
+
+    /// The identities of buffered events, which will be cached by the next flush.
+    pub fn pending_identities(&self) -> Vec<Vec<u8>> {
+        self.identities.clone()
+    }
+
+    /// The most recently cached identities, oldest first, for debugging duplicate
+    /// suppression.
+    pub fn recently_cached(&self) -> Vec<Vec<u8>> {
+        self.recently_cached.iter().cloned().collect()
+    }
+
+    /// A copy of everything buffered, with the policy's state and lifetime stats, for
+    /// debugging dumps. See `HandlerSnapshot::to_json`.
+    pub fn snapshot(&self) -> HandlerSnapshot<CE> {
+        let buffered_message = |msg: &SqsMessage| BufferedMessage {
+            message_id: msg.message_id.clone(),
+            receipt_handle: msg.receipt_handle.clone(),
+        };
+
+        HandlerSnapshot {
+            events: self.completed_events.clone(),
+            event_sources: self.completed_event_sources.clone(),
+            messages: self.completed_messages.iter().map(buffered_message).collect(),
+            pending_deletes: self
+                .pending_deletes
+                .iter()
+                .map(|(_, msg)| buffered_message(msg))
+                .collect(),
+            identities: self.identities.clone(),
+            policy: PolicySnapshot {
+                max_messages: self.completion_policy.max_messages(),
+                schedule: self.completion_policy.schedule(),
+                since_last_flush: self.completion_policy.since_last_flush(),
+            },
+            paused: self.paused,
+            flush_count: self.flush_count,
+            events_emitted: self.stats.events_emitted(),
+            messages_deleted: self.stats.messages_deleted(),
+            delete_failures: self.stats.delete_failures(),
+            completion_counts: self.stats.completion_counts(),
+        }
+    }
+
+    /// Whether `identity` has already been seen, either cached by a previous flush or
+    /// buffered for the next one.
+    #[tracing::instrument(skip(self, identity))]
+    pub async fn is_duplicate(&mut self, identity: Vec<u8>) -> bool {
+        if self.identities.contains(&identity) {
+            return true;
+        }
+
+        match self.cache.exists(Identity(identity)).await {
+            Ok(exists) => exists,
+            Err(e) => {
+                warn!("Failed to check cache with: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Replaces the completion policy, keeping the time of the last flush. The new
+    /// policy applies from the next completion.
+    pub fn update_policy(&mut self, mut completion_policy: CompletionPolicy) {
+        completion_policy.inherit_last_flush(&self.completion_policy);
+        self.completion_policy = completion_policy;
+    }
+
+    /// Checks that SQS is reachable and `queue_url` exists, so operators can hold off
+    /// declaring the handler ready until it can actually delete messages.
+    #[tracing::instrument(skip(self))]
+    pub async fn healthcheck(&self) -> Result<(), HealthError> {
+        self.sqs_client
+            .get_queue_attributes(GetQueueAttributesRequest {
+                queue_url: self.queue_url.clone(),
+                attribute_names: Some(vec!["QueueArn".to_owned()]),
+            })
+            .await
+            .map(|_| ())
+    }
+
+    /// Gives up on everything buffered without emitting or deleting it. Events go to
+    /// the dead-letter sink and every buffered message is reported to `on_ack` as
+    /// failed, so SQS will redeliver them.
+    #[tracing::instrument(skip(self))]
+    pub async fn abandon_buffer(&mut self, reason: String) {
+        warn!(
+            "Abandoning {} events and {} messages: {}",
+            self.completed_events.len(),
+            self.completed_messages.len(),
+            reason,
+        );
+
+        self.completed_event_identities.clear();
+        let events = self.completed_events.drain(..);
+        let sources = self.completed_event_sources.drain(..);
+        for (event, message_id) in events.zip(sources) {
+            match &self.dead_letter {
+                Some(dead_letter) => dead_letter(DeadLetter::new(event, message_id, reason.clone())),
+                None => warn!("No dead-letter sink configured, dropping abandoned event"),
+            }
+        }
+
+        for msg in self.completed_messages.drain(..) {
+            if let Some(message_id) = msg.message_id {
+                (self.on_ack)(self.self_actor.clone().unwrap(), Err(message_id));
+            }
+        }
+
+        self.identities.clear();
+        self.identity_sources.clear();
+        self.source_messages.clear();
+        self.buffered_partials.clear();
+        self.message_queues.clear();
+        self.events_without_messages = 0;
+        self.undeleted_batches.clear();
+        self.publish_pending_deletes();
+        self.rewrite_wal();
+    }
+
+    /// Replaces the `on_ack` callback, eg: to switch from logging failed deletes to
+    /// alerting on them. Deletes acked after this call use `on_ack`.
+    pub fn update_on_ack(&mut self, on_ack: OnAck<CE, ProcErr, SqsT>) {
+        info!("Replacing the on_ack callback");
+        self.on_ack = on_ack;
+    }
+
+    /// Stops flushing, completions keep being buffered and flush requests are held
+    /// until `resume`. A shutdown still flushes.
+    pub fn pause(&mut self) {
+        if !self.paused {
+            info!("Pausing flushes with {} items buffered", self.buffered_len());
+        }
+        self.paused = true;
+    }
+
+    /// Resumes flushing, performing any flush that was requested or became due while
+    /// paused.
+    pub async fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        self.paused = false;
+        info!("Resuming flushes with {} items buffered", self.buffered_len());
+
+        if self.pending_flush.is_some() || self.flush_due() {
+            self.ack_all(None).await;
+            self.completion_policy.set_last_flush();
+        }
+    }
+
+    /// Handles a `shutdown` request. Marks sent before it may still be waiting for
+    /// space in the mailbox, so the request is requeued behind them until the mailbox
+    /// is drained, or the shutdown timeout has passed, before the final flush.
+    async fn request_shutdown(&mut self, respond: tokio::sync::oneshot::Sender<ShutdownSummary>) {
+        let shutdown_started = *self.shutdown_started.get_or_insert_with(Instant::now);
+        let queued = self
+            .self_actor
+            .as_ref()
+            .map(|self_actor| self_actor.mailbox_len())
+            .unwrap_or_default();
+
+        if queued > 0 && shutdown_started.elapsed() < self.shutdown_timeout {
+            debug!("Draining {} queued messages before shutting down", queued);
+            // Sent from a task, as the router can't wait on its own mailbox
+            let self_actor = self.self_actor.clone().unwrap();
+            tokio::task::spawn(async move {
+                if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::shutdown { respond }).await {
+                    warn!("Failed to requeue shutdown: {}", e);
+                }
+            });
+            return;
+        }
+
+        if queued > 0 {
+            warn!(
+                "Shutting down with {} messages still queued after {:?}",
+                queued,
+                shutdown_started.elapsed()
+            );
+        }
+        self.shutdown_started = None;
+        let _ = respond.send(self.shutdown().await);
+    }
+
+    /// Performs a final flush, giving up after the shutdown timeout so that a hung
+    /// downstream can't block termination. Flushes even while paused.
+    ///
+    /// The timeout applies to each phase of the flush as an ack deadline does, so a
+    /// flush that runs out of time leaves the buffer consistent, holding whatever it
+    /// didn't get to.
+    #[tracing::instrument(skip(self))]
+    pub async fn shutdown(&mut self) -> ShutdownSummary {
+        let shutdown_timeout = self.shutdown_timeout;
+        let summary = self
+            .flush_before(None, Some(Instant::now() + shutdown_timeout))
+            .await;
+
+        match summary.timed_out {
+            None => ShutdownSummary::Flushed,
+            Some(phase) => {
+                let lost_events = self.completed_events.len();
+                let lost_messages = self.completed_messages.len();
+                error!(
+                    "Final flush timed out after {:?} while in {:?}, lost {} events and {} messages",
+                    shutdown_timeout, phase, lost_events, lost_messages,
+                );
+                ShutdownSummary::TimedOut {
+                    lost_events,
+                    lost_messages,
+                }
+            }
+        }
+    }
+
+    /// Sets the visibility timeout of `messages` in batches of 10 per source queue, the
+    /// most SQS accepts per request. Returns the ids of the messages whose visibility could not be
+    /// changed.
+    #[tracing::instrument(skip(self, messages))]
+    pub async fn change_visibility_batch(
+        &self,
+        messages: &[SqsMessage],
+        visibility_timeout: i64,
+    ) -> Vec<String> {
+        let mut failed = vec![];
+
+        let mut messages = messages.to_vec();
+        self.group_by_queue(&mut messages);
+
+        for (range, queue_url) in self.queue_batches(&messages) {
+            let chunk = &messages[range];
+            let msg_ids: Vec<String> = chunk
+                .iter()
+                .map(|msg| msg.message_id.clone().unwrap())
+                .collect();
+
+            let entries: Vec<_> = chunk
+                .iter()
+                .map(|msg| ChangeMessageVisibilityBatchRequestEntry {
+                    id: msg.message_id.clone().unwrap(),
+                    receipt_handle: msg.receipt_handle.clone().expect("Message missing receipt"),
+                    visibility_timeout: Some(visibility_timeout),
+                })
+                .collect();
+
+            let result = retry(&self.delete_retry, || async {
+                let _sqs_permit = self.sqs_permit().await;
+                let cmvb = self.sqs_client
+                    .change_message_visibility_batch(ChangeMessageVisibilityBatchRequest {
+                        entries: entries.clone(),
+                        queue_url: queue_url.clone(),
+                    });
+
+                tokio::time::timeout(self.request_timeout, cmvb).await
+            }).await;
+
+            match result {
+                Ok(Ok(batch_result)) => {
+                    failed.extend(batch_result.failed.into_iter().map(|failure| failure.id));
+                }
+                Ok(Err(e)) => {
+                    warn!("Failed to change message visibility: {:?}", e);
+                    failed.extend(msg_ids);
+                }
+                Err(e) => {
+                    warn!("Failed to change message visibility, timed out: {:?}", e);
+                    failed.extend(msg_ids);
+                }
+            }
+        }
+
+        failed
+    }
+
+    pub async fn ack_all(&mut self, notify: Option<tokio::sync::oneshot::Sender<()>>) -> AckSummary {
+        self.flush_before(notify, None).await
+    }
+
+    /// Flushes as `ack_all` does, treating `deadline` like the ack deadline if it
+    /// comes first. Every wait is bounded by the deadline rather than the flush as a
+    /// whole, so that running out of time never leaves the buffer half taken apart.
+    #[tracing::instrument(skip(self, notify), fields(flush_id))]
+    async fn flush_before(
+        &mut self,
+        notify: Option<tokio::sync::oneshot::Sender<()>>,
+        deadline: Option<Instant>,
+    ) -> AckSummary {
+        self.flush_count += 1;
+        let flush_id = self.flush_count;
+        tracing::Span::current().record("flush_id", &flush_id);
+        // Prefixes every log line of this flush, for when tracing isn't in use
+        let flush_tag = format!("[flush {}] ", flush_id);
+        self.flush_id = Some(flush_id);
+        self.idle_since = Instant::now();
+        debug!("{}Flushing completed events", flush_tag);
+
+        // Held until the flush completes
+        let flush_semaphore = self.flush_semaphore.clone();
+        let _flush_permit = match &flush_semaphore {
+            Some(flush_semaphore) => {
+                if flush_semaphore.available_permits() == 0 {
+                    debug!("{}Waiting for a concurrent flush to finish", flush_tag);
+                }
+                match before_deadline(deadline, flush_semaphore.acquire()).await {
+                    Some(flush_permit) => Some(flush_permit),
+                    None => {
+                        warn!("{}Deadline passed while waiting to flush", flush_tag);
+                        self.flush_id = None;
+                        for notify in notify.into_iter().chain(self.pending_flush.take().into_iter().flatten()) {
+                            let _ = notify.send(());
+                        }
+                        return AckSummary {
+                            timed_out: Some(AckPhase::Emit),
+                            ..AckSummary::default()
+                        };
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(clear_buffer) = self.dry_run {
+            self.dry_run_flush(&flush_tag, clear_buffer).await;
+            self.flush_id = None;
+            for notify in notify.into_iter().chain(self.pending_flush.take().into_iter().flatten()) {
+                let _ = notify.send(());
+            }
+            return AckSummary::default();
+        }
+
+        let started = Instant::now();
+        let deadline = match (deadline, self.ack_deadline) {
+            (Some(deadline), Some(ack_deadline)) => Some(deadline.min(started + ack_deadline)),
+            (deadline, ack_deadline) => deadline.or_else(|| ack_deadline.map(|ack_deadline| started + ack_deadline)),
+        };
+        // Compaction and retention reorder the buffer, and partials emitted by this
+        // flush can no longer be replaced
+        self.buffered_partials.clear();
+        let held_back = self.hold_back_newer(&flush_tag);
+        let mut summary = AckSummary::default();
+        self.emitted_bytes = 0;
+        // Events retained for the next flush aren't counted again
+        self.buffered_bytes = 0;
+
+        // Indexes into completed_events of events rejected downstream. They, and the
+        // messages they came from, stay buffered for the next flush.
+        let mut rejected_events = HashSet::new();
+
+        // The messages behind events merged by the compactor
+        let mut compacted_sources = vec![];
+
+        // Streamed events have already been emitted. Empty batches are skipped unless
+        // configured to emit them as heartbeats, and dedup-only handlers emit nothing.
+        if self.event_emitter.is_some()
+            && self.streaming.is_none()
+            && (self.emit_empty || !self.completed_events.is_empty())
+        {
+            compacted_sources = self.compact_buffer();
+            let meta = self.buffered_meta();
+            // Taken so that groups can borrow events while emitting, restored below
+            let events = std::mem::replace(&mut self.completed_events, Vec::new());
+
+            for (group, indexes) in self.flush_groups(&events) {
+                // Groups not emitted before the deadline are retained, as if rejected
+                if summary.timed_out.is_some() {
+                    rejected_events.extend(indexes);
+                    continue;
+                }
+
+                // A single group is emitted straight from the buffer, without cloning
+                let owned_events: Vec<CE>;
+                let owned_meta: Vec<EventMeta>;
+                let (group_events, group_meta) = if indexes.len() == events.len() {
+                    (&events[..], &meta[..])
+                } else {
+                    owned_events = indexes.iter().map(|index| events[*index].clone()).collect();
+                    owned_meta = indexes.iter().map(|index| meta[*index].clone()).collect();
+                    (&owned_events[..], &owned_meta[..])
+                };
+
+                // A panicking serializer or emitter must not take the buffer with it,
+                // so the group is retained as if rejected
+                let emit = self.serialize_and_emit(group_events, group_meta, group, &flush_tag);
+                let emitted = before_deadline(deadline, AssertUnwindSafe(emit).catch_unwind()).await;
+                match emitted {
+                    Some(Ok(rejected)) => {
+                        rejected_events.extend(rejected.iter().map(|index| indexes[*index]))
+                    }
+                    Some(Err(panic)) => {
+                        error!(
+                            "{}Retaining {} events, serializing or emitting them panicked: {}",
+                            flush_tag,
+                            indexes.len(),
+                            panic_reason(&*panic)
+                        );
+                        rejected_events.extend(indexes);
+                    }
+                    None => {
+                        warn!("{}Ack deadline passed while emitting", flush_tag);
+                        summary.timed_out = Some(AckPhase::Emit);
+                        rejected_events.extend(indexes);
+                    }
+                }
+            }
+
+            self.completed_events = events;
+            summary.emitted_events = self.completed_events.len() - rejected_events.len();
+            self.stats.add_events_emitted(summary.emitted_events as u64);
+        }
+
+        if let (Some(side_emitter), None) = (&mut self.side_emitter, summary.timed_out) {
+            let side_outputs = std::mem::replace(&mut self.side_outputs, Vec::new());
+            let side_output_count = side_outputs.len();
+            if side_output_count > 0 {
+                debug!("{}Emitting {} side outputs", flush_tag, side_output_count);
+                match before_deadline(deadline, side_emitter.emit_event(side_outputs)).await {
+                    Some(Ok(_)) => (),
+                    Some(Err(e)) => warn!(
+                        "{}Failed to emit {} side outputs: {}",
+                        flush_tag, side_output_count, e
+                    ),
+                    None => {
+                        warn!("{}Ack deadline passed while emitting side outputs", flush_tag);
+                        summary.timed_out = Some(AckPhase::Emit);
+                    }
+                }
+            }
+        }
+
+        let mut retained_message_ids: HashSet<String> = rejected_events
+            .iter()
+            .filter_map(|index| self.completed_event_sources[*index].clone())
+            .collect();
+        // A compacted event can't be traced back to the messages it was merged from, so
+        // if any is rejected every message behind the batch is kept
+        if !rejected_events.is_empty() {
+            retained_message_ids.extend(compacted_sources);
+        }
+        let (mut retained_messages, to_delete): (Vec<_>, Vec<_>) = self
+            .completed_messages
+            .drain(..)
+            .partition(|msg| match &msg.message_id {
+                Some(message_id) => retained_message_ids.contains(message_id),
+                None => false,
+            });
+        self.completed_messages = to_delete;
+
+        if self.dedup.skip_cache_on_emit_success
+            && self.event_emitter.is_some()
+            && rejected_events.is_empty()
+            && summary.timed_out.is_none()
+        {
+            debug!(
+                "{}Emitted every event, skipping caching {} identities",
+                flush_tag,
+                self.identities.len()
+            );
+            self.identities.clear();
+        }
+
+        // Identities of events rejected downstream are only cached once the events are
+        // emitted, so that a redelivered message isn't dropped as a duplicate meanwhile
+        let rejected_identities: HashSet<&Vec<u8>> = rejected_events
+            .iter()
+            .flat_map(|index| &self.completed_event_identities[*index])
+            .collect();
+        let buffered = std::mem::replace(&mut self.identities, Vec::new());
+        let (unemitted, identities): (Vec<_>, Vec<_>) = buffered
+            .into_iter()
+            .partition(|identity| rejected_identities.contains(identity));
+
+        // Identities not stored before the deadline, stored next flush instead
+        let mut uncached = vec![];
+        for identity in identities {
+            if summary.timed_out.is_some() {
+                uncached.push(identity);
+                continue;
+            }
+
+            let stored = before_deadline(
+                deadline,
+                retry(&self.cache_retry, || {
+                    let mut cache = self.cache.clone();
+                    let identity = identity.clone();
+                    async move { cache.store(identity).await }
+                }),
+            )
+            .await;
+
+            let stored = match stored {
+                Some(stored) => stored,
+                None => {
+                    warn!("{}Ack deadline passed while caching", flush_tag);
+                    summary.timed_out = Some(AckPhase::Cache);
+                    uncached.push(identity);
+                    continue;
+                }
+            };
+
+            match stored {
+                Ok(_) => {
+                    if self.recently_cached.len() == RECENTLY_CACHED_CAPACITY {
+                        self.recently_cached.pop_front();
+                    }
+                    self.recently_cached.push_back(identity);
+                }
+                Err(e) => {
+                    warn!("{}Failed to cache with: {:?}", flush_tag, e);
+                    summary.failed_cache_identities.push(identity);
+                }
+            }
+        }
+
+        // Identities to store again next flush, whose messages are kept until then
+        let mut retry_identities = vec![];
+        if self.dedup.cache_failure_policy == CacheFailurePolicy::RetainMessages
+            && !summary.failed_cache_identities.is_empty()
+        {
+            retry_identities = summary.failed_cache_identities.clone();
+            let unrecorded: HashSet<String> = retry_identities
+                .iter()
+                .filter_map(|identity| self.identity_sources.get(identity))
+                .flatten()
+                .cloned()
+                .collect();
+            let (held, to_delete): (Vec<_>, Vec<_>) = self
+                .completed_messages
+                .drain(..)
+                .partition(|msg| match &msg.message_id {
+                    Some(message_id) => unrecorded.contains(message_id),
+                    None => false,
+                });
+            warn!(
+                "{}Retaining {} messages until their identities are cached",
+                flush_tag,
+                held.len()
+            );
+            self.completed_messages = to_delete;
+            retained_messages.extend(held);
+            self.identities.extend(retry_identities.iter().cloned());
+        }
+        self.identities.extend(uncached.iter().cloned());
+        retry_identities.extend(uncached);
+        self.identities.extend(unemitted.iter().cloned());
+        retry_identities.extend(unemitted);
+
+        self.defer_deletes(&flush_tag);
+
+        let mut index = 0;
+        self.completed_events.retain(|_| {
+            index += 1;
+            rejected_events.contains(&(index - 1))
+        });
+        let mut index = 0;
+        self.completed_event_sources.retain(|_| {
+            index += 1;
+            rejected_events.contains(&(index - 1))
+        });
+        let mut index = 0;
+        self.completed_event_identities.retain(|_| {
+            index += 1;
+            rejected_events.contains(&(index - 1))
+        });
+        self.events_without_messages = self
+            .completed_event_sources
+            .iter()
+            .filter(|source| source.is_none())
+            .count();
+
+        let attempted = self.track_undeleted();
+        self.delete_completed(&flush_tag, deadline, &mut summary).await;
+        self.untrack_deleted(attempted);
+        self.completed_messages.extend(retained_messages);
+        self.strict_ordering.set_retained(
+            self.completed_events.len(),
+            self.completed_messages.len(),
+            self.identities.len(),
+        );
+        if let Some(held_back) = held_back {
+            self.completed_events.extend(held_back.events);
+            self.completed_event_sources.extend(held_back.event_sources);
+            self.completed_event_identities.extend(held_back.event_identities);
+            self.completed_messages.extend(held_back.messages);
+            retry_identities.extend(held_back.identities.iter().cloned());
+            self.identities.extend(held_back.identities);
+            self.events_without_messages = self
+                .completed_event_sources
+                .iter()
+                .filter(|source| source.is_none())
+                .count();
+        }
+        self.prune_message_queues();
+        self.rewrite_wal();
+        self.identity_sources
+            .retain(|identity, _| retry_identities.contains(identity));
+        self.prune_source_messages();
+        self.stats.record_flush();
+        if let Some(metrics) = &self.metrics {
+            metrics.record_flush(started.elapsed(), summary.emitted_events);
+            if !summary.failed_messages.is_empty() {
+                metrics.record_delete_failures(summary.failed_messages.len());
+            }
+        }
+        if let Some(stats_emitter) = &mut self.stats_emitter {
+            let flush_stats = FlushStats {
+                flush_id,
+                emitted_events: summary.emitted_events,
+                emitted_bytes: self.emitted_bytes,
+                deleted_messages: summary.deleted_messages,
+                latency: started.elapsed(),
+                rejected_events: rejected_events.len(),
+                failed_deletes: summary.failed_messages.len(),
+                failed_cache_identities: summary.failed_cache_identities.len(),
+            };
+            match before_deadline(deadline, stats_emitter.emit_event(vec![flush_stats.to_json()])).await {
+                Some(Ok(_)) => (),
+                Some(Err(e)) => warn!("{}Failed to emit flush stats: {}", flush_tag, e),
+                None => warn!("{}Ack deadline passed while emitting flush stats", flush_tag),
+            }
+        }
+        self.report_proc_errors(true);
+
+        self.flush_id = None;
+
+        if let Some(notify) = notify {
+            let _ = notify.send(());
+        }
+        // Any flush absorbs a pending debounced one
+        for notify in self.pending_flush.take().into_iter().flatten() {
+            let _ = notify.send(());
+        }
+
+        summary
     }
-}
 
-#[async_trait]
-impl<CE, ProcErr, SqsT> CompletionHandler for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
-where
-    CE: Send + Sync + Clone + 'static,
-    ProcErr: Debug + Send + Sync + 'static,
-    SqsT: Sqs + Clone + Send + Sync + 'static,
-{
-    type Message = SqsMessage;
-    type CompletedEvent = OutputEvent<CE, ProcErr>;
+    /// Deletes the messages in `completed_messages`, recording the outcome in
+    /// `summary`. Messages whose delete requests weren't sent, because a chunk failed
+    /// with `fail_fast_on_delete` set or `deadline` passed, are left in
+    /// `completed_messages`.
+    async fn delete_completed(&mut self, flush_tag: &str, deadline: Option<Instant>, summary: &mut AckSummary) {
+        let now = SystemTime::now();
+        for msg in &self.completed_messages {
+            if let Some(latency) = time_since_first_receive(msg, now) {
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_receive_latency(latency);
+                }
+                summary.max_time_since_first_receive =
+                    summary.max_time_since_first_receive.max(Some(latency));
+            }
+        }
+
+        let mut acks = vec![];
+        // Index into completed_messages of the first message that was not deleted
+        // because a chunk failed with fail_fast_on_delete set.
+        let mut retain_from = None;
+
+        let mut completed_messages = std::mem::replace(&mut self.completed_messages, Vec::new());
+        if let Some(delete_priority) = &self.delete_priority {
+            completed_messages.sort_by(|a, b| delete_priority(a, b));
+        }
+        // Stable, so the priority order holds within each queue
+        self.group_by_queue(&mut completed_messages);
+        self.completed_messages = completed_messages;
+
+        // Built up front so that chunks can be sent concurrently, and resent when throttled
+        let mut next_delete_client = self.next_delete_client;
+        let mut chunks = VecDeque::new();
+        for (range, queue_url) in self.queue_batches(&self.completed_messages) {
+            let client = next_delete_client % (self.delete_clients.len() + 1);
+            next_delete_client = next_delete_client.wrapping_add(1);
+
+            let chunk = &self.completed_messages[range.clone()];
+            let msg_ids: Vec<String> = chunk
+                .iter()
+                .map(|msg| msg.message_id.clone().unwrap())
+                .collect();
+
+            let entries: Vec<_> = chunk
+                .iter()
+                .map(|msg| {
+                    let receipt_handle = msg.receipt_handle.as_deref().expect("Message missing receipt");
+                    DeleteMessageBatchRequestEntry {
+                        id: msg.message_id.clone().unwrap(),
+                        receipt_handle: match &self.receipt_handle_transform {
+                            Some(receipt_handle_transform) => receipt_handle_transform(receipt_handle),
+                            None => receipt_handle.to_owned(),
+                        },
+                    }
+                })
+                .collect();
+
+            chunks.push_back(DeleteChunk {
+                start: range.start,
+                client,
+                queue_url,
+                msg_ids,
+                entries,
+                attempts: 0,
+            });
+        }
+        self.next_delete_client = next_delete_client;
+
+        let mut sent = vec![];
+        while !chunks.is_empty() && summary.timed_out.is_none() {
+            let delay = self.delete_throttle.delay();
+            if delay > Duration::from_secs(0) {
+                debug!("{}Delete requests are throttled, waiting {:?}", flush_tag, delay);
+                tokio::time::delay_for(delay).await;
+            }
+
+            // Failing fast relies on chunks being sent one at a time, in order
+            let concurrency = if self.fail_fast_on_delete {
+                1
+            } else {
+                self.delete_throttle.concurrency()
+            };
+            let round: Vec<DeleteChunk> = chunks.drain(..concurrency.min(chunks.len())).collect();
+            let results = match before_deadline(
+                deadline,
+                futures::future::join_all(round.iter().map(|chunk| self.send_delete(chunk))),
+            )
+            .await
+            {
+                Some(results) => results,
+                None => {
+                    // Whether the round's deletes went through is unknown, so its
+                    // messages are retained with the unsent ones
+                    warn!("{}Ack deadline passed while deleting", flush_tag);
+                    summary.timed_out = Some(AckPhase::Delete);
+                    for chunk in round.into_iter().rev() {
+                        chunks.push_front(chunk);
+                    }
+                    break;
+                }
+            };
+
+            let mut throttled = vec![];
+            let mut failed = false;
+            for (mut chunk, result) in round.into_iter().zip(results) {
+                match result {
+                    Ok(Err(e))
+                        if e.is_throttling()
+                            && chunk.attempts + 1 < self.delete_throttle.max_attempts() =>
+                    {
+                        chunk.attempts += 1;
+                        warn!(
+                            "{}Delete request throttled, attempt {}: {:?}",
+                            flush_tag, chunk.attempts, e
+                        );
+                        throttled.push(chunk);
+                    }
+                    result => {
+                        failed |= match &result {
+                            Ok(Ok(_)) => false,
+                            _ => true,
+                        };
+                        sent.push((chunk, result));
+                    }
+                }
+            }
+
+            if throttled.is_empty() {
+                self.delete_throttle.on_success();
+            } else {
+                self.delete_throttle.on_throttle();
+            }
+            // Resent ahead of the chunks not yet sent, in their original order
+            for chunk in throttled.into_iter().rev() {
+                chunks.push_front(chunk);
+            }
+            if failed && self.fail_fast_on_delete {
+                break;
+            }
+        }
+
+        // Messages of requests that were never sent, or abandoned at the deadline
+        let unsent_ids: HashSet<String> = chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.msg_ids)
+            .collect();
+
+        sent.sort_by_key(|(chunk, _)| chunk.start);
+        for (chunk, result) in sent {
+            let DeleteChunk {
+                start: chunk_start,
+                msg_ids,
+                ..
+            } = chunk;
+            match result {
+                Ok(Err(e)) if self.fail_fast_on_delete => {
+                    self.stats.add_delete_failures(msg_ids.len() as u64);
+                    summary.failed_messages.extend(msg_ids);
+                    warn!(
+                        "{}Failed to delete messages, retaining the rest of the batch: {:?}",
+                        flush_tag, e
+                    );
+                    retain_from = Some(chunk_start);
+                    break;
+                }
+                Ok(dmb) => acks.push((dmb, msg_ids)),
+                Err(e) if self.fail_fast_on_delete => {
+                    self.stats.add_delete_failures(msg_ids.len() as u64);
+                    summary.failed_messages.extend(msg_ids);
+                    warn!(
+                        "{}Failed to delete messages, retaining the rest of the batch: {:?}",
+                        flush_tag, e
+                    );
+                    retain_from = Some(chunk_start);
+                    break;
+                }
+                Err(e) => {
+                    self.stats.add_delete_failures(msg_ids.len() as u64);
+                    summary.failed_messages.extend(msg_ids);
+                    warn!("{}Failed to delete message, timed out: {:?}", flush_tag, e)
+                }
+            };
+        }
+
+        debug!("{}Acking all messages", flush_tag);
+
+        let mut deleted_ids = vec![];
+        for (result, msg_ids) in acks {
+            match result {
+                Ok(batch_result) => {
+                    self.stats
+                        .add_messages_deleted(batch_result.successful.len() as u64);
+                    self.stats
+                        .add_delete_failures(batch_result.failed.len() as u64);
+                    summary.deleted_messages += batch_result.successful.len();
+                    for success in batch_result.successful {
+                        deleted_ids.push(success.id.clone());
+                        (self.on_ack)(self.self_actor.clone().unwrap(), Ok(success.id))
+                    }
+
+                    for failure in batch_result.failed {
+                        summary.failed_messages.push(failure.id.clone());
+                        (self.on_ack)(self.self_actor.clone().unwrap(), Err(failure.id))
+                    }
+                }
+                Err(e) => {
+                    self.stats.add_delete_failures(msg_ids.len() as u64);
+                    for msg_id in msg_ids {
+                        summary.failed_messages.push(msg_id.clone());
+                        (self.on_ack)(self.self_actor.clone().unwrap(), Err(msg_id))
+                    }
+                    warn!("{}Failed to acknowledge event: {:?}", flush_tag, e);
+                }
+            }
+        }
+        debug!("{}Acked", flush_tag);
+
+        if let Some(audit_sink) = &self.audit_sink {
+            let timestamp = chrono::Utc::now();
+            let recorded = before_deadline(deadline, async {
+                for message_id in &deleted_ids {
+                    audit_sink
+                        .record(message_id, AckOutcome::Deleted, timestamp)
+                        .await;
+                }
+                for message_id in &summary.failed_messages {
+                    audit_sink
+                        .record(message_id, AckOutcome::DeleteFailed, timestamp)
+                        .await;
+                }
+            })
+            .await;
+            if recorded.is_none() {
+                warn!("{}Ack deadline passed while recording to the audit sink", flush_tag);
+            }
+        }
+
+        if let DeleteFailurePolicy::ResetVisibility(visibility_timeout) = self.delete_failure_policy {
+            // Messages retained by fail_fast_on_delete are retried next flush instead
+            let attempted = &self.completed_messages[..retain_from.unwrap_or(self.completed_messages.len())];
+            let failed_ids: HashSet<&String> = summary.failed_messages.iter().collect();
+            let failed: Vec<SqsMessage> = attempted
+                .iter()
+                .filter(|msg| {
+                    msg.message_id
+                        .as_ref()
+                        .map_or(false, |message_id| failed_ids.contains(message_id))
+                })
+                .cloned()
+                .collect();
+
+            if !failed.is_empty() {
+                let not_reset = before_deadline(
+                    deadline,
+                    self.change_visibility_batch(&failed, visibility_timeout.as_secs() as i64),
+                )
+                .await;
+                match not_reset {
+                    Some(not_reset) if !not_reset.is_empty() => warn!(
+                        "{}Failed to reset visibility of {} messages",
+                        flush_tag,
+                        not_reset.len()
+                    ),
+                    Some(_) => (),
+                    None => warn!("{}Ack deadline passed while resetting visibility", flush_tag),
+                }
+            }
+        }
+
+        match retain_from {
+            Some(retain_from) => {
+                self.completed_messages.drain(..retain_from);
+            }
+            None => self.completed_messages.retain(|msg| match &msg.message_id {
+                Some(message_id) => unsent_ids.contains(message_id),
+                None => false,
+            }),
+        }
+    }
+
+    /// Sets aside what strict ordering holds back this flush, logging how much.
+    fn hold_back_newer(&mut self, flush_tag: &str) -> Option<HeldBack<CE>> {
+        let held_back = self.strict_ordering.hold_back(
+            &mut self.completed_events,
+            &mut self.completed_event_sources,
+            &mut self.completed_event_identities,
+            &mut self.completed_messages,
+            &mut self.identities,
+        )?;
+        debug!(
+            "{}Holding back {} newer events and {} messages until {} retained events and {} messages are flushed",
+            flush_tag,
+            held_back.events.len(),
+            held_back.messages.len(),
+            self.completed_events.len(),
+            self.completed_messages.len(),
+        );
+        Some(held_back)
+    }
+
+    /// Serializes the buffer as a flush would and logs what the flush would emit and
+    /// delete, without emitting, caching or deleting anything.
+    async fn dry_run_flush(&mut self, flush_tag: &str, clear_buffer: bool) {
+        let meta = self.buffered_meta();
+        let events = std::mem::replace(&mut self.completed_events, Vec::new());
+
+        for (group, indexes) in self.flush_groups(&events) {
+            let group_events: Vec<CE> = indexes.iter().map(|index| events[*index].clone()).collect();
+            let group_meta: Vec<EventMeta> = indexes.iter().map(|index| meta[*index].clone()).collect();
+            // Caught so that the buffer is restored below
+            let serialize = self.serialize_group(&group_events, &group_meta, &group);
+            let serialized_event = AssertUnwindSafe(serialize).catch_unwind().await;
+            match serialized_event {
+                Ok(Ok(serialized_event)) => info!(
+                    "{}Dry run, would emit {} events as: [{}]",
+                    flush_tag,
+                    group_events.len(),
+                    self.describe_payloads(&serialized_event)
+                ),
+                Ok(Err(e)) => warn!(
+                    "{}Dry run, {} events failed to serialize: {:?}",
+                    flush_tag,
+                    group_events.len(),
+                    e
+                ),
+                Err(panic) => error!(
+                    "{}Dry run, serializing {} events panicked: {}",
+                    flush_tag,
+                    group_events.len(),
+                    panic_reason(&*panic)
+                ),
+            }
+        }
+        self.completed_events = events;
+
+        let message_ids: Vec<&str> = self
+            .completed_messages
+            .iter()
+            .filter_map(|msg| msg.message_id.as_deref())
+            .collect();
+        info!(
+            "{}Dry run, would emit {} side outputs, cache {} identities and delete {} messages: {:?}",
+            flush_tag,
+            self.side_outputs.len(),
+            self.identities.len(),
+            message_ids.len(),
+            message_ids,
+        );
+
+        if clear_buffer {
+            self.completed_events.clear();
+            self.completed_event_sources.clear();
+            self.completed_event_identities.clear();
+            self.completed_messages.clear();
+            self.identities.clear();
+            self.identity_sources.clear();
+            self.source_messages.clear();
+            self.buffered_partials.clear();
+            self.message_queues.clear();
+            self.events_without_messages = 0;
+            self.buffered_bytes = 0;
+            self.side_outputs.clear();
+            self.rewrite_wal();
+        }
+    }
+
+    /// Moves messages that haven't yet waited out the delete grace period from the
+    /// delete pass into the delayed-delete queue, and schedules their deletion.
+    fn defer_deletes(&mut self, flush_tag: &str) {
+        let delete_grace_period = match self.delete_grace_period {
+            Some(delete_grace_period) => delete_grace_period,
+            None => return,
+        };
+
+        let graced = &mut self.graced;
+        let (due, deferred): (Vec<_>, Vec<_>) = self
+            .completed_messages
+            .drain(..)
+            .partition(|msg| match &msg.message_id {
+                Some(message_id) => graced.remove(message_id),
+                None => false,
+            });
+        self.completed_messages = due;
+
+        if deferred.is_empty() {
+            return;
+        }
 
-    async fn mark_complete(&self, msg: Self::Message, completed_event: Self::CompletedEvent) {
-        SqsCompletionHandlerActor::mark_complete(self, msg, completed_event).await
+        debug!(
+            "{}Deferring deletion of {} messages by {:?}",
+            flush_tag,
+            deferred.len(),
+            delete_grace_period
+        );
+        let delete_at = Instant::now() + delete_grace_period;
+        self.pending_deletes
+            .extend(deferred.into_iter().map(|msg| (delete_at, msg)));
+        self.publish_pending_deletes();
+
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(delete_grace_period).await;
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::delete_due {}).await {
+                warn!("Failed to trigger deferred deletes: {}", e);
+            }
+        });
+    }
+
+    /// The number of flushes with emitted messages not yet deleted, whether awaiting
+    /// their delete grace period, being deleted or retained after their deletes
+    /// failed or timed out. Each flush defers its messages to the same instant.
+    fn pending_delete_batches(&self) -> usize {
+        let deferred = self
+            .pending_deletes
+            .iter()
+            .map(|(delete_at, _)| *delete_at)
+            .collect::<HashSet<Instant>>()
+            .len();
+        deferred + self.undeleted_batches.len()
+    }
+
+    /// Counts the messages about to be deleted as a batch awaiting deletion, besides
+    /// those an earlier flush failed to delete, which are still counted by its batch.
+    /// Returns the ids of every message about to be deleted, see `untrack_deleted`.
+    fn track_undeleted(&mut self) -> HashSet<String> {
+        let attempted: HashSet<String> = self
+            .completed_messages
+            .iter()
+            .filter_map(|msg| msg.message_id.clone())
+            .collect();
+        let tracked: HashSet<&String> = self.undeleted_batches.iter().flatten().collect();
+        let batch: HashSet<String> = attempted
+            .iter()
+            .filter(|message_id| !tracked.contains(message_id))
+            .cloned()
+            .collect();
+        if !batch.is_empty() {
+            self.undeleted_batches.push(batch);
+            self.publish_pending_deletes();
+        }
+        attempted
+    }
+
+    /// Stops counting the `attempted` messages that the delete pass didn't leave in
+    /// `completed_messages`, as they were either deleted or given up on.
+    fn untrack_deleted(&mut self, attempted: HashSet<String>) {
+        let retained: HashSet<&String> = self
+            .completed_messages
+            .iter()
+            .filter_map(|msg| msg.message_id.as_ref())
+            .collect();
+        for batch in &mut self.undeleted_batches {
+            batch.retain(|message_id| {
+                !attempted.contains(message_id) || retained.contains(message_id)
+            });
+        }
+        self.undeleted_batches.retain(|batch| !batch.is_empty());
+        self.publish_pending_deletes();
+    }
+
+    fn publish_pending_deletes(&self) {
+        let _ = self.pending_deletes_watch.0.broadcast(self.pending_delete_batches());
+    }
+
+    /// Schedules the next idle check for when the idle interval will have passed since
+    /// the last flush or heartbeat.
+    fn schedule_idle_check(&self) {
+        let idle_interval = match &self.idle_heartbeat {
+            Some((idle_interval, _)) => *idle_interval,
+            None => return,
+        };
+        let delay = idle_interval
+            .checked_sub(self.idle_since.elapsed())
+            .unwrap_or_default();
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(delay).await;
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::check_idle {}).await {
+                debug!("Failed to check for idleness: {}", e);
+            }
+        });
+    }
+
+    /// Emits a heartbeat if no flush has started within the idle interval.
+    async fn check_idle(&mut self) {
+        let (idle_interval, heartbeat_fn) = match &self.idle_heartbeat {
+            Some((idle_interval, heartbeat_fn)) => (*idle_interval, heartbeat_fn),
+            None => return,
+        };
+
+        if self.idle_since.elapsed() >= idle_interval {
+            let heartbeat = heartbeat_fn();
+            let metadata = self.emit_metadata(&[], false);
+            if self.dry_run.is_some() {
+                info!("Dry run, would emit a heartbeat");
+            } else if let Some(event_emitter) = &mut self.event_emitter {
+                debug!("Idle for {:?}, emitting a heartbeat", idle_interval);
+                if let Err(e) = event_emitter
+                    .emit_event_with_receipt(vec![heartbeat], metadata)
+                    .await
+                {
+                    warn!("Failed to emit heartbeat: {:?}", e);
+                }
+            }
+            self.idle_since = Instant::now();
+        }
+
+        self.schedule_idle_check();
+    }
+
+    /// Deletes the messages whose delete grace period has elapsed, without flushing
+    /// anything else.
+    pub async fn delete_due(&mut self) {
+        let now = Instant::now();
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .pending_deletes
+            .drain(..)
+            .partition(|(delete_at, _)| *delete_at <= now);
+        self.pending_deletes = pending;
+        self.publish_pending_deletes();
+
+        if due.is_empty() {
+            return;
+        }
+
+        let due: Vec<SqsMessage> = due.into_iter().map(|(_, msg)| msg).collect();
+        for msg in &due {
+            if let Some(message_id) = &msg.message_id {
+                self.graced.insert(message_id.clone());
+            }
+        }
+
+        // Only the due messages are deleted, the buffer is left for the completion
+        // policy to flush
+        let buffered = std::mem::replace(&mut self.completed_messages, due);
+        let flush_tag = "[deferred delete] ";
+        debug!("{}Deleting {} messages", flush_tag, self.completed_messages.len());
+        let deadline = self.ack_deadline.map(|ack_deadline| Instant::now() + ack_deadline);
+        let mut summary = AckSummary::default();
+        let attempted = self.track_undeleted();
+        self.delete_completed(flush_tag, deadline, &mut summary).await;
+        self.untrack_deleted(attempted);
+        if let (Some(metrics), false) = (&self.metrics, summary.failed_messages.is_empty()) {
+            metrics.record_delete_failures(summary.failed_messages.len());
+        }
+
+        // Those left unsent are deleted by the next flush, without being deferred again
+        let unsent = std::mem::replace(&mut self.completed_messages, buffered);
+        self.completed_messages.extend(unsent);
+    }
+
+    /// Cancels the deferred deletion of a message, eg: because its event was rejected
+    /// downstream within the grace period. The message will be redelivered once its
+    /// visibility timeout expires. Returns false if no deletion was pending.
+    pub fn cancel_delete(&mut self, message_id: &str) -> bool {
+        let pending = self.pending_deletes.len();
+        self.pending_deletes
+            .retain(|(_, msg)| msg.message_id.as_deref() != Some(message_id));
+
+        if self.pending_deletes.len() == pending {
+            return false;
+        }
+        self.publish_pending_deletes();
+        info!("Cancelled deletion of message {}", message_id);
+        (self.on_ack)(self.self_actor.clone().unwrap(), Err(message_id.to_owned()));
+        true
+    }
+
+    /// Splits `events` into groups sharing a partition key, shard and serializer, as
+    /// indexes into `events`. Without a `partition_key_fn`, shards or
+    /// `serializer_selector` the whole batch is a single, unkeyed group for the
+    /// primary serializer.
+    fn flush_groups(&self, events: &[CE]) -> Vec<(FlushGroup, Vec<usize>)> {
+        if events.is_empty()
+            || (self.partition_key_fn.is_none()
+                && self.shards.is_none()
+                && self.serializer_selector.is_none())
+        {
+            return vec![(FlushGroup::default(), (0..events.len()).collect())];
+        }
+
+        let mut groups: Vec<(FlushGroup, Vec<usize>)> = vec![];
+        let mut group_indexes: HashMap<FlushGroup, usize> = HashMap::new();
+        for (index, event) in events.iter().enumerate() {
+            let group = FlushGroup {
+                partition_key: self.partition_key_fn.as_ref().map(|f| f(event)),
+                shard: self.shards.as_ref().map(|(shard_count, shard_key_fn)| {
+                    (shard_key_fn(event) % *shard_count as u64) as u32
+                }),
+                serializer_id: self.selected_serializer(event),
+            };
+            match group_indexes.get(&group) {
+                Some(group_index) => groups[*group_index].1.push(index),
+                None => {
+                    group_indexes.insert(group.clone(), groups.len());
+                    groups.push((group, vec![index]));
+                }
+            }
+        }
+        groups
+    }
+
+    /// The registered serializer `serializer_selector` picks for `event`, None for
+    /// the primary serializer.
+    fn selected_serializer(&self, event: &CE) -> Option<SerializerId> {
+        let serializer_id = (self.serializer_selector.as_ref()?)(event);
+        if self.serializers.contains_key(&serializer_id) {
+            Some(serializer_id)
+        } else {
+            warn!(
+                "No serializer registered as {}, using the primary serializer",
+                serializer_id
+            );
+            None
+        }
+    }
+
+    /// Serializes and emits one group of a flush, returning the indexes into `events`
+    /// of events rejected downstream.
+    async fn serialize_and_emit(
+        &mut self,
+        events: &[CE],
+        meta: &[EventMeta],
+        group: FlushGroup,
+        flush_tag: &str,
+    ) -> Vec<usize> {
+        let serialized_event = self.serialize_group(events, meta, &group).await;
+        let reason = match &serialized_event {
+            Err(e) if self.isolate_serialization_failures => Some(format!("Failed to serialize: {:?}", e)),
+            _ => None,
+        };
+        let reason = match reason {
+            Some(reason) => reason,
+            None => return self.emit_serialized(serialized_event, events, meta, group, flush_tag).await,
+        };
+
+        let failing = self.failing_events(events, meta, &group);
+        if failing.is_empty() {
+            // Only the batch as a whole fails, eg: it is too large
+            return self.emit_serialized(serialized_event, events, meta, group, flush_tag).await;
+        }
+
+        warn!(
+            "{}Dead-lettering {} of {} events that failed to serialize",
+            flush_tag,
+            failing.len(),
+            events.len(),
+        );
+        for index in &failing {
+            match &self.dead_letter {
+                Some(dead_letter) => dead_letter(DeadLetter::new(
+                    events[*index].clone(),
+                    meta[*index].source_message_id.clone(),
+                    reason.clone(),
+                )),
+                None => warn!("No dead-letter sink configured, dropping unserializable event"),
+            }
+        }
+
+        let remaining: Vec<usize> = (0..events.len())
+            .filter(|index| !failing.contains(index))
+            .collect();
+        if remaining.is_empty() {
+            return vec![];
+        }
+        let remaining_events: Vec<CE> = remaining.iter().map(|index| events[*index].clone()).collect();
+        let remaining_meta: Vec<EventMeta> = remaining.iter().map(|index| meta[*index].clone()).collect();
+
+        let serialized_event = self
+            .serialize_group(&remaining_events, &remaining_meta, &group)
+            .await;
+        self.emit_serialized(serialized_event, &remaining_events, &remaining_meta, group, flush_tag)
+            .await
+            .into_iter()
+            .map(|index| remaining[index])
+            .collect()
+    }
+
+    /// Serializes `events` with the group's serializer.
+    async fn serialize_group(
+        &mut self,
+        events: &[CE],
+        meta: &[EventMeta],
+        group: &FlushGroup,
+    ) -> Result<Vec<Payload>, CPE> {
+        let selected = group
+            .serializer_id
+            .as_ref()
+            .and_then(|serializer_id| self.serializers.get_mut(serializer_id));
+        match selected {
+            Some(serializer) => serializer.serialize_completed_events_with_meta(events, meta),
+            None => match self.serialize_parallel(events).await {
+                Some(serialized_event) => serialized_event,
+                None => {
+                    let mut serializer = exclusive(&self.completion_serializer);
+                    serializer.serialize_completed_events_with_meta(events, meta)
+                }
+            },
+        }
+    }
+
+    /// The indexes of the events in a batch that failed to serialize which fail on
+    /// their own, found by bisecting the batch with the group's serializer.
+    fn failing_events(&mut self, events: &[CE], meta: &[EventMeta], group: &FlushGroup) -> Vec<usize> {
+        let selected = group
+            .serializer_id
+            .as_ref()
+            .and_then(|serializer_id| self.serializers.get_mut(serializer_id));
+        match selected {
+            Some(serializer) => bisect_failures(serializer.as_mut(), events, meta),
+            None => bisect_failures(&mut *exclusive(&self.completion_serializer), events, meta),
+        }
+    }
+
+    /// Emits a serialized group, falling back to the fallback serializer if
+    /// serialization failed. Returns the indexes into `events` of events rejected
+    /// downstream, or of every event if the group could not be serialized at all.
+    async fn emit_serialized(
+        &mut self,
+        serialized_event: Result<Vec<Payload>, CPE>,
+        events: &[CE],
+        meta: &[EventMeta],
+        group: FlushGroup,
+        flush_tag: &str,
+    ) -> Vec<usize> {
+        let serialized_event = match (serialized_event, &mut self.fallback_serializer) {
+            (Ok(serialized_event), _) => (serialized_event, false),
+            (Err(e), Some(fallback_serializer)) => {
+                warn!(
+                    "{}Serializing events failed, using fallback serializer: {:?}",
+                    flush_tag, e
+                );
+                match fallback_serializer.serialize_completed_events_with_meta(events, meta) {
+                    Ok(serialized_event) => (serialized_event, true),
+                    Err(fallback_e) => {
+                        error!(
+                            "{}Serializing {} events failed: {:?}, fallback failed: {:?}, retaining them",
+                            flush_tag,
+                            events.len(),
+                            e,
+                            fallback_e
+                        );
+                        return (0..events.len()).collect();
+                    }
+                }
+            }
+            (Err(e), None) => {
+                // Retained with their messages, which are not acked, as if rejected
+                error!(
+                    "{}Serializing {} events failed: {:?}, retaining them",
+                    flush_tag,
+                    events.len(),
+                    e
+                );
+                return (0..events.len()).collect();
+            }
+        };
+
+        let (serialized_event, degraded) = serialized_event;
+        let payload_count = serialized_event.len();
+        let mut metadata = self.emit_metadata(events, degraded);
+        metadata.partition_key = group.partition_key;
+        metadata.shard_id = group.shard;
+        let receipt = match self.emit(serialized_event, metadata).await {
+            Ok(receipt) => receipt,
+            Err(_) => {
+                // Nothing was emitted, so the whole group stays buffered
+                warn!("{}Retaining {} events that failed to emit", flush_tag, events.len());
+                return (0..events.len()).collect();
+            }
+        };
+
+        if receipt.rejected().is_empty() {
+            return vec![];
+        }
+
+        if payload_count == events.len() {
+            receipt.rejected().to_vec()
+        } else {
+            // Payloads don't map one to one onto events, so we can't tell which
+            // events were rejected
+            warn!(
+                "{}{} of {} payloads were rejected, retaining all {} events",
+                flush_tag,
+                receipt.rejected().len(),
+                payload_count,
+                events.len(),
+            );
+            (0..events.len()).collect()
+        }
+    }
+
+    /// Sends one chunk's `DeleteMessageBatch` request, retrying timeouts.
+    async fn send_delete(
+        &self,
+        chunk: &DeleteChunk,
+    ) -> color_eyre::Result<Result<DeleteMessageBatchResult, crate::error::Error>> {
+        let sqs_client = match chunk.client {
+            0 => &self.sqs_client,
+            i => &self.delete_clients[i - 1],
+        };
+
+        retry(&self.delete_retry, || async {
+            let _sqs_permit = self.sqs_permit().await;
+            let dmb = sqs_client.delete_message_batch(DeleteMessageBatchRequest {
+                entries: chunk.entries.clone(),
+                queue_url: chunk.queue_url.clone(),
+            });
+
+            tokio::time::timeout(self.request_timeout, dmb).await
+        })
+        .await
+    }
+
+    /// A permit from the shared `sqs_semaphore`, held for the duration of one request.
+    async fn sqs_permit(&self) -> Option<tokio::sync::SemaphorePermit<'_>> {
+        match &self.sqs_semaphore {
+            Some(sqs_semaphore) => Some(sqs_semaphore.acquire().await),
+            None => None,
+        }
     }
 
-    async fn ack_message(&self, msg: Self::Message) {
-        SqsCompletionHandlerActor::ack_message(self, msg).await
+    /// Handles an `ack_all` request, debouncing it if configured and holding it while
+    /// paused.
+    async fn request_flush(&mut self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
+        if self.paused {
+            debug!("Paused, holding flush request until resumed");
+            self.pending_flush.get_or_insert_with(Vec::new).extend(notify);
+            return;
+        }
+
+        let flush_debounce = match self.flush_debounce {
+            Some(flush_debounce)
+                if self.buffered_len() < self.completion_policy.max_messages() as usize =>
+            {
+                flush_debounce
+            }
+            _ => {
+                self.ack_all(notify).await;
+                return;
+            }
+        };
+
+        if let Some(pending_flush) = &mut self.pending_flush {
+            debug!("Absorbing flush request into pending flush");
+            pending_flush.extend(notify);
+            return;
+        }
+
+        self.pending_flush = Some(notify.into_iter().collect());
+        let self_actor = self.self_actor.clone().unwrap();
+        tokio::task::spawn(async move {
+            tokio::time::delay_for(flush_debounce).await;
+            if let Err(e) = self_actor.send(SqsCompletionHandlerMessage::flush_pending {}).await {
+                warn!("Failed to trigger debounced flush: {}", e);
+            }
+        });
     }
+}
 
-    async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
-        SqsCompletionHandlerActor::ack_all(self, notify).await
+impl<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr> Drop
+    for SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+where
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+    CPE: Debug + Send + Sync + 'static,
+    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+        + Send
+        + Sync
+        + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+    CE: Send + Sync + Clone + 'static,
+    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+    CacheT: Cache + Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(on_router_exit) = &self.on_router_exit {
+            if !self.completed_events.is_empty() || !self.completed_messages.is_empty() {
+                warn!(
+                    "SqsCompletionHandler exiting with {} events and {} messages buffered",
+                    self.completed_events.len(),
+                    self.completed_messages.len(),
+                );
+            }
+            on_router_exit(
+                std::mem::replace(&mut self.completed_events, Vec::new()),
+                std::mem::replace(&mut self.completed_messages, Vec::new()),
+            );
+        }
     }
 }
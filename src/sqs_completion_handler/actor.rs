@@ -0,0 +1,660 @@
+use std::fmt::Debug;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aktors::actor::Actor;
+use async_trait::async_trait;
+use futures::future::FutureExt;
+use log::*;
+use rusoto_sqs::Message as SqsMessage;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::watch;
+
+use crate::ack_summary::{AckSummary, ShutdownSummary};
+use crate::cache::Cache;
+use crate::completion_event_serializer::CompletionEventSerializer;
+use crate::completion_handler::CompletionHandler;
+use crate::completion_policy::CompletionPolicy;
+use crate::error::{ActorGone, HealthError, MailboxError};
+use crate::event_emitter::EventEmitter;
+use crate::event_handler::OutputEvent;
+use crate::handler_snapshot::HandlerSnapshot;
+use crate::handler_stats::HandlerStats;
+use crate::sqs_ops::SqsOps;
+
+use super::{panic_reason, OnAck, SqsCompletionHandler};
+
+#[allow(non_camel_case_types)]
+pub enum SqsCompletionHandlerMessage<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    mark_complete {
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    },
+    mark_complete_ack {
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+        respond: tokio::sync::oneshot::Sender<Option<AckSummary>>,
+    },
+    ack_message {
+        msg: SqsMessage,
+    },
+    mark_complete_from {
+        queue_url: String,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    },
+    ack_message_from {
+        queue_url: String,
+        msg: SqsMessage,
+    },
+    ack_all {
+        notify: Option<tokio::sync::oneshot::Sender<()>>,
+    },
+    is_duplicate {
+        identity: Vec<u8>,
+        respond: tokio::sync::oneshot::Sender<bool>,
+    },
+    pending_identities {
+        respond: tokio::sync::oneshot::Sender<Vec<Vec<u8>>>,
+    },
+    recently_cached {
+        respond: tokio::sync::oneshot::Sender<Vec<Vec<u8>>>,
+    },
+    snapshot {
+        respond: tokio::sync::oneshot::Sender<HandlerSnapshot<CE>>,
+    },
+    shutdown {
+        respond: tokio::sync::oneshot::Sender<ShutdownSummary>,
+    },
+    abandon_buffer {
+        reason: String,
+    },
+    flush_pending {},
+    pause {},
+    resume {},
+    begin_processing {
+        msg: SqsMessage,
+    },
+    check_in_flight {},
+    extend_visibility {},
+    check_idle {},
+    delete_due {},
+    cancel_delete {
+        message_id: String,
+    },
+    update_policy {
+        completion_policy: CompletionPolicy,
+    },
+    update_on_ack {
+        on_ack: OnAck<CE, ProcErr, SqsT>,
+    },
+    healthcheck {
+        respond: tokio::sync::oneshot::Sender<Result<(), HealthError>>,
+    },
+    _p {
+        _p: std::marker::PhantomData<(SqsT)>,
+    },
+}
+
+#[async_trait]
+impl<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+    Actor<SqsCompletionHandlerMessage<CE, ProcErr, SqsT>>
+    for SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+where
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+    CPE: Debug + Send + Sync + 'static,
+    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+        + Send
+        + Sync
+        + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+    CE: Send + Sync + Clone + 'static,
+    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+    CacheT: Cache + Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+{
+    #[tracing::instrument(skip(self, msg))]
+    async fn route_message(&mut self, msg: SqsCompletionHandlerMessage<CE, ProcErr, SqsT>) {
+        // The serializer, emitter and on_ack callback are user supplied. A panic in any
+        // of them would otherwise kill the router task and silently drop every message
+        // sent afterwards, so we log it and keep routing.
+        let routed = AssertUnwindSafe(async {
+            match msg {
+                SqsCompletionHandlerMessage::mark_complete { msg, completed } => {
+                    self.mark_complete(msg, completed).await;
+                }
+                SqsCompletionHandlerMessage::mark_complete_ack { msg, completed, respond } => {
+                    let _ = respond.send(self.mark_complete(msg, completed).await);
+                }
+                SqsCompletionHandlerMessage::ack_all { notify } => {
+                    self.request_flush(notify).await;
+                }
+                SqsCompletionHandlerMessage::update_policy { completion_policy } => {
+                    self.update_policy(completion_policy)
+                }
+                SqsCompletionHandlerMessage::update_on_ack { on_ack } => self.update_on_ack(on_ack),
+                SqsCompletionHandlerMessage::begin_processing { msg } => self.begin_processing(msg),
+                SqsCompletionHandlerMessage::check_in_flight {} => self.check_in_flight(),
+                SqsCompletionHandlerMessage::extend_visibility {} => self.extend_visibility().await,
+                SqsCompletionHandlerMessage::check_idle {} => self.check_idle().await,
+                SqsCompletionHandlerMessage::delete_due {} => self.delete_due().await,
+                SqsCompletionHandlerMessage::cancel_delete { message_id } => {
+                    self.cancel_delete(&message_id);
+                }
+                SqsCompletionHandlerMessage::flush_pending {} => {
+                    // A flush may already have happened since this was scheduled
+                    if self.pending_flush.is_some() && !self.paused {
+                        self.ack_all(None).await;
+                    }
+                }
+                SqsCompletionHandlerMessage::ack_message { msg } => self.ack_message(msg).await,
+                SqsCompletionHandlerMessage::mark_complete_from { queue_url, msg, completed } => {
+                    self.mark_complete_from(queue_url, msg, completed).await;
+                }
+                SqsCompletionHandlerMessage::ack_message_from { queue_url, msg } => {
+                    self.ack_message_from(queue_url, msg).await
+                }
+                SqsCompletionHandlerMessage::is_duplicate { identity, respond } => {
+                    let _ = respond.send(self.is_duplicate(identity).await);
+                }
+                SqsCompletionHandlerMessage::pending_identities { respond } => {
+                    let _ = respond.send(self.pending_identities());
+                }
+                SqsCompletionHandlerMessage::recently_cached { respond } => {
+                    let _ = respond.send(self.recently_cached());
+                }
+                SqsCompletionHandlerMessage::snapshot { respond } => {
+                    let _ = respond.send(self.snapshot());
+                }
+                SqsCompletionHandlerMessage::shutdown { respond } => {
+                    self.request_shutdown(respond).await
+                }
+                SqsCompletionHandlerMessage::pause {} => self.pause(),
+                SqsCompletionHandlerMessage::resume {} => self.resume().await,
+                SqsCompletionHandlerMessage::abandon_buffer { reason } => {
+                    self.abandon_buffer(reason).await
+                }
+                SqsCompletionHandlerMessage::healthcheck { respond } => {
+                    let _ = respond.send(self.healthcheck().await);
+                }
+                SqsCompletionHandlerMessage::_p { .. } => (),
+            };
+        })
+        .catch_unwind()
+        .await;
+
+        if let Err(panic) = routed {
+            error!(
+                "SqsCompletionHandler panicked while routing message: {}",
+                panic_reason(&*panic)
+            );
+        }
+    }
+
+    fn close(&mut self) {
+        self.self_actor = None;
+    }
+
+    fn get_actor_name(&self) -> &str {
+        &self.self_actor.as_ref().unwrap().actor_name
+    }
+}
+
+pub struct SqsCompletionHandlerActor<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    sender: Sender<SqsCompletionHandlerMessage<CE, ProcErr, SqsT>>,
+    inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    actor_name: String,
+    actor_uuid: uuid::Uuid,
+    actor_num: u32,
+    stats: Arc<HandlerStats>,
+    pending_delete_batches: watch::Receiver<usize>,
+    max_pending_delete_batches: Option<usize>,
+}
+
+impl<CE, ProcErr, SqsT> Clone for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    fn clone(&self) -> Self {
+        self.inner_rc
+            .clone()
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        Self {
+            sender: self.sender.clone(),
+            inner_rc: self.inner_rc.clone(),
+            queue_len: self.queue_len.clone(),
+            actor_name: format!(
+                "{} {} {}",
+                stringify!(SqsCompletionHandlerActor),
+                self.actor_uuid,
+                self.actor_num + 1,
+            ),
+            actor_uuid: self.actor_uuid,
+            actor_num: self.actor_num + 1,
+            stats: self.stats.clone(),
+            pending_delete_batches: self.pending_delete_batches.clone(),
+            max_pending_delete_batches: self.max_pending_delete_batches,
+        }
+    }
+}
+
+impl<CE, ProcErr, SqsT> SqsCompletionHandlerActor<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    pub fn new<CPE, CP, Payload, EE, CacheT>(
+        mut actor_impl: SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>,
+    ) -> (Self, tokio::task::JoinHandle<()>)
+    where
+        SqsT: SqsOps + Clone + Send + Sync + 'static,
+        CPE: Debug + Send + Sync + 'static,
+        CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+            + Send
+            + Sync
+            + 'static,
+        Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+        EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+        CacheT: Cache + Send + Sync + Clone + 'static,
+    {
+        let (self_actor, receiver) = Self::attach(&mut actor_impl);
+
+        let handle = tokio::task::spawn(aktors::actor::route_wrapper(aktors::actor::Router::new(
+            actor_impl,
+            receiver,
+            self_actor.inner_rc.clone(),
+            self_actor.queue_len.clone(),
+        )));
+
+        (self_actor, handle)
+    }
+
+    /// Creates a handle to `actor_impl` and gives the handler a copy of it, returning
+    /// the handle with the receiving end of its mailbox. Tests use this directly to
+    /// drive the handler without a router, routing the messages it sends itself.
+    pub(super) fn attach<CPE, CP, Payload, EE, CacheT>(
+        actor_impl: &mut SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>,
+    ) -> (Self, Receiver<SqsCompletionHandlerMessage<CE, ProcErr, SqsT>>)
+    where
+        SqsT: SqsOps + Clone + Send + Sync + 'static,
+        CPE: Debug + Send + Sync + 'static,
+        CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+            + Send
+            + Sync
+            + 'static,
+        Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+        EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+        CacheT: Cache + Send + Sync + Clone + 'static,
+    {
+        let (sender, receiver) = channel(actor_impl.mailbox_capacity);
+        let inner_rc = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(1));
+
+        let queue_len = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let actor_uuid = uuid::Uuid::new_v4();
+        let actor_name = format!("{} {} {}", stringify!(#actor_ty), actor_uuid, 0,);
+        let self_actor = Self {
+            sender,
+            inner_rc,
+            queue_len,
+            actor_name,
+            actor_uuid,
+            actor_num: 0,
+            stats: actor_impl.stats(),
+            pending_delete_batches: actor_impl.pending_deletes_watch.1.clone(),
+            max_pending_delete_batches: actor_impl.max_pending_delete_batches,
+        };
+
+        actor_impl.self_actor = Some(self_actor.clone());
+        actor_impl.schedule_idle_check();
+
+        (self_actor, receiver)
+    }
+
+    /// Lifetime counters for the underlying handler, readable without a round trip
+    /// through the actor.
+    pub fn stats(&self) -> Arc<HandlerStats> {
+        self.stats.clone()
+    }
+
+    pub async fn mark_complete(
+        &self,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    ) -> Result<(), ActorGone> {
+        self.await_pending_deletes().await;
+        self.send(SqsCompletionHandlerMessage::mark_complete { msg, completed }).await
+    }
+
+    /// Like `mark_complete`, but waits for the event to be buffered. If buffering it
+    /// triggered a flush, the flush's summary is returned.
+    pub async fn mark_complete_ack(
+        &self,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    ) -> Result<Option<AckSummary>, ActorGone> {
+        self.await_pending_deletes().await;
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::mark_complete_ack { msg, completed, respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    pub async fn ack_message(&self, msg: SqsMessage) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::ack_message { msg }).await
+    }
+
+    /// Like `mark_complete`, for a message received from `queue_url` rather than the
+    /// handler's own queue.
+    pub async fn mark_complete_from(
+        &self,
+        queue_url: String,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    ) -> Result<(), ActorGone> {
+        self.await_pending_deletes().await;
+        self.send(SqsCompletionHandlerMessage::mark_complete_from { queue_url, msg, completed }).await
+    }
+
+    /// Like `ack_message`, for a message received from `queue_url` rather than the
+    /// handler's own queue.
+    pub async fn ack_message_from(&self, queue_url: String, msg: SqsMessage) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::ack_message_from { queue_url, msg }).await
+    }
+
+    pub async fn ack_all(
+        &self,
+        notify: Option<tokio::sync::oneshot::Sender<()>>,
+    ) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::ack_all { notify }).await
+    }
+
+    /// Like `mark_complete`, but fails with `MailboxError::Full` rather than waiting
+    /// when the mailbox has no space, or too many flushes await deletion.
+    pub fn try_mark_complete(
+        &self,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+    ) -> Result<(), MailboxError> {
+        if self.pending_deletes_full() {
+            return Err(MailboxError::Full);
+        }
+        let msg = SqsCompletionHandlerMessage::mark_complete { msg, completed };
+        let mut sender = self.sender.clone();
+
+        self.queue_len
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        sender.try_send(msg).map_err(|e| {
+            self.queue_len
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            if e.is_closed() {
+                MailboxError::Gone(ActorGone)
+            } else {
+                MailboxError::Full
+            }
+        })
+    }
+
+    /// Like `mark_complete`, but waits at most `timeout` for space in the mailbox.
+    pub async fn mark_complete_timeout(
+        &self,
+        msg: SqsMessage,
+        completed: OutputEvent<CE, ProcErr>,
+        timeout: Duration,
+    ) -> Result<(), MailboxError> {
+        let started = Instant::now();
+        if tokio::time::timeout(timeout, self.await_pending_deletes()).await.is_err() {
+            return Err(MailboxError::TimedOut);
+        }
+        let timeout = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+
+        let msg = SqsCompletionHandlerMessage::mark_complete { msg, completed };
+        let mut sender = self.sender.clone();
+
+        self.queue_len
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        let sent = match tokio::time::timeout(timeout, sender.send(msg)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(MailboxError::Gone(ActorGone)),
+            Err(_) => Err(MailboxError::TimedOut),
+        };
+
+        if sent.is_err() {
+            self.queue_len
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        sent
+    }
+
+    /// Dead-letters and clears everything buffered, eg: to recover a wedged handler
+    /// during an incident.
+    pub async fn abandon_buffer(&self, reason: String) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::abandon_buffer { reason }).await
+    }
+
+    /// Flushes whatever is buffered, including messages sent before this call that
+    /// are still queued, bounded by the handler's shutdown timeout. Flushes even if
+    /// the handler is paused.
+    pub async fn shutdown(&self) -> Result<ShutdownSummary, ActorGone> {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::shutdown { respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    /// Holds flushes until `resume`, see `SqsCompletionHandler::pause`.
+    pub async fn pause(&self) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::pause {}).await
+    }
+
+    pub async fn resume(&self) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::resume {}).await
+    }
+
+    /// Whether more flushes than `max_pending_delete_batches` have messages awaiting
+    /// deletion, see
+    /// `SqsCompletionHandler::with_max_pending_delete_batches`.
+    fn pending_deletes_full(&self) -> bool {
+        match self.max_pending_delete_batches {
+            Some(max_pending_delete_batches) => {
+                *self.pending_delete_batches.borrow() > max_pending_delete_batches
+            }
+            None => false,
+        }
+    }
+
+    /// Waits until no more than `max_pending_delete_batches` flushes have messages
+    /// awaiting deletion. The handler deletes them as their grace periods pass, or
+    /// retries them with the next flush if their deletes didn't go through.
+    async fn await_pending_deletes(&self) {
+        let mut pending_delete_batches = self.pending_delete_batches.clone();
+        loop {
+            if !self.pending_deletes_full() {
+                return;
+            }
+            debug!("Too many flushes awaiting deletion, waiting for the oldest to be deleted");
+            // None once the handler is gone, which the send that follows reports
+            if pending_delete_batches.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// How many messages have been sent to the router without being received yet,
+    /// including those still waiting for space in the mailbox.
+    pub fn mailbox_len(&self) -> usize {
+        self.queue_len.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Registers `msg` as in processing, see `SqsCompletionHandler::begin_processing`.
+    pub async fn begin_processing(&self, msg: SqsMessage) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::begin_processing { msg }).await
+    }
+
+    /// Cancels a deletion deferred by the delete grace period, see
+    /// `SqsCompletionHandler::cancel_delete`.
+    pub async fn cancel_delete(&self, message_id: String) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::cancel_delete { message_id }).await
+    }
+
+    /// Replaces the handler's completion policy at runtime, eg: to tune batch sizes
+    /// without a restart. The time of the last flush is preserved.
+    pub async fn update_policy(&self, completion_policy: CompletionPolicy) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::update_policy { completion_policy }).await
+    }
+
+    /// Replaces the handler's `on_ack` callback at runtime. Messages routed before
+    /// this are acked with the previous callback.
+    pub async fn update_on_ack(
+        &self,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+    ) -> Result<(), ActorGone> {
+        self.send(SqsCompletionHandlerMessage::update_on_ack {
+            on_ack: Arc::new(on_ack),
+        })
+        .await
+    }
+
+    /// Probes SQS from the handler, see `SqsCompletionHandler::healthcheck`.
+    pub async fn healthcheck(&self) -> Result<Result<(), HealthError>, ActorGone> {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::healthcheck { respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    /// The identities of buffered events, see `SqsCompletionHandler::pending_identities`.
+    pub async fn pending_identities(&self) -> Result<Vec<Vec<u8>>, ActorGone> {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::pending_identities { respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    /// The most recently cached identities, see `SqsCompletionHandler::recently_cached`.
+    pub async fn recently_cached(&self) -> Result<Vec<Vec<u8>>, ActorGone> {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::recently_cached { respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    /// Dumps the handler's state, see `SqsCompletionHandler::snapshot`.
+    pub async fn snapshot(&self) -> Result<HandlerSnapshot<CE>, ActorGone> {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        self.send(SqsCompletionHandlerMessage::snapshot { respond }).await?;
+        response.await.map_err(|_| ActorGone)
+    }
+
+    /// Lets a consumer skip messages whose identity has already been processed,
+    /// before spending any work on them. Returns false if the router is gone.
+    pub async fn is_duplicate(&self, identity: Vec<u8>) -> bool {
+        let (respond, response) = tokio::sync::oneshot::channel();
+        if let Err(e) = self.send(SqsCompletionHandlerMessage::is_duplicate { identity, respond }).await {
+            warn!("Failed to check for duplicate: {}", e);
+            return false;
+        }
+
+        response.await.unwrap_or(false)
+    }
+
+    pub async fn mark_complete_or_panic(&self, msg: SqsMessage, completed: OutputEvent<CE, ProcErr>) {
+        if let Err(e) = self.mark_complete(msg, completed).await {
+            panic!("{}, propagating error. SqsCompletionHandler", e)
+        }
+    }
+
+    pub async fn ack_message_or_panic(&self, msg: SqsMessage) {
+        if let Err(e) = self.ack_message(msg).await {
+            panic!("{}, propagating error. SqsCompletionHandler", e)
+        }
+    }
+
+    pub async fn ack_all_or_panic(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
+        if let Err(e) = self.ack_all(notify).await {
+            panic!("{}, propagating error. SqsCompletionHandler", e)
+        }
+    }
+
+    /// Queues `msg` for the router, waiting for space in the mailbox so that callers
+    /// are held back rather than piling up sends. Returns `ActorGone` if the router
+    /// has already shut down.
+    pub(super) async fn send(
+        &self,
+        msg: SqsCompletionHandlerMessage<CE, ProcErr, SqsT>,
+    ) -> Result<(), ActorGone> {
+        let mut sender = self.sender.clone();
+
+        self.queue_len
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        sender.send(msg).await.map_err(|_| {
+            self.queue_len
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            ActorGone
+        })
+    }
+
+    async fn _p(&self, _p: std::marker::PhantomData<(SqsT)>) {
+        panic!("Invalid to call p");
+        let msg = SqsCompletionHandlerMessage::_p { _p };
+        if let Err(_e) = self.sender.clone().send(msg).await {
+            panic!("Receiver has failed, propagating error. _p")
+        }
+        self.queue_len
+            .clone()
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl<CE, ProcErr, SqsT> Drop for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.inner_rc
+            .clone()
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl<CE, ProcErr, SqsT> CompletionHandler for SqsCompletionHandlerActor<CE, ProcErr, SqsT>
+where
+    CE: Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+{
+    type Message = SqsMessage;
+    type CompletedEvent = OutputEvent<CE, ProcErr>;
+
+    async fn mark_complete(&self, msg: Self::Message, completed_event: Self::CompletedEvent) {
+        SqsCompletionHandlerActor::mark_complete_or_panic(self, msg, completed_event).await
+    }
+
+    async fn ack_message(&self, msg: Self::Message) {
+        SqsCompletionHandlerActor::ack_message_or_panic(self, msg).await
+    }
+
+    async fn ack_all(&self, notify: Option<tokio::sync::oneshot::Sender<()>>) {
+        SqsCompletionHandlerActor::ack_all_or_panic(self, notify).await
+    }
+}
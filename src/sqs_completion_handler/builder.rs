@@ -0,0 +1,907 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use rusoto_sqs::Message as SqsMessage;
+use rusoto_sqs::SqsClient;
+use tokio::sync::watch;
+
+use crate::adaptive_visibility::AdaptiveVisibility;
+use crate::audit::AuditSink;
+use crate::cache::Cache;
+use crate::completion_event_serializer::{CompletionEventSerializer, NopSerializer, SerializerId};
+use crate::completion_policy::CompletionPolicy;
+use crate::dead_letter::{DeadLetter, OversizedEventPolicy};
+use crate::dedup::{CacheFailurePolicy, DedupConfig, IdentityFallback};
+use crate::delete_throttle::DeleteThrottle;
+use crate::error::ValidationError;
+use crate::event_emitter::{ErasedEmitter, EventEmitter, NopEmitter};
+use crate::handler_stats::HandlerStats;
+use crate::message_checks::MessageChecks;
+use crate::metrics::CompletionMetrics;
+use crate::ordering::StrictOrdering;
+use crate::retry::RetryConfig;
+use crate::sqs_ops::{SqsConfig, SqsOps};
+use crate::wal::Wal;
+
+use super::{
+    queue_name_from_url, DeleteFailurePolicy, InFlightTimeoutAction, SqsCompletionHandler,
+    SqsCompletionHandlerActor, StreamingConfig, RECENTLY_CACHED_CAPACITY,
+};
+
+impl<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+    SqsCompletionHandler<SqsT, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+where
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+    CPE: Debug + Send + Sync + 'static,
+    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+        + Send
+        + Sync
+        + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+    CE: Send + Sync + Clone + 'static,
+    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+    CacheT: Cache + Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+{
+    pub fn new(
+        sqs_client: SqsT,
+        queue_url: String,
+        completion_serializer: CP,
+        event_emitter: EE,
+        completion_policy: CompletionPolicy,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+        cache: CacheT,
+    ) -> Self {
+        Self::with_optional_emitter(
+            sqs_client,
+            queue_url,
+            completion_serializer,
+            Some(event_emitter),
+            completion_policy,
+            on_ack,
+            cache,
+        )
+    }
+
+    fn with_optional_emitter(
+        sqs_client: SqsT,
+        queue_url: String,
+        completion_serializer: CP,
+        event_emitter: Option<EE>,
+        completion_policy: CompletionPolicy,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+        cache: CacheT,
+    ) -> Self {
+        Self {
+            sqs_client,
+            delete_clients: vec![],
+            next_delete_client: 0,
+            delete_throttle: DeleteThrottle::default(),
+            queue_name: queue_name_from_url(&queue_url),
+            queue_url,
+            completed_events: Vec::with_capacity(completion_policy.max_messages() as usize),
+            completed_event_sources: Vec::with_capacity(completion_policy.max_messages() as usize),
+            completed_event_identities: Vec::with_capacity(completion_policy.max_messages() as usize),
+            identities: Vec::with_capacity(completion_policy.max_messages() as usize),
+            recently_cached: std::collections::VecDeque::with_capacity(RECENTLY_CACHED_CAPACITY),
+            identity_sources: HashMap::new(),
+            source_messages: HashMap::new(),
+            buffered_partials: HashMap::new(),
+            completed_messages: Vec::with_capacity(completion_policy.max_messages() as usize),
+            message_queues: HashMap::new(),
+            completion_serializer: Arc::new(RwLock::new(completion_serializer)),
+            event_emitter,
+            completion_policy,
+            on_ack: Arc::new(on_ack),
+            self_actor: None,
+            cache,
+            stats: Arc::new(HandlerStats::new()),
+            emit_retry: RetryConfig::no_retry(),
+            delete_retry: RetryConfig::default(),
+            cache_retry: RetryConfig::new(3, Duration::from_millis(2)),
+            proc_err_report_interval: None,
+            last_proc_err_report: Instant::now(),
+            fail_fast_on_delete: false,
+            strict_ordering: StrictOrdering::default(),
+            message_checks: MessageChecks::default(),
+            on_first_buffered: None,
+            schema_version: None,
+            deadline_attribute: None,
+            receipt_handle_transform: None,
+            retain_source_body: false,
+            emit_empty: false,
+            flush_debounce: None,
+            idle_heartbeat: None,
+            idle_since: Instant::now(),
+            pending_flush: None,
+            paused: false,
+            shutdown_started: None,
+            events_without_messages: 0,
+            divergence_threshold: 0.5,
+            streaming: None,
+            trace_context: None,
+            shutdown_timeout: Duration::from_secs(10),
+            mailbox_capacity: 1,
+            request_timeout: Duration::from_millis(250),
+            ack_deadline: None,
+            max_event_bytes: None,
+            max_buffer_bytes: None,
+            dry_run: None,
+            buffered_bytes: 0,
+            dead_letter: None,
+            isolate_serialization_failures: false,
+            wal: None,
+            delay_fn: None,
+            in_flight_timeout: None,
+            in_flight: HashMap::new(),
+            adaptive_visibility: None,
+            visibility_extension_scheduled: false,
+            on_router_exit: None,
+            flush_gate: None,
+            redelivery_limit: None,
+            parallel_serialize: None,
+            delete_failure_policy: DeleteFailurePolicy::default(),
+            audit_sink: None,
+            compactor: None,
+            metrics: None,
+            stats_emitter: None,
+            side_emitter: None,
+            side_outputs: vec![],
+            emitted_bytes: 0,
+            flush_semaphore: None,
+            sqs_semaphore: None,
+            partition_key_fn: None,
+            shards: None,
+            message_group_id_fn: None,
+            delete_priority: None,
+            idempotency_tokens: false,
+            dedup: DedupConfig::default(),
+            delete_grace_period: None,
+            pending_deletes: vec![],
+            max_pending_delete_batches: None,
+            undeleted_batches: vec![],
+            pending_deletes_watch: watch::channel(0),
+            graced: HashSet::new(),
+            flush_count: 0,
+            flush_id: None,
+            redactor: Box::new(|payload: &Payload| format!("<{} bytes>", payload.as_ref().len())),
+            fallback_serializer: None,
+            serializers: HashMap::new(),
+            serializer_selector: None,
+            _p: std::marker::PhantomData,
+        }
+    }
+
+    /// Retry policy wrapping `EventEmitter::emit_event`. Defaults to a single attempt.
+    /// Events that still fail to emit stay buffered, with their messages, for the next
+    /// flush.
+    pub fn with_emit_retry(mut self, emit_retry: RetryConfig) -> Self {
+        self.emit_retry = emit_retry;
+        self
+    }
+
+    /// Retry policy wrapping each `delete_message_batch` call. Defaults to 10 attempts.
+    pub fn with_delete_retry(mut self, delete_retry: RetryConfig) -> Self {
+        self.delete_retry = delete_retry;
+        self
+    }
+
+    /// Replaces every message check at once, see `MessageChecks`.
+    pub fn with_message_checks(mut self, message_checks: MessageChecks) -> Self {
+        self.message_checks = message_checks;
+        self
+    }
+
+    /// When set, `mark_complete` checks each message body against its `MD5OfBody`.
+    /// Events from messages that don't match are dead-lettered and their messages
+    /// are deleted, like those failing the validator or the max message age.
+    pub fn with_verify_md5(mut self, verify_md5: bool) -> Self {
+        self.message_checks = self.message_checks.verify_md5(verify_md5);
+        self
+    }
+
+    /// Checks each message in `mark_complete` before its event is buffered. Events
+    /// from messages failing validation are dead-lettered rather than emitted, and
+    /// their messages are deleted, as redelivering a malformed message won't fix it.
+    pub fn with_validator(
+        mut self,
+        validator: impl Fn(&SqsMessage) -> Result<(), ValidationError> + Send + Sync + 'static,
+    ) -> Self {
+        self.message_checks = self.message_checks.validator(validator);
+        self
+    }
+
+    /// Called when `mark_complete` buffers an event into an empty buffer, ie: once
+    /// after each flush that emptied it, eg: to arm a flush timer only while there is
+    /// something to flush.
+    pub fn with_on_first_buffered(
+        mut self,
+        on_first_buffered: impl Fn() + Send + Sync + 'static,
+    ) -> Self {
+        self.on_first_buffered = Some(Box::new(on_first_buffered));
+        self
+    }
+
+    /// Tags each emitted batch with the version of the `CE` schema, passed to the
+    /// emitter as `EmitMetadata::schema_version` and to the serializer as
+    /// `EventMeta::schema_version`.
+    pub fn with_schema_version(mut self, schema_version: u32) -> Self {
+        self.schema_version = Some(schema_version);
+        self
+    }
+
+    /// Messages sent longer than `max_message_age` ago, per their `SentTimestamp`
+    /// attribute, are assumed stale. `mark_complete` dead-letters their events and
+    /// deletes them instead of emitting. Messages received without the attribute are
+    /// never considered stale.
+    pub fn with_max_message_age(mut self, max_message_age: Duration) -> Self {
+        self.message_checks = self.message_checks.max_message_age(max_message_age);
+        self
+    }
+
+    /// Reads a deadline for each message from the message attribute `name`, in
+    /// milliseconds since the unix epoch. Events completed after their message's
+    /// deadline are stale, so `mark_complete` drops them, counting them in
+    /// `HandlerStats::events_expired`, and deletes the message.
+    pub fn with_deadline_attribute(mut self, name: impl Into<String>) -> Self {
+        self.deadline_attribute = Some(name.into());
+        self
+    }
+
+    /// Rewrites each receipt handle before it is sent in a `DeleteMessageBatch`
+    /// request, eg: to strip a prefix added by a gateway the messages were received
+    /// through.
+    pub fn with_receipt_handle_transform(
+        mut self,
+        receipt_handle_transform: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.receipt_handle_transform = Some(Box::new(receipt_handle_transform));
+        self
+    }
+
+    /// Replaces every dedup option at once, see `DedupConfig`. The individual `with_*`
+    /// methods below each set one of its options.
+    pub fn with_dedup(mut self, dedup: DedupConfig) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// When set, `mark_complete` looks up each completion's identities in the cache,
+    /// dropping events that were already emitted and deleting every message that
+    /// contributed to them. This costs a cache round trip per identity on every
+    /// completion, so it defaults to false, leaving consumers to skip duplicates up
+    /// front with `is_duplicate`.
+    pub fn with_dedup_on_complete(mut self, dedup_on_complete: bool) -> Self {
+        self.dedup = self.dedup.dedup_on_complete(dedup_on_complete);
+        self
+    }
+
+    /// When set, an event sharing an identity with one already buffered in the current
+    /// window is not buffered again. Its message is still deleted.
+    pub fn with_coalesce_duplicates(mut self, coalesce_duplicates: bool) -> Self {
+        self.dedup = self.dedup.coalesce_duplicates(coalesce_duplicates);
+        self
+    }
+
+    /// Passes the body of each event's source message to the serializer through
+    /// `serialize_completed_events_with_meta`. Bodies are held only as long as their
+    /// message is buffered, so memory stays bounded by the completion policy.
+    pub fn with_retain_source_body(mut self, retain_source_body: bool) -> Self {
+        self.retain_source_body = retain_source_body;
+        self
+    }
+
+    /// Whether a flush with no buffered events still serializes and emits the empty
+    /// batch, eg: as a heartbeat for downstream consumers. Defaults to false.
+    pub fn with_emit_empty(mut self, emit_empty: bool) -> Self {
+        self.emit_empty = emit_empty;
+        self
+    }
+
+    /// Also dedups on the value of the message attribute named `attribute`, eg: a
+    /// business key set by the producer, alongside `OutputEvent::identities`.
+    pub fn with_identity_from_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.dedup = self.dedup.identity_from_attribute(attribute);
+        self
+    }
+
+    /// Derives an identity for completions that have none, see `IdentityFallback`.
+    pub fn with_identity_fallback(mut self, identity_fallback: IdentityFallback) -> Self {
+        self.dedup = self.dedup.identity_fallback(identity_fallback);
+        self
+    }
+
+    /// Delays flushes requested through `ack_all` by up to `flush_debounce`, so that
+    /// further completions and flush requests arriving in the meantime are flushed
+    /// together. A full buffer is still flushed immediately.
+    pub fn with_flush_debounce(mut self, flush_debounce: Duration) -> Self {
+        self.flush_debounce = Some(flush_debounce);
+        self
+    }
+
+    /// Emits the payload returned by `heartbeat_fn` whenever `idle_interval` passes
+    /// without a flush, so that downstream monitors can tell the handler is alive
+    /// while no messages arrive. Heartbeats are emitted once, without retrying.
+    pub fn with_idle_heartbeat(
+        mut self,
+        idle_interval: Duration,
+        heartbeat_fn: impl Fn() -> Payload + Send + Sync + 'static,
+    ) -> Self {
+        self.idle_heartbeat = Some((idle_interval, Box::new(heartbeat_fn)));
+        self
+    }
+
+    /// Retry policy for storing each identity in the cache. Defaults to 3 attempts.
+    pub fn with_cache_retry(mut self, cache_retry: RetryConfig) -> Self {
+        self.cache_retry = cache_retry;
+        self
+    }
+
+    /// When set, `ack_all` stops at the first chunk whose delete request fails outright
+    /// and keeps that chunk and every later one buffered for the next flush.
+    pub fn with_fail_fast_on_delete(mut self, fail_fast_on_delete: bool) -> Self {
+        self.fail_fast_on_delete = fail_fast_on_delete;
+        self
+    }
+
+    /// When set, a flush following one that retained events or messages, eg: events
+    /// rejected downstream or, with `fail_fast_on_delete`, messages whose delete
+    /// failed, only retries what was retained. Everything buffered since is held
+    /// back until a flush gets the retained items through, so that nothing newer is
+    /// emitted ahead of them. This trades throughput for ordering.
+    pub fn with_strict_ordering(mut self, strict_ordering: bool) -> Self {
+        self.strict_ordering.enabled = strict_ordering;
+        self
+    }
+
+    /// The fraction of buffered events that may lack a source message (`Partial`
+    /// completions) before `mark_complete` warns. Defaults to 0.5.
+    pub fn with_divergence_threshold(mut self, divergence_threshold: f64) -> Self {
+        self.divergence_threshold = divergence_threshold;
+        self
+    }
+
+    pub fn with_streaming(mut self, streaming: StreamingConfig) -> Self {
+        self.streaming = Some(streaming);
+        self
+    }
+
+    /// Called on every emit to get the W3C traceparent of the active span, which is
+    /// forwarded to the emitter as `EmitMetadata::traceparent`. eg: with
+    /// tracing-opentelemetry, format the trace and span ids of
+    /// `Span::current().context()`.
+    pub fn with_trace_context(
+        mut self,
+        trace_context: impl Fn() -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.trace_context = Some(Box::new(trace_context));
+        self
+    }
+
+    /// How long each SQS request may take before it is retried. Defaults to 250ms.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Spreads `DeleteMessageBatch` requests round-robin across the handler's client
+    /// and `delete_clients`, eg: clients with separate connection pools, for very
+    /// high delete throughput.
+    pub fn with_delete_clients(mut self, delete_clients: Vec<SqsT>) -> Self {
+        self.delete_clients = delete_clients;
+        self
+    }
+
+    /// Backs off from SQS when it throttles deletes, see `DeleteThrottle`. By default
+    /// delete requests are sent one at a time.
+    pub fn with_delete_throttle(mut self, delete_throttle: DeleteThrottle) -> Self {
+        self.delete_throttle = delete_throttle;
+        self
+    }
+
+    /// Runs flushes without side effects, eg: to validate a new pipeline against
+    /// production traffic. Events are serialized, to validate them, but nothing is
+    /// emitted, cached or deleted; what would have been is logged instead. Unless
+    /// `clear_buffer` is set the buffer is kept after each flush, so it grows until
+    /// the handler is dropped and its messages are redelivered.
+    pub fn with_dry_run(mut self, dry_run: bool, clear_buffer: bool) -> Self {
+        self.dry_run = if dry_run { Some(clear_buffer) } else { None };
+        self
+    }
+
+    /// Flushes once the buffered events' serialized size reaches `max_buffer_bytes`.
+    /// The size is kept as a running total, each event being measured once with the
+    /// serializer's `serialized_size` when it is marked complete, reusing the
+    /// measurement from `max_event_bytes` when both are set.
+    pub fn with_max_buffer_bytes(mut self, max_buffer_bytes: usize) -> Self {
+        self.max_buffer_bytes = Some(max_buffer_bytes);
+        self
+    }
+
+    /// Checks each completed event's serialized size as it is marked complete and
+    /// applies `policy` to any larger than `max_event_bytes`.
+    pub fn with_max_event_bytes(
+        mut self,
+        max_event_bytes: usize,
+        policy: OversizedEventPolicy<CE>,
+    ) -> Self {
+        self.max_event_bytes = Some((max_event_bytes, policy));
+        self
+    }
+
+    /// Receives events the handler gives up on, eg: oversized events under
+    /// `OversizedEventPolicy::DeadLetter`.
+    pub fn with_dead_letter(
+        mut self,
+        dead_letter: impl Fn(DeadLetter<CE>) + Send + Sync + 'static,
+    ) -> Self {
+        self.dead_letter = Some(Box::new(dead_letter));
+        self
+    }
+
+    /// Computes how long delivery of each event should be delayed, forwarded to the
+    /// emitter as `EmitMetadata::delay`.
+    pub fn with_delay_fn(
+        mut self,
+        delay_fn: impl Fn(&CE) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.delay_fn = Some(Box::new(delay_fn));
+        self
+    }
+
+    /// When a batch fails to serialize, bisects it to find the events that fail on
+    /// their own, dead-letters them and emits the rest. Batches that only fail as a
+    /// whole still go to the fallback serializer, and are retained for the next flush
+    /// if that fails too.
+    pub fn with_isolate_serialization_failures(mut self, isolate_serialization_failures: bool) -> Self {
+        self.isolate_serialization_failures = isolate_serialization_failures;
+        self
+    }
+
+    /// Consulted whenever the completion policy calls for a flush. While it returns
+    /// false flushes are deferred, eg: during a maintenance window, until
+    /// `max_deferred` items are buffered, at which point the handler flushes anyway.
+    pub fn with_flush_gate(
+        mut self,
+        flush_gate: impl Fn() -> bool + Send + Sync + 'static,
+        max_deferred: usize,
+    ) -> Self {
+        self.flush_gate = Some((Box::new(flush_gate), max_deferred));
+        self
+    }
+
+    /// Breaks redelivery loops for messages that keep failing. Each failed completion
+    /// of a message is counted in the cache, and once it has failed `limit` times the
+    /// next failure hands it to `on_poison`, eg: to forward it to a dead-letter queue,
+    /// and deletes it. Unlike an SQS redrive policy this works in-process, and needs a
+    /// cache shared by every consumer of the queue to count accurately.
+    pub fn with_redelivery_limit(
+        mut self,
+        limit: u32,
+        on_poison: impl Fn(SqsMessage) + Send + Sync + 'static,
+    ) -> Self {
+        self.redelivery_limit = Some((limit, Box::new(on_poison)));
+        self
+    }
+
+    /// Merges buffered events at the start of each flush, before serialization, eg:
+    /// summing counters that share a key. Compacted events lose track of their
+    /// source messages, so if any is rejected downstream all of the batch's messages
+    /// are kept for redelivery, and serializers see no source metadata.
+    pub fn with_compactor(
+        mut self,
+        compactor: impl Fn(Vec<CE>) -> Vec<CE> + Send + Sync + 'static,
+    ) -> Self {
+        self.compactor = Some(Box::new(compactor));
+        self
+    }
+
+    /// Bounds how many flushes may emit and delete at once. A handler only ever runs
+    /// one flush at a time, so this is useful to handlers sharing the semaphore, eg:
+    /// one per queue, via `with_flush_semaphore`.
+    pub fn with_max_concurrent_flushes(self, max_concurrent_flushes: usize) -> Self {
+        self.with_flush_semaphore(Arc::new(tokio::sync::Semaphore::new(max_concurrent_flushes)))
+    }
+
+    /// Flushes wait for a permit from `flush_semaphore`, shared with other handlers.
+    pub fn with_flush_semaphore(mut self, flush_semaphore: Arc<tokio::sync::Semaphore>) -> Self {
+        self.flush_semaphore = Some(flush_semaphore);
+        self
+    }
+
+    /// Every SQS batch request the handler sends, ie: deletes and visibility
+    /// changes, waits for a permit from `sqs_semaphore`. Sharing the semaphore
+    /// between handlers bounds their combined in-flight requests, eg: to stay within
+    /// account quotas. Time spent waiting doesn't count towards the request timeout.
+    pub fn with_sqs_semaphore(mut self, sqs_semaphore: Arc<tokio::sync::Semaphore>) -> Self {
+        self.sqs_semaphore = Some(sqs_semaphore);
+        self
+    }
+
+    /// Groups each flushed batch by partition key, serializing and emitting each
+    /// group separately with `EmitMetadata::partition_key` set, eg: for Kinesis or
+    /// Kafka emitters.
+    pub fn with_partition_key_fn(
+        mut self,
+        partition_key_fn: impl Fn(&CE) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.partition_key_fn = Some(Box::new(partition_key_fn));
+        self
+    }
+
+    /// Splits each flushed batch into `shard_count` groups by `shard_key_fn(event) %
+    /// shard_count`, serializing and emitting each group separately with
+    /// `EmitMetadata::shard_id` set, so that load is spread evenly across a fixed
+    /// number of downstream shards. Shards without events in a flush aren't emitted to.
+    pub fn with_shards(
+        mut self,
+        shard_count: u32,
+        shard_key_fn: impl Fn(&CE) -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        self.shards = Some((shard_count.max(1), Box::new(shard_key_fn)));
+        self
+    }
+
+    /// Whether a cache outage should hold up deletes, defaults to
+    /// `CacheFailurePolicy::Proceed`.
+    pub fn with_cache_failure_policy(mut self, cache_failure_policy: CacheFailurePolicy) -> Self {
+        self.dedup = self.dedup.cache_failure_policy(cache_failure_policy);
+        self
+    }
+
+    /// Skips storing identities in the cache after a flush whose events were all
+    /// accepted downstream, saving the cache round trips when the downstream is
+    /// idempotent and duplicates are harmless. Identities are still cached after
+    /// flushes that had events rejected, or that missed their ack deadline.
+    ///
+    /// Ignored by dedup-only handlers, which have no emitter and so rely on the
+    /// cache alone to drop duplicates.
+    pub fn with_skip_cache_on_emit_success(mut self, skip_cache_on_emit_success: bool) -> Self {
+        self.dedup = self.dedup.skip_cache_on_emit_success(skip_cache_on_emit_success);
+        self
+    }
+
+    /// Passes each emitted batch an `EmitMetadata::idempotency_token` derived from its
+    /// payloads, for emitters targeting APIs that deduplicate on an idempotency key.
+    pub fn with_idempotency_tokens(mut self, idempotency_tokens: bool) -> Self {
+        self.idempotency_tokens = idempotency_tokens;
+        self
+    }
+
+    /// Orders messages before they are chunked into delete batches, eg: oldest first
+    /// so that they are deleted before their visibility timeout expires. By default
+    /// messages are deleted in the order they were completed.
+    pub fn with_delete_priority(
+        mut self,
+        delete_priority: impl Fn(&SqsMessage, &SqsMessage) -> std::cmp::Ordering + Send + Sync + 'static,
+    ) -> Self {
+        self.delete_priority = Some(Box::new(delete_priority));
+        self
+    }
+
+    /// Assigns each emitted batch a `MessageGroupId`, passed to the emitter as
+    /// `EmitMetadata::message_group_id`, so that a FIFO downstream preserves order.
+    /// Events are always emitted in the order they were completed, within each
+    /// partition when a `partition_key_fn` is set.
+    pub fn with_message_group_id_fn(
+        mut self,
+        message_group_id_fn: impl Fn(&[CE]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.message_group_id_fn = Some(Box::new(message_group_id_fn));
+        self
+    }
+
+    /// Emits events as usual but holds off deleting their messages for
+    /// `delete_grace_period`, during which `cancel_delete` can still stop the
+    /// deletion, eg: if the downstream rejects an event after accepting it.
+    pub fn with_delete_grace_period(mut self, delete_grace_period: Duration) -> Self {
+        self.delete_grace_period = Some(delete_grace_period);
+        self
+    }
+
+    /// Bounds how many flushes' messages may be awaiting deletion at once, as emitted
+    /// events whose messages would be redelivered, and so emitted again, if the
+    /// handler crashed. Once more than `max_pending_delete_batches` flushes have
+    /// messages awaiting their delete grace period, being deleted or retained after a
+    /// failed or timed out delete, `SqsCompletionHandlerActor::mark_complete`
+    /// and its variants wait for the oldest to be deleted before sending anything
+    /// else, and `try_mark_complete` fails with `MailboxError::Full`. The handler
+    /// itself keeps routing messages, so flushes and deletes carry on meanwhile.
+    pub fn with_max_pending_delete_batches(mut self, max_pending_delete_batches: usize) -> Self {
+        self.max_pending_delete_batches = Some(max_pending_delete_batches);
+        self
+    }
+
+    /// Emits a `FlushStats` record, serialized as JSON, after each flush, eg: to a
+    /// metrics queue for downstream aggregation. Failures to emit it are logged and
+    /// otherwise ignored.
+    pub fn with_stats_emitter<StatsE>(mut self, stats_emitter: StatsE) -> Self
+    where
+        StatsE: EventEmitter<Event = Vec<u8>> + Send + Sync + 'static,
+        StatsE::Error: Send,
+    {
+        self.stats_emitter = Some(Box::new(ErasedEmitter(stats_emitter)));
+        self
+    }
+
+    /// Emits the `OutputEvent::side_output` of each buffered completion at flush, after
+    /// the completed events. Side outputs are not retried, failures to emit them are
+    /// logged and don't hold up deletes. Without a side emitter they are discarded.
+    pub fn with_side_emitter<SideE>(mut self, side_emitter: SideE) -> Self
+    where
+        SideE: EventEmitter<Event = Vec<u8>> + Send + Sync + 'static,
+        SideE::Error: Send,
+    {
+        self.side_emitter = Some(Box::new(ErasedEmitter(side_emitter)));
+        self
+    }
+
+    /// Reports each flush to `metrics`. It is shared so that the caller can keep a
+    /// handle to export from, eg: `PrometheusMetrics::gather`.
+    pub fn with_metrics(mut self, metrics: Arc<dyn CompletionMetrics + Send + Sync>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records the outcome of every message each flush tries to delete.
+    pub fn with_audit_sink(mut self, audit_sink: impl AuditSink + Send + Sync + 'static) -> Self {
+        self.audit_sink = Some(Box::new(audit_sink));
+        self
+    }
+
+    /// How to treat messages whose deletion failed, defaults to
+    /// `DeleteFailurePolicy::WaitTimeout`.
+    pub fn with_delete_failure_policy(mut self, delete_failure_policy: DeleteFailurePolicy) -> Self {
+        self.delete_failure_policy = delete_failure_policy;
+        self
+    }
+
+    /// Serializes flushed batches across up to `workers` blocking tasks, for
+    /// serializers that implement `serialize_one` and whose per-event serialization
+    /// is expensive. Has no effect on other serializers.
+    pub fn with_parallel_serialize(mut self, workers: usize) -> Self {
+        self.parallel_serialize = Some(workers.max(1));
+        self
+    }
+
+    /// Renders a payload wherever the handler logs one. Payloads may hold sensitive
+    /// data, so by default only their size is logged.
+    pub fn with_redactor(mut self, redactor: impl Fn(&Payload) -> String + Send + Sync + 'static) -> Self {
+        self.redactor = Box::new(redactor);
+        self
+    }
+
+    /// Tracks messages registered with `begin_processing`, applying `action` to any
+    /// that aren't completed or acked within `deadline`.
+    pub fn with_in_flight_timeout(mut self, deadline: Duration, action: InFlightTimeoutAction) -> Self {
+        self.in_flight_timeout = Some((deadline, action));
+        self
+    }
+
+    /// Learns how long messages take from `begin_processing` to completion, or from
+    /// first receipt for messages that weren't registered, and periodically extends
+    /// the visibility of in-flight and buffered messages to the configured percentile
+    /// of those durations.
+    pub fn with_adaptive_visibility(mut self, adaptive_visibility: AdaptiveVisibility) -> Self {
+        self.adaptive_visibility = Some(adaptive_visibility);
+        self
+    }
+
+    /// Called with whatever is still buffered when the handler is dropped, ie: when
+    /// its router exits, whether cleanly or by panicking. Lets callers persist or
+    /// alert on events that would otherwise be lost. As it may run during a panic it
+    /// must not panic itself.
+    pub fn with_on_router_exit(
+        mut self,
+        on_router_exit: impl Fn(Vec<CE>, Vec<SqsMessage>) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_router_exit = Some(Box::new(on_router_exit));
+        self
+    }
+
+    /// Used when the primary serializer fails on a batch, eg: a debug-format dump.
+    /// Its output is emitted with `EmitMetadata::degraded` set.
+    pub fn with_fallback_serializer(
+        mut self,
+        fallback_serializer: impl CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.fallback_serializer = Some(Box::new(fallback_serializer));
+        self
+    }
+
+    /// Registers an additional serializer under `serializer_id`, used for the events
+    /// the `serializer_selector` maps to it.
+    pub fn with_serializer(
+        mut self,
+        serializer_id: impl Into<SerializerId>,
+        serializer: impl CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.serializers
+            .insert(serializer_id.into(), Box::new(serializer));
+        self
+    }
+
+    /// Picks the serializer for each completed event, eg: by the variant of an event
+    /// enum. Each flush is grouped by serializer and each group serialized and emitted
+    /// separately. Events mapped to an id that was never registered with
+    /// `with_serializer` are serialized by the primary serializer.
+    pub fn with_serializer_selector(
+        mut self,
+        serializer_selector: impl Fn(&CE) -> SerializerId + Send + Sync + 'static,
+    ) -> Self {
+        self.serializer_selector = Some(Box::new(serializer_selector));
+        self
+    }
+
+    /// Persists buffered events so that `recover` can restore them after a crash.
+    pub fn with_wal(mut self, wal: impl Wal<CE> + Send + Sync + 'static) -> Self {
+        self.wal = Some(Box::new(wal));
+        self
+    }
+
+    /// Bounds how long the final flush in `shutdown` may take. Defaults to 10 seconds.
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// How many messages the actor's mailbox holds before senders have to wait, or
+    /// `try_mark_complete` fails with `MailboxError::Full`. Defaults to 1, so that
+    /// completions are held back while a flush is in progress. At least 1.
+    pub fn with_mailbox_capacity(mut self, mailbox_capacity: usize) -> Self {
+        self.mailbox_capacity = mailbox_capacity.max(1);
+        self
+    }
+
+    /// Bounds how long a flush may take overall. Once `ack_deadline` has passed the
+    /// flush abandons the phase it is in and skips the rest, retaining the events,
+    /// identities and messages they hadn't handled for the next flush, and reports
+    /// the phase in `AckSummary::timed_out`.
+    pub fn with_ack_deadline(mut self, ack_deadline: Duration) -> Self {
+        self.ack_deadline = Some(ack_deadline);
+        self
+    }
+
+    /// How often to log the aggregated `ProcErr` summary. When unset the summary is
+    /// logged on every flush.
+    pub fn with_proc_err_report_interval(mut self, interval: Duration) -> Self {
+        self.proc_err_report_interval = Some(interval);
+        self
+    }
+}
+
+impl<CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+    SqsCompletionHandler<SqsClient, CPE, CP, CE, Payload, EE, CacheT, ProcErr>
+where
+    CPE: Debug + Send + Sync + 'static,
+    CP: CompletionEventSerializer<CompletedEvent = CE, Output = Payload, Error = CPE>
+        + Send
+        + Sync
+        + 'static,
+    Payload: AsRef<[u8]> + Clone + Send + Sync + 'static,
+    CE: Send + Sync + Clone + 'static,
+    EE: EventEmitter<Event = Payload> + Send + Sync + 'static,
+    CacheT: Cache + Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+{
+    /// Builds the SqsClient from `sqs_config` rather than taking one. Use `new` to
+    /// inject a client directly.
+    pub fn new_with_config(
+        sqs_config: SqsConfig,
+        queue_url: String,
+        completion_serializer: CP,
+        event_emitter: EE,
+        completion_policy: CompletionPolicy,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsClient>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+        cache: CacheT,
+    ) -> Self {
+        Self::new(
+            sqs_config.client(),
+            queue_url,
+            completion_serializer,
+            event_emitter,
+            completion_policy,
+            on_ack,
+            cache,
+        )
+        .with_request_timeout(sqs_config.timeout)
+    }
+
+    /// Builds a handler whose SqsClient points at a LocalStack `endpoint`, eg:
+    /// "http://localhost:4566", using the dummy credentials LocalStack accepts.
+    #[cfg(feature = "localstack")]
+    pub fn new_localstack(
+        endpoint: impl Into<String>,
+        queue_url: String,
+        completion_serializer: CP,
+        event_emitter: EE,
+        completion_policy: CompletionPolicy,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsClient>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+        cache: CacheT,
+    ) -> Self {
+        let sqs_client = SqsClient::new_with(
+            rusoto_core::HttpClient::new().expect("Failed to create HttpClient"),
+            rusoto_core::credential::StaticProvider::new_minimal(
+                "localstack".to_owned(),
+                "localstack".to_owned(),
+            ),
+            rusoto_core::Region::Custom {
+                name: "us-east-1".to_owned(),
+                endpoint: endpoint.into(),
+            },
+        );
+
+        Self::new(
+            sqs_client,
+            queue_url,
+            completion_serializer,
+            event_emitter,
+            completion_policy,
+            on_ack,
+            cache,
+        )
+    }
+}
+
+impl<SqsT, CE, CacheT, ProcErr>
+    SqsCompletionHandler<
+        SqsT,
+        Infallible,
+        NopSerializer<CE>,
+        CE,
+        Vec<u8>,
+        NopEmitter<Vec<u8>>,
+        CacheT,
+        ProcErr,
+    >
+where
+    SqsT: SqsOps + Clone + Send + Sync + 'static,
+    CE: Send + Sync + Clone + 'static,
+    CacheT: Cache + Send + Sync + Clone + 'static,
+    ProcErr: Debug + Send + Sync + 'static,
+{
+    /// A dedup-only handler, for pipelines whose side effects happen in the event
+    /// handler itself. Flushes store identities in the cache and delete messages, but
+    /// nothing is serialized or emitted, so no serializer or emitter is needed.
+    pub fn new_without_emitter(
+        sqs_client: SqsT,
+        queue_url: String,
+        completion_policy: CompletionPolicy,
+        on_ack: impl Fn(SqsCompletionHandlerActor<CE, ProcErr, SqsT>, Result<String, String>)
+            + Send
+            + Sync
+            + 'static,
+        cache: CacheT,
+    ) -> Self {
+        Self::with_optional_emitter(
+            sqs_client,
+            queue_url,
+            NopSerializer::default(),
+            None,
+            completion_policy,
+            on_ack,
+            cache,
+        )
+    }
+}
@@ -0,0 +1,2783 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use aktors::actor::Actor;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::Receiver;
+
+use super::*;
+use crate::error::{MailboxError, ValidationError};
+use crate::handler_stats::CompletionCounts;
+use crate::test_support::{
+    capture_logs, captured_logs, message, MockCache, MockEmitter, MockSqs, MockWal,
+    StringSerializer, QUEUE_URL, UNSERIALIZABLE,
+};
+
+type TestHandler<CP = StringSerializer> =
+    SqsCompletionHandler<MockSqs, String, CP, String, Vec<u8>, MockEmitter, MockCache, String>;
+
+type Mailbox = Receiver<SqsCompletionHandlerMessage<String, String, MockSqs>>;
+
+/// The mocks behind a test handler, and every outcome reported to its `on_ack`.
+struct Mocks {
+    sqs: MockSqs,
+    emitter: MockEmitter,
+    cache: MockCache,
+    acks: Arc<Mutex<Vec<Result<String, String>>>>,
+}
+
+impl Mocks {
+    fn acks(&self) -> Vec<Result<String, String>> {
+        self.acks.lock().unwrap().clone()
+    }
+}
+
+/// A handler that flushes every `max_messages` completions, retrying deletes once.
+fn new_handler(max_messages: u16) -> (TestHandler, Mocks) {
+    new_handler_with(StringSerializer, max_messages)
+}
+
+/// Like `new_handler`, serializing with `serializer`.
+fn new_handler_with<CP>(serializer: CP, max_messages: u16) -> (TestHandler<CP>, Mocks)
+where
+    CP: CompletionEventSerializer<CompletedEvent = String, Output = Vec<u8>, Error = String>
+        + Send
+        + Sync
+        + 'static,
+{
+    let mocks = Mocks {
+        sqs: MockSqs::new(),
+        emitter: MockEmitter::new(),
+        cache: MockCache::new(),
+        acks: Arc::new(Mutex::new(vec![])),
+    };
+
+    let acks = mocks.acks.clone();
+    let handler = SqsCompletionHandler::new(
+        mocks.sqs.clone(),
+        QUEUE_URL.to_owned(),
+        serializer,
+        mocks.emitter.clone(),
+        CompletionPolicy::new(max_messages, Duration::from_secs(60)),
+        move |_, ack| acks.lock().unwrap().push(ack),
+        mocks.cache.clone(),
+    )
+    .with_delete_retry(RetryConfig::new(2, Duration::from_millis(1)));
+
+    (handler, mocks)
+}
+
+/// Gives `handler` a handle without a router behind it, so that it can be driven
+/// directly. Messages it sends itself wait in the returned mailbox.
+fn attach<CP>(handler: &mut TestHandler<CP>) -> Mailbox
+where
+    CP: CompletionEventSerializer<CompletedEvent = String, Output = Vec<u8>, Error = String>
+        + Send
+        + Sync
+        + 'static,
+{
+    let (_, mailbox) = SqsCompletionHandlerActor::attach(handler);
+    mailbox
+}
+
+fn total(event: &str) -> OutputEvent<String, String> {
+    OutputEvent::new(Completion::Total(event.to_owned()))
+}
+
+#[tokio::test]
+async fn stats_count_events_emitted_across_flushes() {
+    let (mut handler, mocks) = new_handler(2);
+    let _mailbox = attach(&mut handler);
+    let stats = handler.stats();
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.mark_complete(message("3"), total("c")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(stats.events_emitted(), 3);
+    assert_eq!(stats.messages_deleted(), 3);
+    assert_eq!(stats.delete_failures(), 0);
+    assert!(stats.last_flush_millis().is_some());
+    assert_eq!(mocks.emitter.batches().len(), 2);
+}
+
+#[tokio::test]
+async fn emit_retry_is_separate_from_delete_retry() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_emit_retry(RetryConfig::new(3, Duration::from_millis(1)))
+        .with_delete_retry(RetryConfig::no_retry())
+        .with_request_timeout(Duration::from_millis(20));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.fail_emits(2);
+    mocks.sqs.stall_deletes(1);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.emitted_events, 1);
+    assert_eq!(mocks.emitter.attempts(), 3);
+    assert_eq!(summary.failed_messages, vec!["1".to_owned()]);
+    assert_eq!(mocks.sqs.delete_attempts(), 1);
+}
+
+#[tokio::test]
+async fn delete_retry_is_separate_from_emit_retry() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_delete_retry(RetryConfig::new(2, Duration::from_millis(1)))
+        .with_request_timeout(Duration::from_millis(20));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.fail_emits(1);
+    mocks.sqs.stall_deletes(1);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_message(message("2")).await;
+    let summary = handler.ack_all(None).await;
+
+    // Emits aren't retried by default, so the event and its message are retained
+    assert_eq!(summary.emitted_events, 0);
+    assert_eq!(mocks.emitter.attempts(), 1);
+    assert_eq!(handler.completed_events, vec!["a".to_owned()]);
+    assert_eq!(summary.deleted_messages, 1);
+    assert_eq!(mocks.sqs.delete_attempts(), 2);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["2".to_owned()]);
+}
+
+#[tokio::test]
+async fn exhausted_emit_retries_leave_identities_uncached() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_emit_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.fail_emits(2);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.emitted_events, 0);
+    assert_eq!(mocks.emitter.attempts(), 2);
+    assert_eq!(mocks.cache.len(), 0);
+    assert!(mocks.sqs.deleted_ids().is_empty());
+    assert_eq!(handler.pending_identities(), vec![b"x".to_vec()]);
+
+    // Cached once the event is emitted
+    handler.ack_all(None).await;
+    assert!(mocks.cache.contains(Identity(b"x".to_vec())));
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+fn error(err: &str) -> OutputEvent<String, String> {
+    OutputEvent::new(Completion::Error(err.to_owned()))
+}
+
+fn partial(event: &str, err: &str) -> OutputEvent<String, String> {
+    OutputEvent::new(Completion::Partial((event.to_owned(), err.to_owned())))
+}
+
+#[tokio::test]
+async fn proc_errors_are_tallied_by_kind() {
+    let (mut handler, _mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), error("timeout")).await;
+    handler.mark_complete(message("2"), error("timeout")).await;
+    handler.mark_complete(message("3"), partial("a", "malformed")).await;
+    handler.mark_complete(message("4"), error("malformed")).await;
+    handler.mark_complete(message("5"), total("b")).await;
+
+    let counts = handler.stats().proc_error_counts();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts[&format!("{:?}", "timeout")], 2);
+    assert_eq!(counts[&format!("{:?}", "malformed")], 2);
+}
+
+#[tokio::test]
+async fn routing_continues_after_a_panic() {
+    let (mut handler, mocks) = new_handler(1);
+    let _mailbox = attach(&mut handler);
+    let acks = mocks.acks.clone();
+    let panicked = AtomicBool::new(false);
+    handler.update_on_ack(Arc::new(move |_, ack| {
+        if !panicked.swap(true, Ordering::SeqCst) {
+            panic!("on_ack failed");
+        }
+        acks.lock().unwrap().push(ack);
+    }));
+
+    handler
+        .route_message(SqsCompletionHandlerMessage::mark_complete {
+            msg: message("1"),
+            completed: total("a"),
+        })
+        .await;
+    handler
+        .route_message(SqsCompletionHandlerMessage::mark_complete {
+            msg: message("2"),
+            completed: total("b"),
+        })
+        .await;
+
+    assert!(mocks.acks().contains(&Ok("2".to_owned())));
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+}
+
+/// Panics on the first batch it serializes, then serializes like `StringSerializer`.
+#[derive(Default)]
+struct PanicOnceSerializer {
+    panicked: bool,
+}
+
+impl CompletionEventSerializer for PanicOnceSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[String],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        if !self.panicked {
+            self.panicked = true;
+            panic!("serializer failed");
+        }
+        StringSerializer.serialize_completed_events(completed_events)
+    }
+}
+
+#[tokio::test]
+async fn a_panicking_serializer_leaves_the_buffer_intact() {
+    let (mut handler, mocks) = new_handler_with(PanicOnceSerializer::default(), 10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.emitted_events, 0);
+    assert_eq!(handler.buffered_len(), 1);
+    assert!(mocks.sqs.deleted_ids().is_empty());
+    assert_eq!(mocks.cache.len(), 0);
+
+    // Emitted before its message is deleted
+    handler.ack_all(None).await;
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+    assert!(mocks.cache.contains(Identity(b"x".to_vec())));
+}
+
+#[tokio::test]
+async fn a_panicking_serializer_leaves_a_dry_run_buffer_intact() {
+    let (handler, _mocks) = new_handler_with(PanicOnceSerializer::default(), 10);
+    let mut handler = handler.with_dry_run(true, false);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(handler.buffered_len(), 1);
+}
+
+fn with_identity(mut completed: OutputEvent<String, String>, identity: &str) -> OutputEvent<String, String> {
+    completed.identities.push(identity.as_bytes().to_vec());
+    completed
+}
+
+#[tokio::test]
+async fn duplicate_acks_every_contributing_message() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_dedup_on_complete(true);
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(message("1"), with_identity(partial("a", "incomplete"), "x"))
+        .await;
+    // Another handler completes the identity in the meantime
+    mocks.cache.insert(Identity(b"x".to_vec()));
+    handler
+        .mark_complete(message("2"), with_identity(total("a"), "x"))
+        .await;
+    handler.ack_all(None).await;
+
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn fail_fast_on_delete_skips_later_chunks() {
+    let (handler, mocks) = new_handler(100);
+    let mut handler = handler.with_fail_fast_on_delete(true);
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_deletes(1);
+
+    for id in 0..11 {
+        handler.ack_message(message(&id.to_string())).await;
+    }
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.sqs.delete_attempts(), 1);
+    assert_eq!(summary.failed_messages.len(), 10);
+    assert_eq!(summary.deleted_messages, 0);
+    // Retained to be deleted by the next flush
+    assert_eq!(handler.completed_messages.len(), 11);
+}
+
+#[tokio::test]
+async fn deletes_continue_past_a_failed_chunk_by_default() {
+    let (mut handler, mocks) = new_handler(100);
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_deletes(1);
+
+    for id in 0..11 {
+        handler.ack_message(message(&id.to_string())).await;
+    }
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.sqs.delete_attempts(), 2);
+    assert_eq!(summary.failed_messages.len(), 10);
+    assert_eq!(summary.deleted_messages, 1);
+    assert!(handler.completed_messages.is_empty());
+}
+
+#[tokio::test]
+async fn divergence_is_flagged_past_the_threshold() {
+    let (handler, _mocks) = new_handler(100);
+    let mut handler = handler.with_divergence_threshold(0.5);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.mark_complete(message("3"), partial("c", "incomplete")).await;
+    handler.mark_complete(message("4"), partial("d", "incomplete")).await;
+    assert!(!handler.diverged());
+
+    handler.mark_complete(message("5"), partial("e", "incomplete")).await;
+    assert!(handler.diverged());
+    assert_eq!(handler.stats().events_without_messages(), 3);
+}
+
+#[tokio::test]
+async fn sends_fail_once_the_router_is_gone() {
+    let (mut handler, _mocks) = new_handler(10);
+    let (handle, mailbox) = SqsCompletionHandlerActor::attach(&mut handler);
+    drop(mailbox);
+
+    assert!(handle.mark_complete(message("1"), total("a")).await.is_err());
+    assert!(handle.ack_message(message("2")).await.is_err());
+    assert!(handle.ack_all(None).await.is_err());
+    assert_eq!(handle.mailbox_len(), 0);
+}
+
+#[tokio::test]
+async fn visibility_changes_are_sent_in_batches_of_ten() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+    let messages: Vec<SqsMessage> = (0..15).map(|id| message(&id.to_string())).collect();
+
+    let failed = handler.change_visibility_batch(&messages, 30).await;
+
+    assert!(failed.is_empty());
+    let requests = mocks.sqs.visibility_requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].entries.len(), 10);
+    assert_eq!(requests[1].entries.len(), 5);
+    assert!(requests
+        .iter()
+        .flat_map(|request| request.entries.iter())
+        .all(|entry| entry.visibility_timeout == Some(30)));
+}
+
+#[tokio::test]
+async fn streaming_emits_before_the_flush_threshold() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_streaming(StreamingConfig::new());
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+
+    assert_eq!(mocks.emitter.batches(), vec![vec![b"a".to_vec()]]);
+    assert!(mocks.sqs.delete_requests().is_empty());
+
+    handler.ack_all(None).await;
+
+    // Deletes are still batched, and nothing is emitted again
+    assert_eq!(mocks.emitter.batches().len(), 1);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn streaming_can_drop_partial_events() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_streaming(StreamingConfig::new().emit_partial(false));
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), partial("a", "incomplete")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["b".to_owned()]);
+}
+
+#[tokio::test]
+async fn emits_carry_the_active_traceparent() {
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let context = Arc::new(Mutex::new(Some(traceparent.to_owned())));
+    let (handler, mocks) = new_handler(10);
+    let active = context.clone();
+    let mut handler = handler.with_trace_context(move || active.lock().unwrap().clone());
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+    *context.lock().unwrap() = None;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.ack_all(None).await;
+
+    let metadata = mocks.emitter.metadata();
+    assert_eq!(metadata[0].traceparent.as_deref(), Some(traceparent));
+    assert_eq!(metadata[1].traceparent, None);
+}
+
+#[tokio::test]
+async fn deletes_are_sent_through_the_sqs_ops_backend() {
+    let (mut handler, mocks) = new_handler(100);
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..12 {
+        handler.ack_message(message(&id.to_string())).await;
+    }
+    handler.ack_all(None).await;
+
+    let requests = mocks.sqs.delete_requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|request| request.queue_url == QUEUE_URL));
+    assert_eq!(requests[0].entries.len(), 10);
+    assert_eq!(requests[0].entries[0].id, "0");
+    assert_eq!(requests[0].entries[0].receipt_handle, "receipt-0");
+    assert_eq!(requests[1].entries.len(), 2);
+    assert_eq!(mocks.sqs.deleted_ids().len(), 12);
+}
+
+#[tokio::test]
+async fn failed_entries_are_reported_to_on_ack() {
+    let (mut handler, mocks) = new_handler(100);
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_message("2");
+
+    handler.ack_message(message("1")).await;
+    handler.ack_message(message("2")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.deleted_messages, 1);
+    assert_eq!(summary.failed_messages, vec!["2".to_owned()]);
+    assert_eq!(mocks.acks(), vec![Ok("1".to_owned()), Err("2".to_owned())]);
+    assert_eq!(handler.stats().delete_failures(), 1);
+}
+
+#[tokio::test]
+async fn healthcheck_reports_a_missing_queue() {
+    let (handler, mocks) = new_handler(10);
+
+    assert_eq!(handler.healthcheck().await, Ok(()));
+    mocks.sqs.set_queue_missing(true);
+    assert_eq!(
+        handler.healthcheck().await,
+        Err(HealthError::QueueNotFound(QUEUE_URL.to_owned()))
+    );
+}
+
+/// Counts the events it serializes, and sizes events without serializing them.
+#[derive(Default)]
+struct SizingSerializer {
+    serialized: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl CompletionEventSerializer for SizingSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[String],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        self.serialized
+            .fetch_add(completed_events.len(), Ordering::SeqCst);
+        StringSerializer.serialize_completed_events(completed_events)
+    }
+
+    fn serialized_size(&mut self, completed_events: &[String]) -> Result<usize, String> {
+        Ok(completed_events.iter().map(|event| event.len()).sum())
+    }
+}
+
+#[tokio::test]
+async fn buffer_size_is_measured_without_serializing() {
+    let serializer = SizingSerializer::default();
+    let serialized = serializer.serialized.clone();
+    let (handler, mocks) = new_handler_with(serializer, 100);
+    let mut handler = handler.with_max_buffer_bytes(5);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("abc")).await;
+    assert!(mocks.emitter.batches().is_empty());
+    handler.mark_complete(message("2"), total("de")).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["abc".to_owned(), "de".to_owned()]);
+    // Only serialized by the flush
+    assert_eq!(serialized.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn is_duplicate_checks_the_cache_and_the_buffer() {
+    let (handler, mocks) = new_handler(10);
+    mocks.cache.insert(Identity(b"seen".to_vec()));
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor
+        .mark_complete(message("1"), with_identity(total("a"), "buffered"))
+        .await
+        .unwrap();
+
+    assert!(actor.is_duplicate(b"seen".to_vec()).await);
+    assert!(actor.is_duplicate(b"buffered".to_vec()).await);
+    assert!(!actor.is_duplicate(b"unseen".to_vec()).await);
+}
+
+#[tokio::test]
+async fn full_mailboxes_shed_load() {
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_mailbox_capacity(1);
+    // Nothing receives from the mailbox, so it stays full
+    let (handle, _mailbox) = SqsCompletionHandlerActor::attach(&mut handler);
+
+    assert_eq!(handle.try_mark_complete(message("1"), total("a")), Ok(()));
+    assert_eq!(
+        handle.try_mark_complete(message("2"), total("b")),
+        Err(MailboxError::Full)
+    );
+
+    let started = Instant::now();
+    let timeout = Duration::from_millis(20);
+    assert_eq!(
+        handle
+            .mark_complete_timeout(message("3"), total("c"), timeout)
+            .await,
+        Err(MailboxError::TimedOut)
+    );
+    assert!(started.elapsed() >= timeout);
+    assert_eq!(handle.mailbox_len(), 1);
+}
+
+#[tokio::test]
+async fn rejected_events_retain_their_messages() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.reject_next(vec![1]);
+
+    handler.mark_complete(message("0"), total("a")).await;
+    handler.mark_complete(message("1"), total("b")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.emitted_events, 1);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["0".to_owned()]);
+    assert_eq!(handler.completed_events, vec!["b".to_owned()]);
+    assert_eq!(handler.completed_messages, vec![message("1")]);
+
+    handler.ack_all(None).await;
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["0".to_owned(), "1".to_owned()]);
+}
+
+#[tokio::test]
+async fn shutdown_gives_up_on_a_hung_emitter() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_shutdown_timeout(Duration::from_millis(50));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.stall_emits(1);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    let started = Instant::now();
+    let summary = handler.shutdown().await;
+
+    assert_eq!(
+        summary,
+        ShutdownSummary::TimedOut {
+            lost_events: 1,
+            lost_messages: 1
+        }
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(mocks.sqs.delete_requests().is_empty());
+}
+
+#[tokio::test]
+async fn shutdown_flushes_through_the_actor() {
+    let (handler, mocks) = new_handler(10);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor.mark_complete(message("1"), total("a")).await.unwrap();
+    assert_eq!(actor.shutdown().await.unwrap(), ShutdownSummary::Flushed);
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn config_sets_the_request_timeout() {
+    let handler = SqsCompletionHandler::new_with_config(
+        crate::sqs_ops::SqsConfig::new(rusoto_core::Region::UsEast1).timeout(Duration::from_secs(1)),
+        QUEUE_URL.to_owned(),
+        StringSerializer,
+        MockEmitter::new(),
+        CompletionPolicy::new(10, Duration::from_secs(60)),
+        |_: SqsCompletionHandlerActor<String, String, rusoto_sqs::SqsClient>, _| {},
+        MockCache::new(),
+    );
+
+    assert_eq!(handler.request_timeout, Duration::from_secs(1));
+    assert_eq!(handler.queue_name, "test-queue");
+}
+
+#[tokio::test]
+async fn completion_variants_are_counted() {
+    let (mut handler, _mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), partial("b", "incomplete")).await;
+    handler.mark_complete(message("3"), error("failed")).await;
+
+    let stats = handler.stats();
+    let counts = CompletionCounts {
+        total: 1,
+        partial: 1,
+        error: 1,
+    };
+    assert_eq!(stats.completion_counts(), counts);
+    assert_eq!(stats.take_completion_counts(), counts);
+    assert_eq!(stats.completion_counts(), CompletionCounts::default());
+}
+
+#[tokio::test]
+async fn oversized_events_are_dead_lettered() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_max_event_bytes(4, OversizedEventPolicy::DeadLetter)
+        .with_dead_letter({
+            let dead_letters = dead_letters.clone();
+            move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+        });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("small")).await;
+    handler.mark_complete(message("2"), total("tiny")).await;
+    handler.ack_all(None).await;
+
+    let dead_letters = dead_letters.lock().unwrap().clone();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].event, "small");
+    assert_eq!(dead_letters[0].message_id, Some("1".to_owned()));
+    assert_eq!(mocks.emitter.events(), vec!["tiny".to_owned()]);
+    // The dead-lettered event's message is still deleted
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn buffered_events_are_recovered_from_the_wal() {
+    let wal = MockWal::new();
+    {
+        let (handler, _mocks) = new_handler(10);
+        let mut handler = handler.with_wal(wal.clone());
+        let _mailbox = attach(&mut handler);
+
+        handler.mark_complete(message("1"), total("a")).await;
+        handler.mark_complete(message("2"), partial("b", "incomplete")).await;
+        // Crashes before flushing
+    }
+    assert_eq!(wal.events(), vec!["a".to_owned(), "b".to_owned()]);
+
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_wal(wal.clone());
+    assert_eq!(handler.recover().unwrap(), 2);
+    let _mailbox = attach(&mut handler);
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+    assert!(wal.events().is_empty());
+}
+
+#[test]
+fn recovered_messages_without_ids_count_as_events_without_messages() {
+    let mut wal = MockWal::new();
+    let mut no_id = message("1");
+    no_id.message_id = None;
+    wal.append(&WalEntry {
+        event: "a".to_owned(),
+        message: Some(no_id),
+    })
+    .unwrap();
+    wal.append(&WalEntry {
+        event: "b".to_owned(),
+        message: Some(message("2")),
+    })
+    .unwrap();
+
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_wal(wal);
+    assert_eq!(handler.recover().unwrap(), 2);
+
+    assert_eq!(handler.completed_event_sources, vec![None, Some("2".to_owned())]);
+    assert_eq!(handler.events_without_messages, 1);
+}
+
+#[tokio::test]
+async fn wal_failures_are_counted() {
+    let wal = MockWal::new();
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_wal(wal.clone());
+    let _mailbox = attach(&mut handler);
+    let stats = handler.stats();
+    wal.fail_writes(2);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(stats.wal_failures(), 2);
+    assert!(wal.events().is_empty());
+}
+
+#[tokio::test]
+async fn the_wal_is_rewritten_with_each_retained_event_and_its_message() {
+    let mut wal = MockWal::new();
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_wal(wal.clone());
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.reject_next(vec![0, 2]);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.mark_complete(message("3"), total("c")).await;
+    handler.ack_all(None).await;
+
+    let entries = wal.replay().unwrap();
+    let recovered: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.event.clone(), entry.message.clone()))
+        .collect();
+    assert_eq!(
+        recovered,
+        vec![
+            ("a".to_owned(), Some(message("1"))),
+            ("c".to_owned(), Some(message("3"))),
+        ]
+    );
+}
+
+#[test]
+fn queue_names_are_parsed_from_urls() {
+    assert_eq!(queue_name_from_url(QUEUE_URL), "test-queue");
+    assert_eq!(queue_name_from_url(&format!("{}/", QUEUE_URL)), "test-queue");
+    assert_eq!(queue_name_from_url("not-a-url"), "not-a-url");
+}
+
+#[tokio::test]
+async fn emits_are_tagged_with_the_source_queue() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    let metadata = mocks.emitter.metadata();
+    assert_eq!(metadata.len(), 1);
+    assert_eq!(metadata[0].source_queue, Some("test-queue".to_owned()));
+}
+
+#[tokio::test]
+async fn retried_emits_shrink_adaptive_batches() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_emit_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    handler.completion_policy = CompletionPolicy::adaptive(
+        AdaptiveBatching::new(2, 16, Duration::from_secs(1)),
+        FlushSchedule::Interval(Duration::from_secs(60)),
+    );
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..3 {
+        mocks.emitter.fail_emits(1);
+        handler.mark_complete(message(&id.to_string()), total(&id.to_string())).await;
+        handler.ack_all(None).await;
+    }
+
+    assert_eq!(handler.completion_policy.max_messages(), 2);
+    assert_eq!(mocks.emitter.events().len(), 3);
+}
+
+#[tokio::test]
+async fn abandoned_buffers_are_dead_lettered() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_dead_letter({
+        let dead_letters = dead_letters.clone();
+        move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), partial("b", "incomplete")).await;
+    handler.abandon_buffer("incident".to_owned()).await;
+
+    let dead_letters = dead_letters.lock().unwrap().clone();
+    let abandoned: Vec<_> = dead_letters
+        .iter()
+        .map(|dead_letter| (dead_letter.event.as_str(), dead_letter.message_id.clone()))
+        .collect();
+    assert_eq!(abandoned, vec![("a", Some("1".to_owned())), ("b", None)]);
+    assert!(dead_letters.iter().all(|dead_letter| dead_letter.reason == "incident"));
+    assert_eq!(mocks.acks(), vec![Err("1".to_owned())]);
+    assert_eq!(handler.buffered_len(), 0);
+
+    handler.ack_all(None).await;
+    assert!(mocks.emitter.batches().is_empty());
+    assert!(mocks.sqs.delete_requests().is_empty());
+}
+
+#[tokio::test]
+async fn cache_stores_are_retried() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_cache_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+    mocks.cache.fail_stores(1);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert!(summary.failed_cache_identities.is_empty());
+    assert!(mocks.cache.contains(Identity(b"x".to_vec())));
+}
+
+#[tokio::test]
+async fn exhausted_cache_stores_are_reported() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_cache_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+    mocks.cache.fail_stores(2);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.failed_cache_identities, vec![b"x".to_vec()]);
+    assert!(!mocks.cache.contains(Identity(b"x".to_vec())));
+    assert!(mocks.cache.contains(Identity(b"y".to_vec())));
+}
+
+#[tokio::test]
+async fn md5_mismatches_are_dead_lettered() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_verify_md5(true).with_dead_letter({
+        let dead_letters = dead_letters.clone();
+        move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+    });
+    let _mailbox = attach(&mut handler);
+
+    let mut corrupted = message("1");
+    corrupted.md5_of_body = Some(format!("{:x}", md5::compute(b"something else")));
+    let mut intact = message("2");
+    intact.md5_of_body = Some(format!("{:x}", md5::compute(b"body-2")));
+    handler.mark_complete(corrupted, total("a")).await;
+    handler.mark_complete(intact, total("b")).await;
+    handler.ack_all(None).await;
+
+    let dead_letters = dead_letters.lock().unwrap().clone();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].event, "a");
+    assert_eq!(mocks.emitter.events(), vec!["b".to_owned()]);
+}
+
+#[tokio::test]
+async fn duplicates_within_a_window_are_coalesced() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_coalesce_duplicates(true);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("3"), with_identity(total("b"), "y")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+    assert_eq!(summary.deleted_messages, 3);
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned(), "3".to_owned()]);
+}
+
+#[tokio::test]
+async fn duplicates_are_not_coalesced_by_default() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("a"), "x")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "a".to_owned()]);
+}
+
+/// Dumps a whole batch as one payload in its debug format.
+struct DebugSerializer;
+
+impl CompletionEventSerializer for DebugSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(&mut self, completed_events: &[String]) -> Result<Vec<Vec<u8>>, String> {
+        Ok(vec![format!("{:?}", completed_events).into_bytes()])
+    }
+}
+
+#[tokio::test]
+async fn the_fallback_serializer_is_used_when_the_primary_fails() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_fallback_serializer(DebugSerializer);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total(UNSERIALIZABLE)).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec![format!("{:?}", vec![UNSERIALIZABLE])]);
+    assert!(mocks.emitter.metadata()[0].degraded);
+    assert_eq!(summary.deleted_messages, 1);
+}
+
+#[tokio::test]
+async fn primary_output_is_not_degraded() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_fallback_serializer(DebugSerializer);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    assert!(!mocks.emitter.metadata()[0].degraded);
+}
+
+#[tokio::test]
+async fn acked_completions_return_the_flush_they_triggered() {
+    let (handler, mocks) = new_handler(3);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    for id in &["1", "2"] {
+        let flushed = actor.mark_complete_ack(message(id), total(id)).await.unwrap();
+        assert!(flushed.is_none());
+    }
+    let flushed = actor
+        .mark_complete_ack(message("3"), total("3"))
+        .await
+        .unwrap()
+        .expect("The third completion should flush");
+
+    assert_eq!(flushed.emitted_events, 3);
+    assert_eq!(flushed.deleted_messages, 3);
+    assert_eq!(mocks.emitter.events().len(), 3);
+}
+
+#[tokio::test]
+async fn delays_are_forwarded_to_the_emitter() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_delay_fn(|event: &String| match event.as_str() {
+        "later" => Some(Duration::from_secs(30)),
+        "latest" => Some(Duration::from_secs(60)),
+        _ => None,
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("now")).await;
+    handler.ack_all(None).await;
+    handler.mark_complete(message("2"), total("later")).await;
+    handler.mark_complete(message("3"), total("latest")).await;
+    handler.ack_all(None).await;
+
+    let delays: Vec<_> = mocks.emitter.metadata().iter().map(|metadata| metadata.delay).collect();
+    // A batch is delayed by its longest delay
+    assert_eq!(delays, vec![None, Some(Duration::from_secs(60))]);
+}
+
+/// Serializes like `StringSerializer`, recording the metadata it is given.
+#[derive(Default)]
+struct MetaSerializer {
+    meta: Arc<Mutex<Vec<EventMeta>>>,
+}
+
+impl CompletionEventSerializer for MetaSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(&mut self, completed_events: &[String]) -> Result<Vec<Vec<u8>>, String> {
+        StringSerializer.serialize_completed_events(completed_events)
+    }
+
+    fn serialize_completed_events_with_meta(
+        &mut self,
+        completed_events: &[String],
+        meta: &[EventMeta],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        self.meta.lock().unwrap().extend_from_slice(meta);
+        self.serialize_completed_events(completed_events)
+    }
+}
+
+#[tokio::test]
+async fn retained_source_bodies_reach_the_serializer() {
+    let serializer = MetaSerializer::default();
+    let meta = serializer.meta.clone();
+    let (handler, _mocks) = new_handler_with(serializer, 10);
+    let mut handler = handler.with_retain_source_body(true);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), partial("b", "incomplete")).await;
+    handler.ack_all(None).await;
+
+    let meta = meta.lock().unwrap().clone();
+    let sources: Vec<_> = meta
+        .iter()
+        .map(|meta| (meta.source_message_id.clone(), meta.source_body.clone()))
+        .collect();
+    assert_eq!(
+        sources,
+        vec![(Some("1".to_owned()), Some("body-1".to_owned())), (None, None)]
+    );
+}
+
+#[tokio::test]
+async fn source_bodies_are_not_retained_by_default() {
+    let serializer = MetaSerializer::default();
+    let meta = serializer.meta.clone();
+    let (mut handler, _mocks) = new_handler_with(serializer, 10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    let meta = meta.lock().unwrap().clone();
+    assert_eq!(meta.len(), 1);
+    assert_eq!(meta[0].source_message_id, Some("1".to_owned()));
+    assert_eq!(meta[0].source_body, None);
+}
+
+#[tokio::test]
+async fn debounced_flushes_are_coalesced() {
+    let (handler, mocks) = new_handler(10);
+    let handler = handler.with_flush_debounce(Duration::from_millis(50));
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    let (first_tx, first_rx) = tokio::sync::oneshot::channel();
+    actor.mark_complete(message("1"), total("a")).await.unwrap();
+    actor.ack_all(Some(first_tx)).await.unwrap();
+    let (second_tx, second_rx) = tokio::sync::oneshot::channel();
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    actor.ack_all(Some(second_tx)).await.unwrap();
+    first_rx.await.unwrap();
+    second_rx.await.unwrap();
+
+    let batches = mocks.emitter.batches();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+}
+
+#[tokio::test]
+async fn full_buffers_skip_the_debounce() {
+    let (handler, mocks) = new_handler(2);
+    let mut handler = handler.with_flush_debounce(Duration::from_secs(60));
+    // Holds off the count-triggered flush, leaving the buffer full
+    handler.completion_policy = CompletionPolicy::new(2, Duration::from_secs(60))
+        .min_interval_between_flushes(Duration::from_secs(60));
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    assert!(mocks.emitter.batches().is_empty());
+    handler.request_flush(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+}
+
+#[tokio::test]
+async fn fallback_identities_are_cached() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_identity_fallback(IdentityFallback::MessageId);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert!(mocks.cache.contains(Identity(b"1".to_vec())));
+    assert_eq!(mocks.cache.len(), 1);
+}
+
+#[tokio::test]
+async fn empty_flushes_emit_heartbeats_when_configured() {
+    let (handler, mocks) = new_handler_with(DebugSerializer, 10);
+    let mut handler = handler.with_emit_empty(true);
+    let _mailbox = attach(&mut handler);
+
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["[]".to_owned()]);
+}
+
+#[tokio::test]
+async fn empty_flushes_emit_nothing_by_default() {
+    let (mut handler, mocks) = new_handler_with(DebugSerializer, 10);
+    let _mailbox = attach(&mut handler);
+
+    handler.ack_all(None).await;
+
+    assert!(mocks.emitter.batches().is_empty());
+}
+
+#[tokio::test]
+async fn deletes_target_the_queue_each_message_came_from() {
+    const OTHER_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/other-queue";
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler
+        .mark_complete_from(OTHER_QUEUE_URL.to_owned(), message("2"), total("b"))
+        .await;
+    handler.mark_complete(message("3"), total("c")).await;
+    handler.ack_all(None).await;
+
+    let mut deletes: Vec<(String, Vec<String>)> = mocks
+        .sqs
+        .delete_requests()
+        .into_iter()
+        .map(|request| {
+            let ids = request.entries.into_iter().map(|entry| entry.id).collect();
+            (request.queue_url, ids)
+        })
+        .collect();
+    deletes.sort();
+    assert_eq!(
+        deletes,
+        vec![
+            (OTHER_QUEUE_URL.to_owned(), vec!["2".to_owned()]),
+            (QUEUE_URL.to_owned(), vec!["1".to_owned(), "3".to_owned()]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rapid_completions_are_batched_under_a_min_interval() {
+    let (mut handler, mocks) = new_handler(1);
+    handler.completion_policy = CompletionPolicy::new(1, Duration::from_secs(60))
+        .min_interval_between_flushes(Duration::from_secs(60));
+    let _mailbox = attach(&mut handler);
+
+    for id in &["1", "2", "3"] {
+        handler.mark_complete(message(id), total(id)).await;
+    }
+    assert!(mocks.emitter.batches().is_empty());
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.batches().len(), 1);
+    assert_eq!(mocks.emitter.events().len(), 3);
+}
+
+#[tokio::test]
+async fn router_exit_reports_the_lost_buffer() {
+    let lost = Arc::new(Mutex::new(None));
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_on_router_exit({
+        let lost = lost.clone();
+        move |events, messages| {
+            let message_ids: Vec<_> = messages
+                .into_iter()
+                .filter_map(|message: rusoto_sqs::Message| message.message_id)
+                .collect();
+            *lost.lock().unwrap() = Some((events, message_ids));
+        }
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), partial("b", "incomplete")).await;
+    drop(handler);
+
+    assert_eq!(
+        lost.lock().unwrap().clone(),
+        Some((vec!["a".to_owned(), "b".to_owned()], vec!["1".to_owned()]))
+    );
+}
+
+#[tokio::test]
+async fn router_exit_reports_an_empty_buffer_after_a_flush() {
+    let lost = Arc::new(Mutex::new(None));
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_on_router_exit({
+        let lost = lost.clone();
+        move |events: Vec<String>, messages: Vec<rusoto_sqs::Message>| {
+            *lost.lock().unwrap() = Some((events.len(), messages.len()))
+        }
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+    drop(handler);
+
+    assert_eq!(*lost.lock().unwrap(), Some((0, 0)));
+}
+
+#[tokio::test]
+async fn updated_policies_apply_to_later_flushes() {
+    let (handler, mocks) = new_handler(10);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor.mark_complete(message("1"), total("a")).await.unwrap();
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    actor
+        .update_policy(CompletionPolicy::new(2, Duration::from_secs(60)))
+        .await
+        .unwrap();
+    assert!(actor.mark_complete_ack(message("3"), total("c")).await.unwrap().is_some());
+    assert!(actor.mark_complete_ack(message("4"), total("d")).await.unwrap().is_none());
+    assert!(actor.mark_complete_ack(message("5"), total("e")).await.unwrap().is_some());
+
+    assert_eq!(mocks.emitter.batches().len(), 2);
+}
+
+#[tokio::test]
+async fn messages_never_completed_time_out() {
+    let timed_out = Arc::new(Mutex::new(vec![]));
+    let (handler, _mocks) = new_handler(10);
+    let handler = handler.with_in_flight_timeout(
+        Duration::from_millis(20),
+        InFlightTimeoutAction::Handle(Box::new({
+            let timed_out = timed_out.clone();
+            move |message: rusoto_sqs::Message| {
+                timed_out.lock().unwrap().extend(message.message_id)
+            }
+        })),
+    );
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor.begin_processing(message("1")).await.unwrap();
+    actor.begin_processing(message("2")).await.unwrap();
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    assert_eq!(*timed_out.lock().unwrap(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn payloads_are_redacted_from_logs() {
+    capture_logs();
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_emit_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.fail_emits(2);
+
+    handler.mark_complete(message("1"), total("s3cr3t")).await;
+    handler.ack_all(None).await;
+
+    let logs = captured_logs();
+    assert!(logs.iter().any(|line| line.contains("<6 bytes>")));
+    assert!(logs.iter().all(|line| !line.contains("s3cr3t")));
+}
+
+#[tokio::test]
+async fn payloads_are_logged_through_the_redactor() {
+    capture_logs();
+    let (handler, _mocks) = new_handler(10);
+    let mut handler =
+        handler.with_redactor(|payload: &Vec<u8>| format!("redacted {} bytes", payload.len()));
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("s3cr3t")).await;
+    handler.ack_all(None).await;
+
+    assert!(captured_logs()
+        .iter()
+        .any(|line| line.contains("redacted 6 bytes")));
+}
+
+#[tokio::test]
+async fn closed_flush_gates_defer_flushes() {
+    let open = Arc::new(AtomicBool::new(false));
+    let (handler, mocks) = new_handler(2);
+    let mut handler = handler.with_flush_gate(
+        {
+            let open = open.clone();
+            move || open.load(Ordering::SeqCst)
+        },
+        10,
+    );
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.mark_complete(message("3"), total("c")).await;
+    assert!(mocks.emitter.batches().is_empty());
+
+    open.store(true, Ordering::SeqCst);
+    handler.mark_complete(message("4"), total("d")).await;
+    assert_eq!(mocks.emitter.events().len(), 4);
+}
+
+#[tokio::test]
+async fn closed_flush_gates_give_way_to_a_full_buffer() {
+    let (handler, mocks) = new_handler(1);
+    let mut handler = handler.with_flush_gate(|| false, 3);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    assert!(mocks.emitter.batches().is_empty());
+    handler.mark_complete(message("3"), total("c")).await;
+
+    assert_eq!(mocks.emitter.events().len(), 3);
+}
+
+#[tokio::test]
+async fn repeatedly_failing_messages_are_dead_lettered() {
+    let poisoned = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_redelivery_limit(2, {
+        let poisoned = poisoned.clone();
+        move |message: rusoto_sqs::Message| poisoned.lock().unwrap().extend(message.message_id)
+    });
+    let _mailbox = attach(&mut handler);
+
+    for _ in 0..2 {
+        handler.mark_complete(message("1"), error("failed")).await;
+        handler.ack_all(None).await;
+        assert!(poisoned.lock().unwrap().is_empty());
+        assert!(mocks.sqs.deleted_ids().is_empty());
+    }
+    handler.mark_complete(message("1"), error("failed")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(*poisoned.lock().unwrap(), vec!["1".to_owned()]);
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+/// Serializes a batch into one newline-delimited payload, event by event if asked.
+#[derive(Default)]
+struct LinesSerializer {
+    serialized_one: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl CompletionEventSerializer for LinesSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(&mut self, completed_events: &[String]) -> Result<Vec<Vec<u8>>, String> {
+        Ok(vec![completed_events.join("\n").into_bytes()])
+    }
+
+    fn serialize_one(&self, completed_event: &String) -> Option<Result<Vec<u8>, String>> {
+        self.serialized_one.fetch_add(1, Ordering::SeqCst);
+        Some(Ok(completed_event.clone().into_bytes()))
+    }
+
+    fn combine(&self, outputs: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        vec![outputs.join(&b'\n')]
+    }
+}
+
+#[tokio::test]
+async fn parallel_serialization_matches_sequential() {
+    let events: Vec<String> = (0..25).map(|i| format!("event-{}", i)).collect();
+
+    let (mut sequential, sequential_mocks) = new_handler_with(LinesSerializer::default(), 100);
+    let _sequential_mailbox = attach(&mut sequential);
+    let serializer = LinesSerializer::default();
+    let serialized_one = serializer.serialized_one.clone();
+    let (parallel, parallel_mocks) = new_handler_with(serializer, 100);
+    let mut parallel = parallel.with_parallel_serialize(4);
+    let _parallel_mailbox = attach(&mut parallel);
+
+    for (id, event) in events.iter().enumerate() {
+        sequential.mark_complete(message(&id.to_string()), total(event)).await;
+        parallel.mark_complete(message(&id.to_string()), total(event)).await;
+    }
+    sequential.ack_all(None).await;
+    parallel.ack_all(None).await;
+
+    assert_eq!(parallel_mocks.emitter.batches(), sequential_mocks.emitter.batches());
+    assert_eq!(parallel_mocks.emitter.events(), vec![events.join("\n")]);
+    // Once per event, plus the probe
+    assert_eq!(serialized_one.load(Ordering::SeqCst), events.len() + 1);
+}
+
+#[tokio::test]
+async fn failed_deletes_can_reset_visibility() {
+    let (handler, mocks) = new_handler(10);
+    let reset_visibility = DeleteFailurePolicy::ResetVisibility(Duration::from_secs(0));
+    let mut handler = handler.with_delete_failure_policy(reset_visibility);
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_message("2");
+
+    for id in &["1", "2", "3"] {
+        handler.mark_complete(message(id), total(id)).await;
+    }
+    handler.ack_all(None).await;
+
+    let reset: Vec<_> = mocks
+        .sqs
+        .visibility_requests()
+        .into_iter()
+        .flat_map(|request| request.entries)
+        .map(|entry| (entry.receipt_handle, entry.visibility_timeout))
+        .collect();
+    assert_eq!(reset, vec![("receipt-2".to_owned(), Some(0))]);
+}
+
+#[tokio::test]
+async fn failed_deletes_wait_out_the_timeout_by_default() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_message("1");
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert!(mocks.sqs.visibility_requests().is_empty());
+}
+
+/// Records every message the handler audits.
+#[derive(Clone, Default)]
+struct RecordingAuditSink {
+    records: Arc<Mutex<Vec<(String, AckOutcome)>>>,
+}
+
+#[async_trait]
+impl AuditSink for RecordingAuditSink {
+    async fn record(&self, message_id: &str, outcome: AckOutcome, _timestamp: DateTime<Utc>) {
+        self.records.lock().unwrap().push((message_id.to_owned(), outcome));
+    }
+}
+
+#[tokio::test]
+async fn every_acked_message_is_audited_once() {
+    let audit_sink = RecordingAuditSink::default();
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_audit_sink(audit_sink.clone());
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.fail_message("2");
+
+    for id in &["1", "2", "3"] {
+        handler.mark_complete(message(id), total(id)).await;
+    }
+    handler.ack_all(None).await;
+
+    let mut records = audit_sink.records.lock().unwrap().clone();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        records,
+        vec![
+            ("1".to_owned(), AckOutcome::Deleted),
+            ("2".to_owned(), AckOutcome::DeleteFailed),
+            ("3".to_owned(), AckOutcome::Deleted),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn identities_are_taken_from_message_attributes() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_identity_from_attribute("business-key");
+    let _mailbox = attach(&mut handler);
+
+    let mut keyed = message("1");
+    let mut attributes = HashMap::new();
+    attributes.insert(
+        "business-key".to_owned(),
+        rusoto_sqs::MessageAttributeValue {
+            data_type: "String".to_owned(),
+            string_value: Some("order-42".to_owned()),
+            ..Default::default()
+        },
+    );
+    keyed.message_attributes = Some(attributes);
+    handler.mark_complete(keyed, with_identity(total("a"), "explicit")).await;
+    handler.ack_all(None).await;
+
+    assert!(mocks.cache.contains(Identity(b"order-42".to_vec())));
+    assert!(mocks.cache.contains(Identity(b"explicit".to_vec())));
+}
+
+/// Sums "key:count" events sharing a key, keeping the order keys were first seen.
+fn sum_by_key(events: Vec<String>) -> Vec<String> {
+    let mut sums: Vec<(String, u64)> = vec![];
+    for event in events {
+        let (key, count) = event.split_at(event.find(':').unwrap());
+        let count: u64 = count[1..].parse().unwrap();
+        match sums.iter_mut().find(|(summed, _)| summed == key) {
+            Some((_, sum)) => *sum += count,
+            None => sums.push((key.to_owned(), count)),
+        }
+    }
+    sums.into_iter().map(|(key, sum)| format!("{}:{}", key, sum)).collect()
+}
+
+#[tokio::test]
+async fn buffers_are_compacted_before_serialization() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_compactor(sum_by_key);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a:1")).await;
+    handler.mark_complete(message("2"), total("b:2")).await;
+    handler.mark_complete(message("3"), total("a:3")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a:4".to_owned(), "b:2".to_owned()]);
+    assert_eq!(summary.deleted_messages, 3);
+}
+
+#[cfg(feature = "prometheus")]
+#[tokio::test]
+async fn flushes_are_exported_to_prometheus() {
+    let metrics = Arc::new(crate::metrics::PrometheusMetrics::new("handler").unwrap());
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_metrics(metrics.clone());
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.ack_all(None).await;
+
+    let families = metrics.gather();
+    let batch_size = families
+        .iter()
+        .find(|family| family.get_name() == "handler_flush_batch_size")
+        .unwrap();
+    assert_eq!(batch_size.get_metric()[0].get_histogram().get_sample_sum(), 2.0);
+}
+
+/// The flush ids of the `[flush N]` tagged lines in `logs`.
+fn flush_ids(logs: &[String]) -> HashSet<String> {
+    logs.iter()
+        .filter(|line| line.starts_with("[flush "))
+        .map(|line| line[..line.find(']').unwrap() + 1].to_owned())
+        .collect()
+}
+
+#[tokio::test]
+async fn flush_log_lines_share_a_flush_id() {
+    let (mut handler, _mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    capture_logs();
+    handler.ack_all(None).await;
+    let first = captured_logs();
+
+    handler.mark_complete(message("2"), total("b")).await;
+    capture_logs();
+    handler.ack_all(None).await;
+    let second = captured_logs();
+
+    assert!(first.iter().filter(|line| line.starts_with("[flush ")).count() >= 2);
+    let first_ids = flush_ids(&first);
+    let second_ids = flush_ids(&second);
+    assert_eq!(first_ids.len(), 1);
+    assert_eq!(second_ids.len(), 1);
+    assert_ne!(first_ids, second_ids);
+}
+
+#[tokio::test]
+async fn flushes_wait_for_a_shared_flush_permit() {
+    let flush_semaphore = Arc::new(tokio::sync::Semaphore::new(1));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_flush_semaphore(flush_semaphore.clone());
+    let mailbox = attach(&mut handler);
+    handler.mark_complete(message("1"), total("a")).await;
+
+    // Another handler sharing the semaphore is mid-flush
+    let permit = flush_semaphore.acquire().await;
+    let flush = tokio::spawn(async move {
+        handler.ack_all(None).await;
+        (handler, mailbox)
+    });
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert!(mocks.emitter.batches().is_empty());
+
+    drop(permit);
+    let _flushed = flush.await.unwrap();
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    assert_eq!(flush_semaphore.available_permits(), 1);
+}
+
+#[tokio::test]
+async fn events_are_emitted_in_groups_by_partition_key() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_partition_key_fn(|event: &String| event[..1].to_owned());
+    let _mailbox = attach(&mut handler);
+
+    for (id, event) in ["a1", "b1", "a2"].iter().enumerate() {
+        handler.mark_complete(message(&id.to_string()), total(event)).await;
+    }
+    let summary = handler.ack_all(None).await;
+
+    let keyed: Vec<_> = mocks
+        .emitter
+        .metadata()
+        .into_iter()
+        .map(|metadata| metadata.partition_key)
+        .zip(mocks.emitter.batches())
+        .collect();
+    assert_eq!(
+        keyed,
+        vec![
+            (Some("a".to_owned()), vec![b"a1".to_vec(), b"a2".to_vec()]),
+            (Some("b".to_owned()), vec![b"b1".to_vec()]),
+        ]
+    );
+    assert_eq!(summary.deleted_messages, 3);
+}
+
+#[tokio::test]
+async fn deletes_wait_out_the_grace_period() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_delete_grace_period(Duration::from_millis(50));
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    assert!(mocks.sqs.deleted_ids().is_empty());
+
+    handler.delete_due().await;
+    assert!(mocks.sqs.deleted_ids().is_empty());
+
+    tokio::time::delay_for(Duration::from_millis(60)).await;
+    handler.delete_due().await;
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn cancelled_deletes_are_never_sent() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_delete_grace_period(Duration::from_millis(50));
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+    assert!(handler.cancel_delete("1"));
+    assert!(!handler.cancel_delete("1"));
+
+    tokio::time::delay_for(Duration::from_millis(60)).await;
+    handler.delete_due().await;
+    assert!(mocks.sqs.deleted_ids().is_empty());
+    assert!(mocks.acks().contains(&Err("1".to_owned())));
+}
+
+#[tokio::test]
+async fn fifo_batches_keep_insertion_order_under_one_group() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_message_group_id_fn(|events: &[String]| {
+        assert!(!events.is_empty());
+        "orders".to_owned()
+    });
+    let _mailbox = attach(&mut handler);
+
+    for (id, event) in ["c", "a", "b"].iter().enumerate() {
+        handler.mark_complete(message(&id.to_string()), total(event)).await;
+    }
+    handler.ack_all(None).await;
+
+    assert_eq!(
+        mocks.emitter.events(),
+        vec!["c".to_owned(), "a".to_owned(), "b".to_owned()]
+    );
+    let group_ids: Vec<_> = mocks
+        .emitter
+        .metadata()
+        .into_iter()
+        .map(|metadata| metadata.message_group_id)
+        .collect();
+    assert_eq!(group_ids, vec![Some("orders".to_owned())]);
+}
+
+/// `message(id)`, first received `ago`.
+fn first_received(id: &str, ago: Duration) -> SqsMessage {
+    let millis = (SystemTime::now() - ago).duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let mut attributes = HashMap::new();
+    attributes.insert("ApproximateFirstReceiveTimestamp".to_owned(), millis.to_string());
+    SqsMessage {
+        attributes: Some(attributes),
+        ..message(id)
+    }
+}
+
+#[tokio::test]
+async fn flushes_report_the_time_since_first_receive() {
+    let (mut handler, _mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(first_received("1", Duration::from_secs(5)), total("a"))
+        .await;
+    handler
+        .mark_complete(first_received("2", Duration::from_secs(2)), total("b"))
+        .await;
+    // Received without the attribute
+    handler.mark_complete(message("3"), total("c")).await;
+    let summary = handler.ack_all(None).await;
+
+    let latency = summary.max_time_since_first_receive.unwrap();
+    assert!(latency >= Duration::from_secs(5), "{:?}", latency);
+    assert!(latency < Duration::from_secs(6), "{:?}", latency);
+}
+
+/// `message(id)`, sent at `millis` since the epoch.
+fn sent_at(id: &str, millis: u64) -> SqsMessage {
+    let mut attributes = HashMap::new();
+    attributes.insert("SentTimestamp".to_owned(), millis.to_string());
+    SqsMessage {
+        attributes: Some(attributes),
+        ..message(id)
+    }
+}
+
+/// The message ids in each delete request, which may have been sent concurrently.
+fn delete_chunks(sqs: &MockSqs) -> Vec<Vec<String>> {
+    sqs.delete_requests()
+        .iter()
+        .map(|request| request.entries.iter().map(|entry| entry.id.clone()).collect())
+        .collect()
+}
+
+#[tokio::test]
+async fn deletes_follow_the_delete_priority() {
+    let sent = |msg: &SqsMessage| -> u64 {
+        msg.attributes.as_ref().unwrap()["SentTimestamp"].parse().unwrap()
+    };
+    let (handler, mocks) = new_handler(20);
+    let mut handler = handler.with_delete_priority(move |a, b| sent(a).cmp(&sent(b)));
+    let _mailbox = attach(&mut handler);
+
+    // Buffered newest first, so the oldest would be in the last chunk by insertion order
+    for id in 0..12u64 {
+        let msg = sent_at(&id.to_string(), 1_000 - id);
+        handler.mark_complete(msg, total(&id.to_string())).await;
+    }
+    handler.ack_all(None).await;
+
+    let chunks = delete_chunks(&mocks.sqs);
+    assert_eq!(chunks.len(), 2);
+    let expected: Vec<String> = (2..12).rev().map(|id| id.to_string()).collect();
+    assert!(chunks.contains(&expected));
+    assert!(chunks.contains(&vec!["1".to_owned(), "0".to_owned()]));
+}
+
+#[tokio::test]
+async fn deletes_keep_insertion_order_by_default() {
+    let (mut handler, mocks) = new_handler(20);
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..12u64 {
+        let msg = sent_at(&id.to_string(), 1_000 - id);
+        handler.mark_complete(msg, total(&id.to_string())).await;
+    }
+    handler.ack_all(None).await;
+
+    let chunks = delete_chunks(&mocks.sqs);
+    let expected: Vec<String> = (0..10).map(|id| id.to_string()).collect();
+    assert!(chunks.contains(&expected));
+    assert!(chunks.contains(&vec!["10".to_owned(), "11".to_owned()]));
+}
+
+#[tokio::test]
+async fn retried_batches_keep_their_idempotency_token() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_idempotency_tokens(true)
+        .with_emit_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+
+    mocks.emitter.fail_emits(1);
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.ack_all(None).await;
+
+    let tokens: Vec<_> = mocks
+        .emitter
+        .attempted_metadata()
+        .into_iter()
+        .map(|metadata| metadata.idempotency_token.unwrap())
+        .collect();
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[0], tokens[1]);
+    assert_ne!(tokens[1], tokens[2]);
+}
+
+#[test]
+fn idempotency_tokens_depend_on_payload_boundaries() {
+    assert_eq!(idempotency_token(&[b"ab"]), idempotency_token(&[b"ab"]));
+    assert_ne!(
+        idempotency_token(&[b"ab".to_vec()]),
+        idempotency_token(&[b"a".to_vec(), b"b".to_vec()])
+    );
+}
+
+#[tokio::test]
+async fn cache_failures_retain_messages_when_configured() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_cache_retry(RetryConfig::new(2, Duration::from_millis(1)))
+        .with_cache_failure_policy(CacheFailurePolicy::RetainMessages);
+    let _mailbox = attach(&mut handler);
+    mocks.cache.fail_stores(2);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    handler.ack_all(None).await;
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["2".to_owned()]);
+
+    // Deleted once its identity is cached
+    handler.ack_all(None).await;
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["2".to_owned(), "1".to_owned()]);
+    assert!(mocks.cache.contains(Identity(b"x".to_vec())));
+}
+
+#[tokio::test]
+async fn cache_failures_do_not_hold_up_deletes_by_default() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_cache_retry(RetryConfig::new(2, Duration::from_millis(1)));
+    let _mailbox = attach(&mut handler);
+    mocks.cache.fail_stores(2);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    handler.ack_all(None).await;
+
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn pending_identities_are_queryable_through_the_actor() {
+    let (handler, _mocks) = new_handler(10);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor
+        .mark_complete(message("1"), with_identity(total("a"), "x"))
+        .await
+        .unwrap();
+    actor
+        .mark_complete(message("2"), with_identity(total("b"), "y"))
+        .await
+        .unwrap();
+    assert_eq!(
+        actor.pending_identities().await.unwrap(),
+        vec![b"x".to_vec(), b"y".to_vec()]
+    );
+    assert!(actor.recently_cached().await.unwrap().is_empty());
+
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    actor.ack_all(Some(tx)).await.unwrap();
+    rx.await.unwrap();
+    assert!(actor.pending_identities().await.unwrap().is_empty());
+    assert_eq!(
+        actor.recently_cached().await.unwrap(),
+        vec![b"x".to_vec(), b"y".to_vec()]
+    );
+}
+
+#[tokio::test]
+async fn events_are_serialized_by_their_selected_serializer() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_serializer("debug", DebugSerializer)
+        .with_serializer_selector(|event: &String| {
+            if event.starts_with("debug-") {
+                "debug".to_owned()
+            } else {
+                "unregistered".to_owned()
+            }
+        });
+    let _mailbox = attach(&mut handler);
+
+    for (id, event) in ["plain-1", "debug-1", "plain-2", "debug-2"].iter().enumerate() {
+        handler.mark_complete(message(&id.to_string()), total(event)).await;
+    }
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(
+        mocks.emitter.batches(),
+        vec![
+            vec![b"plain-1".to_vec(), b"plain-2".to_vec()],
+            vec![format!("{:?}", vec!["debug-1", "debug-2"]).into_bytes()],
+        ]
+    );
+    assert_eq!(summary.deleted_messages, 4);
+}
+
+#[tokio::test]
+async fn visibility_is_extended_to_the_observed_percentile() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_adaptive_visibility(
+        AdaptiveVisibility::new(50.0, Duration::from_secs(300)).min_samples(3),
+    );
+    let _mailbox = attach(&mut handler);
+
+    // Processing times are taken from the first receive, as nothing began processing
+    for (id, secs) in [("1", 10), ("2", 40), ("3", 20)].iter() {
+        let msg = first_received(id, Duration::from_secs(*secs));
+        handler.mark_complete(msg, total(id)).await;
+    }
+    handler.extend_visibility().await;
+
+    let extended: Vec<_> = mocks
+        .sqs
+        .visibility_requests()
+        .into_iter()
+        .flat_map(|request| request.entries)
+        .map(|entry| entry.visibility_timeout)
+        .collect();
+    assert_eq!(extended, vec![Some(20); 3]);
+}
+
+#[tokio::test]
+async fn paused_shutdowns_drain_the_mailbox() {
+    let (handler, mocks) = new_handler(10);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    // Nothing is routed until the test yields, so all of these are queued together
+    for (id, event) in ["a", "b", "c"].iter().enumerate() {
+        actor.mark_complete(message(&id.to_string()), total(event)).await.unwrap();
+    }
+    actor.pause().await.unwrap();
+    assert_eq!(actor.shutdown().await.unwrap(), ShutdownSummary::Flushed);
+
+    assert_eq!(
+        mocks.emitter.events(),
+        vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+    );
+    assert_eq!(mocks.sqs.deleted_ids().len(), 3);
+}
+
+#[tokio::test]
+async fn only_unserializable_events_are_dead_lettered() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_isolate_serialization_failures(true)
+        .with_dead_letter({
+            let dead_letters = dead_letters.clone();
+            move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+        });
+    let _mailbox = attach(&mut handler);
+
+    for (id, event) in ["a", UNSERIALIZABLE, "b"].iter().enumerate() {
+        handler.mark_complete(message(&id.to_string()), total(event)).await;
+    }
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+    let dead_letters = dead_letters.lock().unwrap();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].event, UNSERIALIZABLE);
+    assert_eq!(dead_letters[0].message_id, Some("1".to_owned()));
+    assert_eq!(summary.deleted_messages, 3);
+}
+
+#[test]
+fn bisecting_finds_every_failing_event() {
+    let events: Vec<String> = ["a", UNSERIALIZABLE, "b", "c", UNSERIALIZABLE]
+        .iter()
+        .map(|event| event.to_string())
+        .collect();
+    let meta = vec![EventMeta::default(); events.len()];
+
+    assert_eq!(bisect_failures(&mut StringSerializer, &events, &meta), vec![1, 4]);
+}
+
+#[tokio::test]
+async fn each_flush_emits_a_stats_record() {
+    let stats_emitter = MockEmitter::new();
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_stats_emitter(stats_emitter.clone());
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("bc")).await;
+    handler.ack_all(None).await;
+    handler.mark_complete(message("3"), total("d")).await;
+    handler.ack_all(None).await;
+
+    let records: Vec<serde_json::Value> = stats_emitter
+        .batches()
+        .into_iter()
+        .map(|batch| {
+            assert_eq!(batch.len(), 1);
+            serde_json::from_slice(&batch[0]).unwrap()
+        })
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["emitted_events"], 2);
+    assert_eq!(records[0]["emitted_bytes"], 3);
+    assert_eq!(records[0]["deleted_messages"], 2);
+    assert_eq!(records[1]["emitted_events"], 1);
+    assert_ne!(records[0]["flush_id"], records[1]["flush_id"]);
+}
+
+#[tokio::test]
+async fn old_messages_are_dead_lettered_before_emit() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_max_message_age(Duration::from_secs(60 * 60))
+        .with_dead_letter({
+            let dead_letters = dead_letters.clone();
+            move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+        });
+    let _mailbox = attach(&mut handler);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let two_hours_ago = (now - Duration::from_secs(2 * 60 * 60)).as_millis() as u64;
+    handler.mark_complete(sent_at("1", two_hours_ago), total("a")).await;
+    handler
+        .mark_complete(sent_at("2", now.as_millis() as u64), total("b"))
+        .await;
+
+    // Dead-lettered on completion, before any flush
+    {
+        let dead_letters = dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].event, "a");
+        assert_eq!(dead_letters[0].message_id, Some("1".to_owned()));
+    }
+    assert!(mocks.emitter.batches().is_empty());
+
+    handler.ack_all(None).await;
+    assert_eq!(mocks.emitter.events(), vec!["b".to_owned()]);
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn deletes_use_transformed_receipt_handles() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_receipt_handle_transform(|receipt_handle: &str| {
+        receipt_handle.trim_start_matches("receipt-").to_owned()
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    let receipt_handles: Vec<_> = mocks
+        .sqs
+        .delete_requests()
+        .into_iter()
+        .flat_map(|request| request.entries)
+        .map(|entry| entry.receipt_handle)
+        .collect();
+    assert_eq!(receipt_handles, vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn buffered_bytes_accumulate_until_a_flush() {
+    let (handler, mocks) = new_handler_with(SizingSerializer::default(), 100);
+    let mut handler = handler.with_max_buffer_bytes(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("abc")).await;
+    handler.mark_complete(message("2"), total("de")).await;
+    assert_eq!(handler.buffered_bytes, 5);
+    handler.mark_complete(message("3"), total("fghi")).await;
+    assert_eq!(handler.buffered_bytes, 9);
+    assert!(mocks.emitter.batches().is_empty());
+
+    // Reaches the threshold exactly
+    handler.mark_complete(message("4"), total("j")).await;
+    assert_eq!(mocks.emitter.batches().len(), 1);
+    assert_eq!(handler.buffered_bytes, 0);
+
+    handler.mark_complete(message("5"), total("kl")).await;
+    assert_eq!(handler.buffered_bytes, 2);
+}
+
+#[tokio::test]
+async fn dry_runs_serialize_without_emitting_or_deleting() {
+    let serializer = SizingSerializer::default();
+    let serialized = serializer.serialized.clone();
+    let (handler, mocks) = new_handler_with(serializer, 10);
+    let mut handler = handler.with_dry_run(true, false);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(serialized.load(Ordering::SeqCst), 2);
+    assert_eq!(mocks.emitter.attempts(), 0);
+    assert!(mocks.sqs.delete_requests().is_empty());
+    assert_eq!(mocks.cache.len(), 0);
+    // Kept, as the buffer isn't cleared by default
+    assert_eq!(handler.buffered_len(), 2);
+}
+
+#[tokio::test]
+async fn dry_runs_can_clear_the_buffer() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_dry_run(true, true);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.attempts(), 0);
+    assert!(mocks.sqs.delete_requests().is_empty());
+    assert_eq!(handler.buffered_len(), 0);
+}
+
+#[tokio::test]
+async fn delete_chunks_are_spread_across_clients() {
+    let extra_sqs = MockSqs::new();
+    let (handler, mocks) = new_handler(40);
+    let mut handler = handler.with_delete_clients(vec![extra_sqs.clone()]);
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..30 {
+        handler.mark_complete(message(&id.to_string()), total("a")).await;
+    }
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.sqs.delete_requests().len(), 2);
+    assert_eq!(extra_sqs.delete_requests().len(), 1);
+    let mut deleted: Vec<String> = mocks.sqs.deleted_ids();
+    deleted.extend(extra_sqs.deleted_ids());
+    assert_eq!(deleted.len(), 30);
+}
+
+#[tokio::test]
+async fn deletes_use_a_single_client_by_default() {
+    let (mut handler, mocks) = new_handler(40);
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..30 {
+        handler.mark_complete(message(&id.to_string()), total("a")).await;
+    }
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.sqs.delete_requests().len(), 3);
+}
+
+/// `message(id)`, whose result is useless after `deadline`.
+fn due_by(id: &str, deadline: SystemTime) -> SqsMessage {
+    let millis = deadline.duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let mut attributes = HashMap::new();
+    attributes.insert(
+        "deadline".to_owned(),
+        rusoto_sqs::MessageAttributeValue {
+            data_type: "Number".to_owned(),
+            string_value: Some(millis.to_string()),
+            ..Default::default()
+        },
+    );
+    SqsMessage {
+        message_attributes: Some(attributes),
+        ..message(id)
+    }
+}
+
+#[tokio::test]
+async fn events_past_their_deadline_are_dropped_but_acked() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_deadline_attribute("deadline");
+    let _mailbox = attach(&mut handler);
+    let stats = handler.stats();
+
+    let now = SystemTime::now();
+    handler
+        .mark_complete(due_by("1", now - Duration::from_secs(1)), total("stale"))
+        .await;
+    handler
+        .mark_complete(due_by("2", now + Duration::from_secs(60 * 60)), total("fresh"))
+        .await;
+    // Without a deadline
+    handler.mark_complete(message("3"), total("timeless")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(
+        mocks.emitter.events(),
+        vec!["fresh".to_owned(), "timeless".to_owned()]
+    );
+    assert_eq!(stats.events_expired(), 1);
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned(), "3".to_owned()]);
+}
+
+#[tokio::test]
+async fn swapped_on_ack_callbacks_receive_later_acks() {
+    let (handler, mocks) = new_handler(1);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+    let swapped_acks = Arc::new(Mutex::new(vec![]));
+
+    actor.mark_complete(message("1"), total("a")).await.unwrap();
+    actor
+        .update_on_ack({
+            let swapped_acks = swapped_acks.clone();
+            move |_, ack| swapped_acks.lock().unwrap().push(ack)
+        })
+        .await
+        .unwrap();
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    actor.ack_all(Some(tx)).await.unwrap();
+    rx.await.unwrap();
+
+    assert_eq!(mocks.acks(), vec![Ok("1".to_owned())]);
+    assert_eq!(*swapped_acks.lock().unwrap(), vec![Ok("2".to_owned())]);
+}
+
+#[tokio::test]
+async fn throttled_deletes_are_resent_after_a_delay() {
+    let (handler, mocks) = new_handler(50);
+    let mut handler = handler.with_delete_throttle(
+        DeleteThrottle::new(4).delays(Duration::from_millis(20), Duration::from_millis(100)),
+    );
+    let _mailbox = attach(&mut handler);
+    for id in 0..40 {
+        handler.mark_complete(message(&id.to_string()), total("a")).await;
+    }
+
+    // The first three of the four concurrent requests are throttled
+    mocks.sqs.fail_deletes_with(3, "Throttling");
+    let started = Instant::now();
+    let summary = handler.ack_all(None).await;
+
+    assert!(started.elapsed() >= Duration::from_millis(20));
+    assert_eq!(mocks.sqs.delete_attempts(), 7);
+    assert_eq!(summary.deleted_messages, 40);
+    assert!(summary.failed_messages.is_empty());
+}
+
+#[tokio::test]
+async fn the_schema_version_is_forwarded_to_emitter_and_serializer() {
+    let serializer = MetaSerializer::default();
+    let meta = serializer.meta.clone();
+    let (handler, mocks) = new_handler_with(serializer, 10);
+    let mut handler = handler.with_schema_version(3);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.metadata()[0].schema_version, Some(3));
+    assert_eq!(meta.lock().unwrap()[0].schema_version, Some(3));
+}
+
+#[tokio::test]
+async fn batches_are_unversioned_by_default() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.metadata()[0].schema_version, None);
+}
+
+#[tokio::test]
+async fn on_first_buffered_fires_once_per_empty_buffer() {
+    let first_buffered = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let (handler, _mocks) = new_handler(10);
+    let mut handler = handler.with_on_first_buffered({
+        let first_buffered = first_buffered.clone();
+        move || {
+            first_buffered.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    assert_eq!(first_buffered.load(Ordering::SeqCst), 1);
+
+    handler.ack_all(None).await;
+    assert_eq!(first_buffered.load(Ordering::SeqCst), 1);
+    handler.mark_complete(message("3"), total("c")).await;
+    handler.mark_complete(message("4"), total("d")).await;
+    assert_eq!(first_buffered.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn side_outputs_go_to_the_side_emitter() {
+    let side_emitter = MockEmitter::new();
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_side_emitter(side_emitter.clone());
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(message("1"), total("a").with_side_output("a-side"))
+        .await;
+    handler.mark_complete(message("2"), total("b")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned(), "b".to_owned()]);
+    assert_eq!(side_emitter.events(), vec!["a-side".to_owned()]);
+}
+
+#[tokio::test]
+async fn failed_side_outputs_do_not_hold_up_deletes() {
+    let side_emitter = MockEmitter::new();
+    side_emitter.fail_emits(1);
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_side_emitter(side_emitter.clone());
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(message("1"), total("a").with_side_output("a-side"))
+        .await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(side_emitter.attempts(), 1);
+    assert!(side_emitter.batches().is_empty());
+    assert_eq!(summary.deleted_messages, 1);
+}
+
+#[tokio::test]
+async fn ack_deadlines_report_the_phase_they_cut_short() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_ack_deadline(Duration::from_millis(50));
+    let _mailbox = attach(&mut handler);
+    mocks.emitter.stall_emits(1);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    let started = Instant::now();
+    let summary = handler.ack_all(None).await;
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(summary.timed_out, Some(AckPhase::Emit));
+    assert_eq!(summary.deleted_messages, 0);
+    assert!(mocks.sqs.delete_requests().is_empty());
+    // Retained for the next flush
+    assert_eq!(handler.buffered_len(), 1);
+    let summary = handler.ack_all(None).await;
+    assert_eq!(summary.timed_out, None);
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+}
+
+#[tokio::test]
+async fn events_are_spread_across_shards_by_key() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_shards(3, |event: &String| event.parse().unwrap());
+    let _mailbox = attach(&mut handler);
+
+    for id in 0..6 {
+        let event = id.to_string();
+        handler.mark_complete(message(&event), total(&event)).await;
+    }
+    handler.ack_all(None).await;
+
+    let sharded: Vec<_> = mocks
+        .emitter
+        .metadata()
+        .into_iter()
+        .map(|metadata| metadata.shard_id)
+        .zip(mocks.emitter.batches())
+        .collect();
+    assert_eq!(
+        sharded,
+        vec![
+            (Some(0), vec![b"0".to_vec(), b"3".to_vec()]),
+            (Some(1), vec![b"1".to_vec(), b"4".to_vec()]),
+            (Some(2), vec![b"2".to_vec(), b"5".to_vec()]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn empty_shards_are_not_emitted_to() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_shards(3, |event: &String| event.parse().unwrap());
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("1")).await;
+    handler.mark_complete(message("4"), total("4")).await;
+    handler.ack_all(None).await;
+
+    assert_eq!(mocks.emitter.batches().len(), 1);
+    assert_eq!(mocks.emitter.metadata()[0].shard_id, Some(1));
+}
+
+#[tokio::test]
+async fn totals_supersede_buffered_partials() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(message("1"), with_identity(partial("a-partial", "incomplete"), "x"))
+        .await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    // The retry of message 1 succeeds
+    handler
+        .mark_complete(message("1"), with_identity(total("a-total"), "x"))
+        .await;
+    handler.ack_all(None).await;
+
+    assert_eq!(
+        mocks.emitter.events(),
+        vec!["a-total".to_owned(), "b".to_owned()]
+    );
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn totals_do_not_supersede_flushed_partials() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler
+        .mark_complete(message("1"), with_identity(partial("a-partial", "incomplete"), "x"))
+        .await;
+    handler.ack_all(None).await;
+    handler
+        .mark_complete(message("1"), with_identity(total("a-total"), "x"))
+        .await;
+    handler.ack_all(None).await;
+
+    assert_eq!(
+        mocks.emitter.events(),
+        vec!["a-partial".to_owned(), "a-total".to_owned()]
+    );
+}
+
+#[tokio::test]
+async fn idle_handlers_emit_heartbeats() {
+    let (handler, mocks) = new_handler(10);
+    let handler =
+        handler.with_idle_heartbeat(Duration::from_millis(100), || b"heartbeat".to_vec());
+    let (_actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert!(mocks.emitter.batches().is_empty());
+
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(mocks.emitter.events(), vec!["heartbeat".to_owned()]);
+    // The timer is reset by the heartbeat
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert_eq!(mocks.emitter.events().len(), 2);
+    assert!(mocks.sqs.delete_requests().is_empty());
+}
+
+/// Flushes a handler per semaphore concurrently, all deleting through one client
+/// that takes a while to answer, returning the client.
+async fn flush_concurrently(sqs_semaphores: Vec<Option<Arc<tokio::sync::Semaphore>>>) -> MockSqs {
+    let sqs = MockSqs::new();
+    sqs.slow_deletes(Duration::from_millis(20));
+    let mut flushes = vec![];
+    for (index, sqs_semaphore) in sqs_semaphores.into_iter().enumerate() {
+        let mut handler = SqsCompletionHandler::new(
+            sqs.clone(),
+            QUEUE_URL.to_owned(),
+            StringSerializer,
+            MockEmitter::new(),
+            CompletionPolicy::new(10, Duration::from_secs(60)),
+            |_, _| {},
+            MockCache::new(),
+        );
+        if let Some(sqs_semaphore) = sqs_semaphore {
+            handler = handler.with_sqs_semaphore(sqs_semaphore);
+        }
+        let mailbox = attach(&mut handler);
+        handler.ack_message(message(&index.to_string())).await;
+        flushes.push(async move {
+            let _mailbox = mailbox;
+            handler.ack_all(None).await
+        });
+    }
+    futures::future::join_all(flushes).await;
+    sqs
+}
+
+#[tokio::test]
+async fn handlers_sharing_an_sqs_semaphore_take_turns() {
+    let sqs_semaphore = Arc::new(tokio::sync::Semaphore::new(1));
+    let sqs = flush_concurrently(vec![Some(sqs_semaphore.clone()), Some(sqs_semaphore)]).await;
+
+    assert_eq!(sqs.delete_attempts(), 2);
+    assert_eq!(sqs.max_deletes_in_flight(), 1);
+}
+
+#[tokio::test]
+async fn handlers_without_an_sqs_semaphore_overlap() {
+    let sqs = flush_concurrently(vec![None, None]).await;
+
+    assert_eq!(sqs.delete_attempts(), 2);
+    assert_eq!(sqs.max_deletes_in_flight(), 2);
+}
+
+#[tokio::test]
+async fn dedup_only_handlers_cache_and_delete_without_emitting() {
+    let (sqs, cache) = (MockSqs::new(), MockCache::new());
+    let mut handler = SqsCompletionHandler::new_without_emitter(
+        sqs.clone(),
+        QUEUE_URL.to_owned(),
+        CompletionPolicy::new(10, Duration::from_secs(60)),
+        |_, _| {},
+        cache.clone(),
+    );
+    let _mailbox = SqsCompletionHandlerActor::attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(summary.emitted_events, 0);
+    assert!(cache.contains(Identity(b"x".to_vec())));
+    assert!(cache.contains(Identity(b"y".to_vec())));
+    let mut deleted = sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+#[tokio::test]
+async fn marks_wait_for_pending_deletes_over_the_limit() {
+    let (handler, mocks) = new_handler(1);
+    let handler = handler
+        .with_delete_grace_period(Duration::from_millis(100))
+        .with_max_pending_delete_batches(0);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    // Flushed straight away, its delete held for the grace period
+    actor.mark_complete_ack(message("1"), total("a")).await.unwrap();
+    assert_eq!(
+        actor.try_mark_complete(message("2"), total("b")),
+        Err(MailboxError::Full)
+    );
+    let blocked = tokio::time::timeout(
+        Duration::from_millis(30),
+        actor.mark_complete(message("2"), total("b")),
+    )
+    .await;
+    assert!(blocked.is_err());
+    assert!(mocks.sqs.deleted_ids().is_empty());
+
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn marks_wait_for_slow_deletes_without_a_grace_period() {
+    let (handler, mocks) = new_handler(1);
+    let handler = handler.with_max_pending_delete_batches(0);
+    mocks.sqs.slow_deletes(Duration::from_millis(100));
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    // Flushed straight away, deleting it takes a while
+    actor.mark_complete(message("1"), total("a")).await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(20)).await;
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    let blocked = tokio::time::timeout(
+        Duration::from_millis(30),
+        actor.mark_complete(message("2"), total("b")),
+    )
+    .await;
+    assert!(blocked.is_err());
+    assert!(mocks.sqs.deleted_ids().is_empty());
+
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+}
+
+#[tokio::test]
+async fn retained_deletes_count_as_pending() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_max_pending_delete_batches(0)
+        .with_ack_deadline(Duration::from_millis(10));
+    let _mailbox = attach(&mut handler);
+    mocks.sqs.stall_deletes(1);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    let summary = handler.ack_all(None).await;
+    assert_eq!(summary.timed_out, Some(AckPhase::Delete));
+    assert_eq!(handler.pending_delete_batches(), 1);
+
+    handler.ack_all(None).await;
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["1".to_owned()]);
+    assert_eq!(handler.pending_delete_batches(), 0);
+}
+
+#[tokio::test]
+async fn invalid_messages_are_dead_lettered_and_not_buffered() {
+    let dead_letters = Arc::new(Mutex::new(vec![]));
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler
+        .with_validator(|msg: &SqsMessage| match msg.body.as_deref() {
+            Some("body-2") => Err(ValidationError("malformed".to_owned())),
+            _ => Ok(()),
+        })
+        .with_dead_letter({
+            let dead_letters = dead_letters.clone();
+            move |dead_letter: DeadLetter<String>| dead_letters.lock().unwrap().push(dead_letter)
+        });
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    assert_eq!(handler.buffered_len(), 1);
+    {
+        let dead_letters = dead_letters.lock().unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].event, "b");
+        assert!(dead_letters[0].reason.contains("malformed"));
+    }
+
+    handler.ack_all(None).await;
+    assert_eq!(mocks.emitter.events(), vec!["a".to_owned()]);
+    let mut deleted = mocks.sqs.deleted_ids();
+    deleted.sort();
+    assert_eq!(deleted, vec!["1".to_owned(), "2".to_owned()]);
+}
+
+/// Flushes "a", rejected downstream twice, with "b" buffered after the first
+/// rejection, returning each emitted batch.
+async fn flush_behind_a_stuck_batch(strict_ordering: bool) -> Vec<Vec<Vec<u8>>> {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_strict_ordering(strict_ordering);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), total("a")).await;
+    mocks.emitter.reject_next(vec![0]);
+    handler.ack_all(None).await;
+    handler.mark_complete(message("2"), total("b")).await;
+    mocks.emitter.reject_next(vec![0]);
+    handler.ack_all(None).await;
+    handler.ack_all(None).await;
+    handler.ack_all(None).await;
+
+    mocks.emitter.batches()
+}
+
+#[tokio::test]
+async fn strict_ordering_holds_newer_events_behind_a_stuck_batch() {
+    assert_eq!(
+        flush_behind_a_stuck_batch(true).await,
+        vec![
+            vec![b"a".to_vec()],
+            vec![b"a".to_vec()],
+            vec![b"a".to_vec()],
+            vec![b"b".to_vec()],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn newer_events_overtake_a_stuck_batch_by_default() {
+    assert_eq!(
+        flush_behind_a_stuck_batch(false).await,
+        vec![
+            vec![b"a".to_vec()],
+            vec![b"a".to_vec(), b"b".to_vec()],
+            vec![b"a".to_vec()],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn snapshots_dump_the_buffer_and_policy() {
+    let (handler, _mocks) = new_handler(10);
+    let (actor, _router) = SqsCompletionHandlerActor::new(handler);
+
+    actor
+        .mark_complete(message("1"), with_identity(total("a"), "x"))
+        .await
+        .unwrap();
+    actor.mark_complete(message("2"), total("b")).await.unwrap();
+    let snapshot = actor.snapshot().await.unwrap();
+
+    assert_eq!(snapshot.events, vec!["a".to_owned(), "b".to_owned()]);
+    let message_ids: Vec<_> = snapshot
+        .messages
+        .iter()
+        .map(|msg| msg.message_id.clone().unwrap())
+        .collect();
+    assert_eq!(message_ids, vec!["1".to_owned(), "2".to_owned()]);
+    assert_eq!(snapshot.messages[0].receipt_handle, Some("receipt-1".to_owned()));
+    assert_eq!(snapshot.identities, vec![b"x".to_vec()]);
+    assert_eq!(snapshot.policy.max_messages, 10);
+    assert_eq!(snapshot.flush_count, 0);
+    assert_eq!(snapshot.completion_counts.total, 2);
+
+    let json: serde_json::Value = serde_json::from_slice(&snapshot.to_json().unwrap()).unwrap();
+    assert_eq!(json["events"], serde_json::json!(["a", "b"]));
+    assert_eq!(json["identities"], serde_json::json!([hex::encode(b"x")]));
+    assert_eq!(json["policy"]["max_messages"], 10);
+}
+
+#[tokio::test]
+async fn successful_emits_skip_caching_when_configured() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_skip_cache_on_emit_success(true);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    let summary = handler.ack_all(None).await;
+
+    assert_eq!(mocks.cache.len(), 0);
+    assert_eq!(summary.deleted_messages, 2);
+    assert!(handler.pending_identities().is_empty());
+}
+
+#[tokio::test]
+async fn rejected_emits_are_still_cached_when_skipping() {
+    let (handler, mocks) = new_handler(10);
+    let mut handler = handler.with_skip_cache_on_emit_success(true);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    mocks.emitter.reject_next(vec![0]);
+    handler.ack_all(None).await;
+
+    assert!(mocks.cache.contains(Identity(b"y".to_vec())));
+    assert!(!mocks.cache.contains(Identity(b"x".to_vec())));
+}
+
+#[tokio::test]
+async fn rejected_events_are_cached_once_emitted() {
+    let (mut handler, mocks) = new_handler(10);
+    let _mailbox = attach(&mut handler);
+
+    handler.mark_complete(message("1"), with_identity(total("a"), "x")).await;
+    handler.mark_complete(message("2"), with_identity(total("b"), "y")).await;
+    mocks.emitter.reject_next(vec![0]);
+    handler.ack_all(None).await;
+
+    assert!(!mocks.cache.contains(Identity(b"x".to_vec())));
+    assert!(mocks.cache.contains(Identity(b"y".to_vec())));
+    assert_eq!(handler.pending_identities(), vec![b"x".to_vec()]);
+
+    handler.ack_all(None).await;
+    assert!(mocks.cache.contains(Identity(b"x".to_vec())));
+    assert!(handler.pending_identities().is_empty());
+    assert_eq!(mocks.sqs.deleted_ids(), vec!["2".to_owned(), "1".to_owned()]);
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use log::*;
+use rusoto_sqs::{MessageAttributeValue, SendMessageBatchRequest, SendMessageBatchRequestEntry, Sqs};
+
+use crate::error::Error;
+use crate::event_emitter::{EmitMetadata, EmitReceipt, EventEmitter};
+
+/// SQS accepts at most 10 entries per SendMessageBatch
+const MAX_BATCH_ENTRIES: usize = 10;
+/// SQS rejects messages, and whole batches, larger than 256KB
+const MAX_BATCH_BYTES: usize = 256 * 1024;
+
+/// Sends each emitted payload as a message to a downstream SQS queue.
+///
+/// Payloads are grouped into `SendMessageBatch` calls of at most 10 entries and
+/// 256KB. Payloads that are individually too large, or that SQS fails to enqueue,
+/// are reported as rejected in the `EmitReceipt` so that their source messages are
+/// not deleted. SQS message bodies are text, so payloads that aren't valid UTF-8 or
+/// that have an `EmitMetadata::content_encoding`, eg: from a `CompressingEmitter`,
+/// are sent base64 encoded with a `content-transfer-encoding: base64` attribute.
+/// The content encoding itself is sent as a `content-encoding` attribute.
+///
+/// Batches are sent one after another, in order, so a FIFO queue receives payloads
+/// in the order they were emitted under `EmitMetadata::message_group_id`. FIFO
+/// queues need content-based deduplication enabled, as no deduplication id is set.
+#[derive(Clone)]
+pub struct SqsEventEmitter<S>
+where
+    S: Sqs + Send + Sync + 'static,
+{
+    sqs: S,
+    queue_url: String,
+}
+
+impl<S> SqsEventEmitter<S>
+where
+    S: Sqs + Send + Sync + 'static,
+{
+    pub fn new(sqs: S, queue_url: impl Into<String>) -> Self {
+        Self {
+            sqs,
+            queue_url: queue_url.into(),
+        }
+    }
+}
+
+fn string_attribute(value: String) -> MessageAttributeValue {
+    MessageAttributeValue {
+        data_type: "String".to_owned(),
+        string_value: Some(value),
+        ..Default::default()
+    }
+}
+
+fn message_attributes(metadata: &EmitMetadata) -> HashMap<String, MessageAttributeValue> {
+    let mut attributes = HashMap::new();
+    if let Some(traceparent) = &metadata.traceparent {
+        attributes.insert("traceparent".to_owned(), string_attribute(traceparent.clone()));
+    }
+    if let Some(source_queue) = &metadata.source_queue {
+        attributes.insert("source-queue".to_owned(), string_attribute(source_queue.clone()));
+    }
+    if let Some(content_encoding) = &metadata.content_encoding {
+        attributes.insert(
+            "content-encoding".to_owned(),
+            string_attribute(content_encoding.clone()),
+        );
+    }
+    if metadata.degraded {
+        attributes.insert("degraded".to_owned(), string_attribute("true".to_owned()));
+    }
+    if let Some(schema_version) = metadata.schema_version {
+        attributes.insert(
+            "schema-version".to_owned(),
+            MessageAttributeValue {
+                data_type: "Number".to_owned(),
+                string_value: Some(schema_version.to_string()),
+                ..Default::default()
+            },
+        );
+    }
+    attributes
+}
+
+/// SQS counts attribute names, types and values towards the message size
+fn attributes_size(attributes: &HashMap<String, MessageAttributeValue>) -> usize {
+    attributes
+        .iter()
+        .map(|(name, value)| {
+            name.len()
+                + value.data_type.len()
+                + value.string_value.as_ref().map(String::len).unwrap_or(0)
+        })
+        .sum()
+}
+
+#[async_trait]
+impl<S> EventEmitter for SqsEventEmitter<S>
+where
+    S: Sqs + Send + Sync + 'static,
+{
+    type Event = Vec<u8>;
+    type Error = Error;
+
+    #[tracing::instrument(skip(self, events))]
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        self.emit_event_with_metadata(events, EmitMetadata::default())
+            .await
+    }
+
+    #[tracing::instrument(skip(self, events, metadata))]
+    async fn emit_event_with_metadata(
+        &mut self,
+        events: Vec<Self::Event>,
+        metadata: EmitMetadata,
+    ) -> Result<(), Self::Error> {
+        let receipt = self.emit_event_with_receipt(events, metadata).await?;
+        match receipt.rejected().len() {
+            0 => Ok(()),
+            rejected => Err(Error::OnEmissionError(format!(
+                "{} events were not sent to SQS",
+                rejected
+            ))),
+        }
+    }
+
+    #[tracing::instrument(skip(self, events, metadata))]
+    async fn emit_event_with_receipt(
+        &mut self,
+        events: Vec<Self::Event>,
+        metadata: EmitMetadata,
+    ) -> Result<EmitReceipt, Self::Error> {
+        let mut receipt = EmitReceipt::accepted();
+        let attributes = message_attributes(&metadata);
+        let mut base64_attributes = attributes.clone();
+        base64_attributes.insert(
+            "content-transfer-encoding".to_owned(),
+            string_attribute("base64".to_owned()),
+        );
+        let delay_seconds = metadata.delay_seconds();
+
+        // Entry ids are the event's index in `events`, so failures map straight back
+        let mut batches: Vec<(Vec<SendMessageBatchRequestEntry>, usize)> = vec![];
+        for (index, event) in events.into_iter().enumerate() {
+            // Encoded payloads are binary even when they happen to be valid UTF-8
+            let (body, attributes) = match String::from_utf8(event) {
+                Ok(body) if metadata.content_encoding.is_none() => (body, &attributes),
+                Ok(body) => (base64::encode(body), &base64_attributes),
+                Err(e) => (base64::encode(e.into_bytes()), &base64_attributes),
+            };
+
+            let size = body.len() + attributes_size(attributes);
+            if size > MAX_BATCH_BYTES {
+                warn!("Event {} of {} bytes is too large for SQS", index, size);
+                receipt.reject(index);
+                continue;
+            }
+
+            let entry = SendMessageBatchRequestEntry {
+                id: index.to_string(),
+                message_body: body,
+                delay_seconds,
+                message_group_id: metadata.message_group_id.clone(),
+                message_attributes: if attributes.is_empty() {
+                    None
+                } else {
+                    Some(attributes.clone())
+                },
+                ..Default::default()
+            };
+
+            match batches.last_mut() {
+                Some((entries, batch_size))
+                    if entries.len() < MAX_BATCH_ENTRIES && *batch_size + size <= MAX_BATCH_BYTES =>
+                {
+                    entries.push(entry);
+                    *batch_size += size;
+                }
+                _ => batches.push((vec![entry], size)),
+            }
+        }
+
+        for (entries, _) in batches {
+            let ids: Vec<usize> = entries
+                .iter()
+                .map(|entry| entry.id.parse().unwrap())
+                .collect();
+
+            let sent = self
+                .sqs
+                .send_message_batch(SendMessageBatchRequest {
+                    entries,
+                    queue_url: self.queue_url.clone(),
+                })
+                .await;
+
+            match sent {
+                Ok(result) => {
+                    for failure in result.failed {
+                        warn!(
+                            "Failed to send event {} to SQS: {} {:?}",
+                            failure.id, failure.code, failure.message
+                        );
+                        receipt.reject(failure.id.parse().unwrap());
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to send batch to SQS: {}", e);
+                    for id in ids {
+                        receipt.reject(id);
+                    }
+                }
+            }
+        }
+
+        Ok(receipt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_core::request::{DispatchSignedRequest, DispatchSignedRequestFuture, HttpResponse};
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_core::{ByteStream, Region};
+    use rusoto_sqs::SqsClient;
+
+    use super::*;
+
+    /// Answers every request with an empty 200, recording each request's body.
+    #[derive(Clone, Default)]
+    struct RecordingDispatcher {
+        bodies: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl DispatchSignedRequest for RecordingDispatcher {
+        fn dispatch(
+            &self,
+            request: SignedRequest,
+            _timeout: Option<Duration>,
+        ) -> DispatchSignedRequestFuture {
+            let body = match &request.payload {
+                Some(SignedRequestPayload::Buffer(body)) => {
+                    String::from_utf8_lossy(&body[..]).into_owned()
+                }
+                _ => String::new(),
+            };
+            self.bodies.lock().unwrap().push(body);
+
+            Box::pin(async {
+                Ok(HttpResponse {
+                    status: Default::default(),
+                    body: ByteStream::from(vec![]),
+                    headers: Default::default(),
+                })
+            })
+        }
+    }
+
+    impl RecordingDispatcher {
+        /// The number of entries in each SendMessageBatch request.
+        fn batch_sizes(&self) -> Vec<usize> {
+            self.bodies
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|body| body.matches(".Id=").count())
+                .collect()
+        }
+    }
+
+    fn emitter(dispatcher: &RecordingDispatcher) -> SqsEventEmitter<SqsClient> {
+        let sqs = SqsClient::new_with(
+            dispatcher.clone(),
+            StaticProvider::new_minimal("key".to_owned(), "secret".to_owned()),
+            Region::UsEast1,
+        );
+        SqsEventEmitter::new(sqs, "https://sqs.us-east-1.amazonaws.com/123456789012/downstream")
+    }
+
+    #[tokio::test]
+    async fn batches_are_split_by_size() {
+        let dispatcher = RecordingDispatcher::default();
+        let events = vec![vec![b'a'; 100 * 1024]; 3];
+
+        let receipt = emitter(&dispatcher)
+            .emit_event_with_receipt(events, EmitMetadata::default())
+            .await
+            .unwrap();
+
+        assert!(receipt.rejected().is_empty());
+        assert_eq!(dispatcher.batch_sizes(), vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn batches_are_split_by_entry_count() {
+        let dispatcher = RecordingDispatcher::default();
+        let events = vec![b"event".to_vec(); 12];
+
+        emitter(&dispatcher).emit_event(events).await.unwrap();
+
+        assert_eq!(dispatcher.batch_sizes(), vec![10, 2]);
+    }
+
+    #[tokio::test]
+    async fn oversized_events_are_rejected() {
+        let dispatcher = RecordingDispatcher::default();
+        let events = vec![vec![b'a'; MAX_BATCH_BYTES + 1], b"event".to_vec()];
+
+        let receipt = emitter(&dispatcher)
+            .emit_event_with_receipt(events, EmitMetadata::default())
+            .await
+            .unwrap();
+
+        assert_eq!(receipt.rejected(), &[0]);
+        assert_eq!(dispatcher.batch_sizes(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn non_utf8_events_are_sent_as_base64() {
+        let dispatcher = RecordingDispatcher::default();
+        // Not valid UTF-8, base64 encoded as "ABCD"
+        let events = vec![vec![0x00, 0x10, 0x83]];
+
+        let receipt = emitter(&dispatcher)
+            .emit_event_with_receipt(events, EmitMetadata::default())
+            .await
+            .unwrap();
+
+        assert!(receipt.rejected().is_empty());
+        let body = dispatcher.bodies.lock().unwrap()[0].clone();
+        assert!(body.contains("MessageBody=ABCD"));
+        assert!(body.contains("content-transfer-encoding"));
+        assert!(!body.contains("content-encoding"));
+    }
+
+    #[tokio::test]
+    async fn encoded_events_are_sent_as_base64_with_their_encoding() {
+        let dispatcher = RecordingDispatcher::default();
+        let metadata = EmitMetadata {
+            content_encoding: Some("gzip".to_owned()),
+            ..EmitMetadata::default()
+        };
+
+        emitter(&dispatcher)
+            .emit_event_with_metadata(vec![b"abc".to_vec()], metadata)
+            .await
+            .unwrap();
+
+        let body = dispatcher.bodies.lock().unwrap()[0].clone();
+        assert!(body.contains("MessageBody=YWJj"));
+        assert!(body.contains("content-transfer-encoding"));
+        assert!(body.contains("content-encoding"));
+        assert!(body.contains("gzip"));
+    }
+}
@@ -0,0 +1,434 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusoto_core::{Region, RusotoError};
+use rusoto_sqs::{
+    ChangeMessageVisibilityBatchRequest, ChangeMessageVisibilityBatchResult,
+    DeleteMessageBatchRequest, DeleteMessageBatchResult, GetQueueAttributesRequest,
+    GetQueueAttributesResult, Sqs, SqsClient,
+};
+
+use crate::error::{Error, HealthError};
+
+/// Where and how to reach SQS, for callers that would rather not construct a
+/// rusoto client themselves.
+#[derive(Clone, Debug)]
+pub struct SqsConfig {
+    pub region: Region,
+    /// Overrides the region's default endpoint, eg: for a VPC endpoint
+    pub endpoint: Option<String>,
+    /// How long each SQS request may take before it is retried
+    pub timeout: Duration,
+}
+
+impl SqsConfig {
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            endpoint: None,
+            timeout: Duration::from_millis(250),
+        }
+    }
+
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The region the client will target, with the endpoint override applied.
+    pub fn resolved_region(&self) -> Region {
+        match &self.endpoint {
+            Some(endpoint) => Region::Custom {
+                name: self.region.name().to_owned(),
+                endpoint: endpoint.clone(),
+            },
+            None => self.region.clone(),
+        }
+    }
+
+    /// Builds a client using the default credential chain.
+    pub fn client(&self) -> SqsClient {
+        SqsClient::new(self.resolved_region())
+    }
+}
+
+/// The SQS operations the completion handler depends on.
+///
+/// Any rusoto `Sqs` client implements this, so `SqsClient` is the default backend.
+/// To use the official `aws-sdk-sqs` client instead, enable the `aws-sdk` feature and
+/// pass the handler an `AwsSdkSqs` in place of the rusoto client. Another backend,
+/// eg: an in-memory queue in tests, is selected by implementing this trait for it.
+/// Requests and results use the rusoto types regardless of the backend. Backends
+/// are driven on the handler's tokio 0.2 runtime, so a client built for a different
+/// runtime has to be bridged to it by its implementation, as `AwsSdkSqs` does.
+#[async_trait]
+pub trait SqsOps {
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, Error>;
+
+    async fn change_message_visibility_batch(
+        &self,
+        input: ChangeMessageVisibilityBatchRequest,
+    ) -> Result<ChangeMessageVisibilityBatchResult, Error>;
+
+    /// Used to probe connectivity, so errors distinguish a missing queue from an
+    /// unreachable SQS.
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, HealthError>;
+}
+
+#[async_trait]
+impl<S> SqsOps for S
+where
+    S: Sqs + Send + Sync + 'static,
+{
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, Error> {
+        Sqs::delete_message_batch(self, input)
+            .await
+            .map_err(|e| Error::SqsError(format!("{}", e)))
+    }
+
+    async fn change_message_visibility_batch(
+        &self,
+        input: ChangeMessageVisibilityBatchRequest,
+    ) -> Result<ChangeMessageVisibilityBatchResult, Error> {
+        Sqs::change_message_visibility_batch(self, input)
+            .await
+            .map_err(|e| Error::SqsError(format!("{}", e)))
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, HealthError> {
+        Sqs::get_queue_attributes(self, input)
+            .await
+            .map_err(|e| match e {
+                RusotoError::HttpDispatch(e) => HealthError::Unreachable(format!("{}", e)),
+                // rusoto has no typed variant for a missing queue, it surfaces as an
+                // unrecognised error code in the response body
+                RusotoError::Unknown(ref response)
+                    if is_queue_not_found(&response.body_as_str()) =>
+                {
+                    HealthError::QueueNotFound(response.body_as_str().into_owned())
+                }
+                e => HealthError::Other(format!("{}", e)),
+            })
+    }
+}
+
+fn is_queue_not_found(body: &str) -> bool {
+    body.contains("AWS.SimpleQueueService.NonExistentQueue") || body.contains("QueueDoesNotExist")
+}
+
+/// Adapts an `aws_sdk_sqs::Client` to `SqsOps`. The SDK needs tokio 1, so its
+/// requests are spawned onto a tokio 1 runtime and awaited from the handler's.
+#[cfg(feature = "aws-sdk")]
+#[derive(Clone)]
+pub struct AwsSdkSqs {
+    client: aws_sdk_sqs::Client,
+    runtime: tokio1::runtime::Handle,
+}
+
+#[cfg(feature = "aws-sdk")]
+impl AwsSdkSqs {
+    /// Sends requests with `client` on `runtime`, which must be kept running for as
+    /// long as the handler is, eg:
+    ///
+    /// ```ignore
+    /// let runtime = tokio1::runtime::Runtime::new()?;
+    /// let config = runtime.block_on(aws_config::load_from_env());
+    /// let sqs = AwsSdkSqs::new(aws_sdk_sqs::Client::new(&config), runtime.handle().clone());
+    /// ```
+    pub fn new(client: aws_sdk_sqs::Client, runtime: tokio1::runtime::Handle) -> Self {
+        Self { client, runtime }
+    }
+
+    /// Drives `request` to completion on the SDK's runtime.
+    async fn on_runtime<T>(
+        &self,
+        request: impl std::future::Future<Output = T> + Send + 'static,
+    ) -> Result<T, String>
+    where
+        T: Send + 'static,
+    {
+        self.runtime
+            .spawn(request)
+            .await
+            .map_err(|e| format!("SQS request failed to complete: {}", e))
+    }
+}
+
+#[cfg(feature = "aws-sdk")]
+#[async_trait]
+impl SqsOps for AwsSdkSqs {
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, Error> {
+        use aws_sdk_sqs::types::DeleteMessageBatchRequestEntry;
+
+        let entries = input
+            .entries
+            .into_iter()
+            .map(|entry| {
+                DeleteMessageBatchRequestEntry::builder()
+                    .id(entry.id)
+                    .receipt_handle(entry.receipt_handle)
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::SqsError(format!("{}", e)))?;
+
+        let request = self
+            .client
+            .delete_message_batch()
+            .queue_url(input.queue_url)
+            .set_entries(Some(entries))
+            .send();
+        let output = self
+            .on_runtime(request)
+            .await
+            .map_err(Error::SqsError)?
+            .map_err(|e| Error::SqsError(format!("{}", e)))?;
+
+        Ok(DeleteMessageBatchResult {
+            successful: output
+                .successful()
+                .iter()
+                .map(|success| rusoto_sqs::DeleteMessageBatchResultEntry {
+                    id: success.id().to_owned(),
+                })
+                .collect(),
+            failed: output.failed().iter().map(into_rusoto_failure).collect(),
+        })
+    }
+
+    async fn change_message_visibility_batch(
+        &self,
+        input: ChangeMessageVisibilityBatchRequest,
+    ) -> Result<ChangeMessageVisibilityBatchResult, Error> {
+        use aws_sdk_sqs::types::ChangeMessageVisibilityBatchRequestEntry;
+
+        let entries = input
+            .entries
+            .into_iter()
+            .map(|entry| {
+                ChangeMessageVisibilityBatchRequestEntry::builder()
+                    .id(entry.id)
+                    .receipt_handle(entry.receipt_handle)
+                    .set_visibility_timeout(entry.visibility_timeout.map(|t| t as i32))
+                    .build()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::SqsError(format!("{}", e)))?;
+
+        let request = self
+            .client
+            .change_message_visibility_batch()
+            .queue_url(input.queue_url)
+            .set_entries(Some(entries))
+            .send();
+        let output = self
+            .on_runtime(request)
+            .await
+            .map_err(Error::SqsError)?
+            .map_err(|e| Error::SqsError(format!("{}", e)))?;
+
+        Ok(ChangeMessageVisibilityBatchResult {
+            successful: output
+                .successful()
+                .iter()
+                .map(|success| rusoto_sqs::ChangeMessageVisibilityBatchResultEntry {
+                    id: success.id().to_owned(),
+                })
+                .collect(),
+            failed: output.failed().iter().map(into_rusoto_failure).collect(),
+        })
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, HealthError> {
+        use aws_sdk_sqs::error::SdkError;
+        use aws_sdk_sqs::operation::get_queue_attributes::GetQueueAttributesError;
+        use aws_sdk_sqs::types::QueueAttributeName;
+
+        let attribute_names = input.attribute_names.map(|attribute_names| {
+            attribute_names
+                .iter()
+                .map(|attribute_name| QueueAttributeName::from(attribute_name.as_str()))
+                .collect()
+        });
+        let request = self
+            .client
+            .get_queue_attributes()
+            .queue_url(input.queue_url)
+            .set_attribute_names(attribute_names)
+            .send();
+        let output = self
+            .on_runtime(request)
+            .await
+            .map_err(HealthError::Other)?
+            .map_err(|e| match e {
+                SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => {
+                    HealthError::Unreachable(format!("{}", e))
+                }
+                e => match e.into_service_error() {
+                    GetQueueAttributesError::QueueDoesNotExist(e) => {
+                        HealthError::QueueNotFound(format!("{}", e))
+                    }
+                    e => HealthError::Other(format!("{}", e)),
+                },
+            })?;
+
+        Ok(GetQueueAttributesResult {
+            attributes: output.attributes().map(|attributes| {
+                attributes
+                    .iter()
+                    .map(|(name, value)| (name.as_str().to_owned(), value.clone()))
+                    .collect()
+            }),
+        })
+    }
+}
+
+#[cfg(feature = "aws-sdk")]
+fn into_rusoto_failure(
+    failure: &aws_sdk_sqs::types::BatchResultErrorEntry,
+) -> rusoto_sqs::BatchResultErrorEntry {
+    rusoto_sqs::BatchResultErrorEntry {
+        code: failure.code().to_owned(),
+        id: failure.id().to_owned(),
+        message: failure.message().map(str::to_owned),
+        sender_fault: failure.sender_fault(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_queue_errors_are_recognised() {
+        assert!(is_queue_not_found(
+            "<Error><Code>AWS.SimpleQueueService.NonExistentQueue</Code></Error>"
+        ));
+        assert!(is_queue_not_found("<Error><Code>QueueDoesNotExist</Code></Error>"));
+        assert!(!is_queue_not_found("<Error><Code>AccessDenied</Code></Error>"));
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[test]
+    fn sdk_failures_convert_to_rusoto() {
+        let failure = aws_sdk_sqs::types::BatchResultErrorEntry::builder()
+            .id("1")
+            .code("ReceiptHandleIsInvalid")
+            .sender_fault(true)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            into_rusoto_failure(&failure),
+            rusoto_sqs::BatchResultErrorEntry {
+                code: "ReceiptHandleIsInvalid".to_owned(),
+                id: "1".to_owned(),
+                message: None,
+                sender_fault: true,
+            }
+        );
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[tokio::test]
+    async fn sdk_requests_run_on_the_sdk_runtime() {
+        let runtime = tokio1::runtime::Runtime::new().unwrap();
+        let credentials = aws_sdk_sqs::config::Credentials::new("key", "secret", None, None, "test");
+        let config = aws_sdk_sqs::Config::builder()
+            .behavior_version(aws_sdk_sqs::config::BehaviorVersion::latest())
+            .region(aws_sdk_sqs::config::Region::new("us-east-1"))
+            .credentials_provider(credentials)
+            // Nothing listens on port 1
+            .endpoint_url("http://127.0.0.1:1")
+            .build();
+        let sqs = AwsSdkSqs::new(aws_sdk_sqs::Client::from_conf(config), runtime.handle().clone());
+
+        let checked = SqsOps::get_queue_attributes(
+            &sqs,
+            GetQueueAttributesRequest {
+                queue_url: "http://127.0.0.1:1/123456789012/test-queue".to_owned(),
+                attribute_names: None,
+            },
+        )
+        .await;
+
+        match checked {
+            Err(HealthError::Unreachable(_)) => (),
+            checked => panic!("Expected an unreachable endpoint, got {:?}", checked),
+        }
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn endpoint_overrides_the_region_endpoint() {
+        let sqs_config = SqsConfig::new(Region::UsWest2)
+            .endpoint("http://localhost:4566")
+            .timeout(Duration::from_secs(1));
+
+        assert_eq!(
+            sqs_config.resolved_region(),
+            Region::Custom {
+                name: "us-west-2".to_owned(),
+                endpoint: "http://localhost:4566".to_owned(),
+            }
+        );
+        assert_eq!(sqs_config.timeout, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn region_is_used_without_an_endpoint() {
+        assert_eq!(SqsConfig::new(Region::EuWest1).resolved_region(), Region::EuWest1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoints_are_distinguished() {
+        let credentials =
+            rusoto_core::credential::StaticProvider::new_minimal("key".to_owned(), "secret".to_owned());
+        let sqs_client = SqsClient::new_with(
+            rusoto_core::HttpClient::new().unwrap(),
+            credentials,
+            Region::Custom {
+                name: "us-east-1".to_owned(),
+                // Nothing listens on port 1
+                endpoint: "http://127.0.0.1:1".to_owned(),
+            },
+        );
+
+        let checked = SqsOps::get_queue_attributes(
+            &sqs_client,
+            GetQueueAttributesRequest {
+                queue_url: "http://127.0.0.1:1/123456789012/test-queue".to_owned(),
+                attribute_names: None,
+            },
+        )
+        .await;
+
+        match checked {
+            Err(HealthError::Unreachable(_)) => (),
+            checked => panic!("Expected an unreachable endpoint, got {:?}", checked),
+        }
+    }
+}
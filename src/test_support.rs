@@ -0,0 +1,539 @@
+//! In-memory stand-ins for SQS, emitters, caches and serializers, shared by the
+//! crate's tests.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusoto_sqs::Message as SqsMessage;
+use rusoto_sqs::{
+    BatchResultErrorEntry, ChangeMessageVisibilityBatchRequest,
+    ChangeMessageVisibilityBatchResult, ChangeMessageVisibilityBatchResultEntry,
+    DeleteMessageBatchRequest, DeleteMessageBatchResult, DeleteMessageBatchResultEntry,
+    GetQueueAttributesRequest, GetQueueAttributesResult,
+};
+
+use crate::cache::{Cache, CacheResponse, Cacheable};
+use crate::completion_event_serializer::CompletionEventSerializer;
+use crate::error::{Error, HealthError};
+use crate::event_emitter::{EmitMetadata, EmitReceipt, EventEmitter};
+use crate::sqs_ops::SqsOps;
+use crate::wal::{Wal, WalEntry};
+
+pub(crate) const QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/test-queue";
+
+/// A received message with an id, a receipt handle and a body derived from `id`.
+pub(crate) fn message(id: &str) -> SqsMessage {
+    SqsMessage {
+        message_id: Some(id.to_owned()),
+        receipt_handle: Some(format!("receipt-{}", id)),
+        body: Some(format!("body-{}", id)),
+        ..SqsMessage::default()
+    }
+}
+
+#[derive(Default)]
+struct MockSqsState {
+    delete_requests: Vec<DeleteMessageBatchRequest>,
+    visibility_requests: Vec<ChangeMessageVisibilityBatchRequest>,
+    // Errors to fail the next delete requests with, in order
+    delete_errors: Vec<String>,
+    // Delete requests left to hang, until the handler times them out
+    stalled_deletes: usize,
+    delete_attempts: usize,
+    // How long each delete request takes
+    delete_latency: Duration,
+    deletes_in_flight: usize,
+    max_deletes_in_flight: usize,
+    // Entries reported as failed within an otherwise successful response
+    failing_ids: HashSet<String>,
+    queue_missing: bool,
+}
+
+/// An `SqsOps` backend that records every request, succeeding unless told to fail.
+#[derive(Clone, Default)]
+pub(crate) struct MockSqs {
+    state: Arc<Mutex<MockSqsState>>,
+}
+
+impl MockSqs {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the next `count` delete requests outright.
+    pub(crate) fn fail_deletes(&self, count: usize) {
+        self.fail_deletes_with(count, "InternalError");
+    }
+
+    /// Fails the next `count` delete requests with an `SqsError` carrying `error`,
+    /// eg: "Throttling".
+    pub(crate) fn fail_deletes_with(&self, count: usize, error: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .delete_errors
+            .extend(std::iter::repeat(error.to_owned()).take(count));
+    }
+
+    /// Hangs the next `count` delete requests, so that they time out.
+    pub(crate) fn stall_deletes(&self, count: usize) {
+        self.state.lock().unwrap().stalled_deletes = count;
+    }
+
+    /// Takes `delete_latency` to answer each delete request.
+    pub(crate) fn slow_deletes(&self, delete_latency: Duration) {
+        self.state.lock().unwrap().delete_latency = delete_latency;
+    }
+
+    /// The most delete requests that were in flight at once.
+    pub(crate) fn max_deletes_in_flight(&self) -> usize {
+        self.state.lock().unwrap().max_deletes_in_flight
+    }
+
+    /// Delete requests received, including those that failed or timed out.
+    pub(crate) fn delete_attempts(&self) -> usize {
+        self.state.lock().unwrap().delete_attempts
+    }
+
+    /// Reports the message as failed whenever its deletion is requested.
+    pub(crate) fn fail_message(&self, message_id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .failing_ids
+            .insert(message_id.to_owned());
+    }
+
+    pub(crate) fn set_queue_missing(&self, queue_missing: bool) {
+        self.state.lock().unwrap().queue_missing = queue_missing;
+    }
+
+    pub(crate) fn delete_requests(&self) -> Vec<DeleteMessageBatchRequest> {
+        self.state.lock().unwrap().delete_requests.clone()
+    }
+
+    pub(crate) fn visibility_requests(&self) -> Vec<ChangeMessageVisibilityBatchRequest> {
+        self.state.lock().unwrap().visibility_requests.clone()
+    }
+
+    /// The ids of every message successfully deleted, in the order they were deleted.
+    pub(crate) fn deleted_ids(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        // Requests that failed outright aren't recorded
+        state
+            .delete_requests
+            .iter()
+            .flat_map(|request| request.entries.iter())
+            .filter(|entry| !state.failing_ids.contains(&entry.id))
+            .map(|entry| entry.id.clone())
+            .collect()
+    }
+}
+
+#[async_trait]
+impl SqsOps for MockSqs {
+    async fn delete_message_batch(
+        &self,
+        input: DeleteMessageBatchRequest,
+    ) -> Result<DeleteMessageBatchResult, Error> {
+        let stalled = {
+            let mut state = self.state.lock().unwrap();
+            state.delete_attempts += 1;
+            let stalled = state.stalled_deletes > 0;
+            state.stalled_deletes = state.stalled_deletes.saturating_sub(1);
+            stalled
+        };
+        if stalled {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+        }
+
+        let delete_latency = {
+            let mut state = self.state.lock().unwrap();
+            state.deletes_in_flight += 1;
+            state.max_deletes_in_flight = state.max_deletes_in_flight.max(state.deletes_in_flight);
+            state.delete_latency
+        };
+        tokio::time::delay_for(delete_latency).await;
+
+        let mut state = self.state.lock().unwrap();
+        state.deletes_in_flight -= 1;
+        if !state.delete_errors.is_empty() {
+            let error = state.delete_errors.remove(0);
+            return Err(Error::SqsError(error));
+        }
+
+        let mut result = DeleteMessageBatchResult::default();
+        for entry in &input.entries {
+            if state.failing_ids.contains(&entry.id) {
+                result.failed.push(BatchResultErrorEntry {
+                    code: "ReceiptHandleIsInvalid".to_owned(),
+                    id: entry.id.clone(),
+                    message: None,
+                    sender_fault: true,
+                });
+            } else {
+                result.successful.push(DeleteMessageBatchResultEntry {
+                    id: entry.id.clone(),
+                });
+            }
+        }
+        state.delete_requests.push(input);
+        Ok(result)
+    }
+
+    async fn change_message_visibility_batch(
+        &self,
+        input: ChangeMessageVisibilityBatchRequest,
+    ) -> Result<ChangeMessageVisibilityBatchResult, Error> {
+        let mut state = self.state.lock().unwrap();
+        let result = ChangeMessageVisibilityBatchResult {
+            successful: input
+                .entries
+                .iter()
+                .map(|entry| ChangeMessageVisibilityBatchResultEntry {
+                    id: entry.id.clone(),
+                })
+                .collect(),
+            failed: vec![],
+        };
+        state.visibility_requests.push(input);
+        Ok(result)
+    }
+
+    async fn get_queue_attributes(
+        &self,
+        input: GetQueueAttributesRequest,
+    ) -> Result<GetQueueAttributesResult, HealthError> {
+        if self.state.lock().unwrap().queue_missing {
+            return Err(HealthError::QueueNotFound(input.queue_url));
+        }
+        Ok(GetQueueAttributesResult::default())
+    }
+}
+
+#[derive(Default)]
+struct MockEmitterState {
+    batches: Vec<(Vec<Vec<u8>>, EmitMetadata)>,
+    // Emits left to fail before emits succeed again
+    failures: usize,
+    // Indexes into the next batch to reject
+    rejections: Vec<usize>,
+    // Emits left to hang, until the handler gives up on them
+    stalled: usize,
+    attempts: usize,
+    // The metadata of every attempt, including those that failed
+    attempted_metadata: Vec<EmitMetadata>,
+}
+
+/// An emitter that records every batch it accepts, with its metadata.
+#[derive(Clone, Default)]
+pub(crate) struct MockEmitter {
+    state: Arc<Mutex<MockEmitterState>>,
+}
+
+impl MockEmitter {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the next `count` emits.
+    pub(crate) fn fail_emits(&self, count: usize) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// Hangs the next `count` emits.
+    pub(crate) fn stall_emits(&self, count: usize) {
+        self.state.lock().unwrap().stalled = count;
+    }
+
+    /// Rejects the events at `indexes` of the next batch.
+    pub(crate) fn reject_next(&self, indexes: Vec<usize>) {
+        self.state.lock().unwrap().rejections = indexes;
+    }
+
+    pub(crate) fn batches(&self) -> Vec<Vec<Vec<u8>>> {
+        self.state
+            .lock()
+            .unwrap()
+            .batches
+            .iter()
+            .map(|(batch, _)| batch.clone())
+            .collect()
+    }
+
+    pub(crate) fn metadata(&self) -> Vec<EmitMetadata> {
+        self.state
+            .lock()
+            .unwrap()
+            .batches
+            .iter()
+            .map(|(_, metadata)| metadata.clone())
+            .collect()
+    }
+
+    /// Every accepted event, as strings, in the order they were emitted.
+    pub(crate) fn events(&self) -> Vec<String> {
+        self.batches()
+            .into_iter()
+            .flatten()
+            .map(|event| String::from_utf8(event).unwrap())
+            .collect()
+    }
+
+    /// Emits attempted, including those that failed.
+    pub(crate) fn attempts(&self) -> usize {
+        self.state.lock().unwrap().attempts
+    }
+
+    /// The metadata of every emit attempted, including those that failed.
+    pub(crate) fn attempted_metadata(&self) -> Vec<EmitMetadata> {
+        self.state.lock().unwrap().attempted_metadata.clone()
+    }
+}
+
+#[async_trait]
+impl EventEmitter for MockEmitter {
+    type Event = Vec<u8>;
+    type Error = String;
+
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        self.emit_event_with_receipt(events, EmitMetadata::default())
+            .await
+            .map(|_| ())
+    }
+
+    async fn emit_event_with_receipt(
+        &mut self,
+        events: Vec<Self::Event>,
+        metadata: EmitMetadata,
+    ) -> Result<EmitReceipt, Self::Error> {
+        let stalled = {
+            let mut state = self.state.lock().unwrap();
+            state.attempts += 1;
+            state.attempted_metadata.push(metadata.clone());
+            let stalled = state.stalled > 0;
+            state.stalled = state.stalled.saturating_sub(1);
+            stalled
+        };
+        if stalled {
+            tokio::time::delay_for(Duration::from_secs(60)).await;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err("Emitter unavailable".to_owned());
+        }
+
+        let mut receipt = EmitReceipt::accepted();
+        for index in std::mem::replace(&mut state.rejections, vec![]) {
+            receipt.reject(index);
+        }
+        state.batches.push((events, metadata));
+        Ok(receipt)
+    }
+}
+
+#[derive(Default)]
+struct MockCacheState {
+    stored: HashSet<Vec<u8>>,
+    // Stores left to fail before stores succeed again
+    store_failures: usize,
+    unavailable: bool,
+}
+
+/// A cache held in memory and shared between its clones.
+#[derive(Clone, Default)]
+pub(crate) struct MockCache {
+    state: Arc<Mutex<MockCacheState>>,
+}
+
+impl MockCache {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn insert(&self, identity: impl Cacheable) {
+        self.state
+            .lock()
+            .unwrap()
+            .stored
+            .insert(identity.identity());
+    }
+
+    pub(crate) fn contains(&self, identity: impl Cacheable) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .stored
+            .contains(&identity.identity())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().stored.len()
+    }
+
+    /// Fails the next `count` stores.
+    pub(crate) fn fail_stores(&self, count: usize) {
+        self.state.lock().unwrap().store_failures = count;
+    }
+
+    /// Fails every read and store while `unavailable`.
+    pub(crate) fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+}
+
+#[async_trait]
+impl Cache for MockCache {
+    async fn get<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<CacheResponse, Error> {
+        let state = self.state.lock().unwrap();
+        if state.unavailable {
+            return Err(Error::CacheError("Cache unavailable".to_owned()));
+        }
+        if state.stored.contains(&cacheable.identity()) {
+            Ok(CacheResponse::Hit)
+        } else {
+            Ok(CacheResponse::Miss)
+        }
+    }
+
+    async fn store(&mut self, identity: Vec<u8>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.unavailable {
+            return Err(Error::CacheError("Cache unavailable".to_owned()));
+        }
+        if state.store_failures > 0 {
+            state.store_failures -= 1;
+            return Err(Error::CacheError("Store failed".to_owned()));
+        }
+        state.stored.insert(identity);
+        Ok(())
+    }
+}
+
+/// A WAL held in memory and shared between its clones, so that it outlives the
+/// handler it was given to.
+#[derive(Clone, Default)]
+pub(crate) struct MockWal {
+    entries: Arc<Mutex<Vec<WalEntry<String>>>>,
+    // How many of the next appends and truncations fail
+    failing_writes: Arc<Mutex<usize>>,
+}
+
+impl MockWal {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn events(&self) -> Vec<String> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.event.clone())
+            .collect()
+    }
+
+    pub(crate) fn fail_writes(&self, count: usize) {
+        *self.failing_writes.lock().unwrap() = count;
+    }
+
+    fn write_fails(&self) -> bool {
+        let mut failing_writes = self.failing_writes.lock().unwrap();
+        if *failing_writes == 0 {
+            return false;
+        }
+        *failing_writes -= 1;
+        true
+    }
+}
+
+impl Wal<String> for MockWal {
+    fn append(&mut self, entry: &WalEntry<String>) -> Result<(), Error> {
+        if self.write_fails() {
+            return Err(Error::IoError("append failed".to_owned()));
+        }
+        self.entries.lock().unwrap().push(entry.clone());
+        Ok(())
+    }
+
+    fn replay(&mut self) -> Result<Vec<WalEntry<String>>, Error> {
+        Ok(self.entries.lock().unwrap().clone())
+    }
+
+    fn truncate(&mut self) -> Result<(), Error> {
+        if self.write_fails() {
+            return Err(Error::IoError("truncate failed".to_owned()));
+        }
+        self.entries.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// Events that `StringSerializer` fails to serialize.
+pub(crate) const UNSERIALIZABLE: &str = "unserializable";
+
+/// Serializes each event to its bytes as a payload of its own, failing on
+/// `UNSERIALIZABLE` events.
+#[derive(Clone, Default)]
+pub(crate) struct StringSerializer;
+
+impl CompletionEventSerializer for StringSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        completed_events
+            .iter()
+            .map(|event| match event.as_str() {
+                UNSERIALIZABLE => Err(format!("Can't serialize {}", event)),
+                _ => Ok(event.clone().into_bytes()),
+            })
+            .collect()
+    }
+}
+
+thread_local! {
+    static CAPTURED_LOGS: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+/// Records log lines per thread. `#[tokio::test]`s run their tasks on the test's
+/// own thread, so each test only sees its own lines.
+struct ThreadLogger;
+
+static LOGGER: ThreadLogger = ThreadLogger;
+
+impl log::Log for ThreadLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        CAPTURED_LOGS.with(|logs| logs.borrow_mut().push(format!("{}", record.args())));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts capturing every log line written on this thread, at every level.
+pub(crate) fn capture_logs() {
+    // Fails if another test already installed it
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Trace);
+    CAPTURED_LOGS.with(|logs| logs.borrow_mut().clear());
+}
+
+/// The lines logged on this thread since `capture_logs`.
+pub(crate) fn captured_logs() -> Vec<String> {
+    CAPTURED_LOGS.with(|logs| logs.borrow().clone())
+}
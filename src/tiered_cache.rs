@@ -0,0 +1,118 @@
+use async_trait::async_trait;
+use log::*;
+
+use crate::cache::{Cache, CacheResponse, Cacheable, Identity};
+
+/// A fast `primary` cache, eg: a local one, backed by a durable `secondary` one.
+///
+/// Identities are stored in the primary, and only stored in the secondary if the
+/// primary fails. Lookups that miss or fail in the primary are retried against the
+/// secondary, and identities found there are stored in the primary so that the next
+/// lookup doesn't have to.
+#[derive(Clone)]
+pub struct TieredCache<A, B>
+where
+    A: Cache + Send + Sync + 'static,
+    B: Cache + Send + Sync + 'static,
+{
+    primary: A,
+    secondary: B,
+}
+
+impl<A, B> TieredCache<A, B>
+where
+    A: Cache + Send + Sync + 'static,
+    B: Cache + Send + Sync + 'static,
+{
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+#[async_trait]
+impl<A, B> Cache for TieredCache<A, B>
+where
+    A: Cache + Send + Sync + 'static,
+    B: Cache + Send + Sync + 'static,
+{
+    async fn get<CA: Cacheable + Send + Sync + 'static>(
+        &mut self,
+        cacheable: CA,
+    ) -> Result<CacheResponse, crate::error::Error> {
+        let identity = cacheable.identity();
+        match self.primary.get(Identity(identity.clone())).await {
+            Ok(CacheResponse::Hit) => return Ok(CacheResponse::Hit),
+            Ok(CacheResponse::Miss) => (),
+            Err(e) => warn!("Primary cache lookup failed, falling back: {:?}", e),
+        }
+
+        let response = self.secondary.get(Identity(identity.clone())).await?;
+        if let CacheResponse::Hit = response {
+            if let Err(e) = self.primary.store(identity).await {
+                warn!("Failed to populate the primary cache: {:?}", e);
+            }
+        }
+        Ok(response)
+    }
+
+    async fn store(&mut self, identity: Vec<u8>) -> Result<(), crate::error::Error> {
+        match self.primary.store(identity.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Primary cache store failed, falling back: {:?}", e);
+                self.secondary.store(identity).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockCache;
+
+    fn is_hit(response: CacheResponse) -> bool {
+        match response {
+            CacheResponse::Hit => true,
+            CacheResponse::Miss => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_primaries_fall_through_to_the_secondary() {
+        let (primary, secondary) = (MockCache::new(), MockCache::new());
+        let mut cache = TieredCache::new(primary.clone(), secondary.clone());
+        primary.set_unavailable(true);
+
+        cache.store(b"a".to_vec()).await.unwrap();
+        assert!(secondary.contains(Identity(b"a".to_vec())));
+        assert!(is_hit(cache.get(Identity(b"a".to_vec())).await.unwrap()));
+        assert!(!is_hit(cache.get(Identity(b"b".to_vec())).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn secondary_hits_populate_the_primary() {
+        let (primary, secondary) = (MockCache::new(), MockCache::new());
+        let mut cache = TieredCache::new(primary.clone(), secondary.clone());
+        secondary.insert(Identity(b"a".to_vec()));
+
+        assert!(is_hit(cache.get(Identity(b"a".to_vec())).await.unwrap()));
+        assert!(primary.contains(Identity(b"a".to_vec())));
+
+        // Stored in the primary alone while it is healthy
+        cache.store(b"b".to_vec()).await.unwrap();
+        assert!(primary.contains(Identity(b"b".to_vec())));
+        assert!(!secondary.contains(Identity(b"b".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn lookups_fail_when_both_tiers_fail() {
+        let (primary, secondary) = (MockCache::new(), MockCache::new());
+        let mut cache = TieredCache::new(primary.clone(), secondary.clone());
+        primary.set_unavailable(true);
+        secondary.set_unavailable(true);
+
+        assert!(cache.get(Identity(b"a".to_vec())).await.is_err());
+        assert!(cache.store(b"a".to_vec()).await.is_err());
+    }
+}
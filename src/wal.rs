@@ -0,0 +1,23 @@
+use rusoto_sqs::Message as SqsMessage;
+
+use crate::error::Error;
+
+/// A buffered completed event, as persisted to a `Wal`.
+#[derive(Clone, Debug)]
+pub struct WalEntry<CE> {
+    pub event: CE,
+    /// The message to delete once the event is emitted, None for Partial completions
+    pub message: Option<SqsMessage>,
+}
+
+/// A write-ahead log of completed events that have been buffered but not yet
+/// flushed, so that they survive a crash.
+///
+/// The handler appends each event as it is buffered and truncates the log once a
+/// flush completes. On startup `SqsCompletionHandler::recover` replays the log
+/// back into the buffer.
+pub trait Wal<CE> {
+    fn append(&mut self, entry: &WalEntry<CE>) -> Result<(), Error>;
+    fn replay(&mut self) -> Result<Vec<WalEntry<CE>>, Error>;
+    fn truncate(&mut self) -> Result<(), Error>;
+}
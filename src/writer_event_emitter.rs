@@ -0,0 +1,242 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::completion_event_serializer::{CompletionEventSerializer, SerializeToError};
+use crate::event_emitter::EventEmitter;
+
+/// Writes emitted payloads to any `Write`, eg: a file or stdout, each on its own line
+/// by default. Handy for local debugging, see `WriterEmitter::stdout`. Writes block
+/// the handler, use `AsyncWriterEmitter` for anything slower than a local file.
+pub struct WriterEmitter<W>
+where
+    W: Write + Send + Sync + 'static,
+{
+    writer: W,
+    newline_delimited: bool,
+}
+
+impl WriterEmitter<std::io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+}
+
+impl<W> WriterEmitter<W>
+where
+    W: Write + Send + Sync + 'static,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            newline_delimited: true,
+        }
+    }
+
+    /// Whether to follow each payload with a newline, eg: for JSON lines. Disable it
+    /// to write payloads back to back, as is.
+    pub fn newline_delimited(mut self, newline_delimited: bool) -> Self {
+        self.newline_delimited = newline_delimited;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[async_trait]
+impl<W> EventEmitter for WriterEmitter<W>
+where
+    W: Write + Send + Sync + 'static,
+{
+    type Event = Vec<u8>;
+    type Error = std::io::Error;
+
+    #[tracing::instrument(skip(self, events))]
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        for event in events {
+            self.writer.write_all(&event)?;
+            if self.newline_delimited {
+                self.writer.write_all(b"\n")?;
+            }
+        }
+
+        self.writer.flush()
+    }
+}
+
+/// Writes emitted payloads, as is, to any `AsyncWrite`, eg: a socket. Serializers
+/// that override `serialize_to` can stream into it with `serialize_into`.
+pub struct AsyncWriterEmitter<W>
+where
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    writer: W,
+}
+
+impl<W> AsyncWriterEmitter<W>
+where
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Serializes `completed_events` straight into the writer, skipping the
+    /// intermediate payloads for serializers that override `serialize_to`.
+    pub async fn serialize_into<CP>(
+        &mut self,
+        serializer: &mut CP,
+        completed_events: &[CP::CompletedEvent],
+    ) -> Result<(), SerializeToError<CP::Error>>
+    where
+        CP: CompletionEventSerializer + Send,
+        CP::CompletedEvent: Sync,
+        CP::Output: AsRef<[u8]> + Send,
+        CP::Error: std::fmt::Debug,
+    {
+        serializer
+            .serialize_to(completed_events, &mut self.writer)
+            .await
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[async_trait]
+impl<W> EventEmitter for AsyncWriterEmitter<W>
+where
+    W: AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    type Event = Vec<u8>;
+    type Error = std::io::Error;
+
+    #[tracing::instrument(skip(self, events))]
+    async fn emit_event(&mut self, events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        for event in events {
+            self.writer.write_all(&event).await?;
+        }
+
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use super::*;
+
+    /// Records each write separately, so tests can see how output was chunked.
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Writes each event as it goes, and refuses to build the whole batch in memory.
+    struct StreamingSerializer;
+
+    #[async_trait]
+    impl CompletionEventSerializer for StreamingSerializer {
+        type CompletedEvent = String;
+        type Output = Vec<u8>;
+        type Error = String;
+
+        fn serialize_completed_events(
+            &mut self,
+            _completed_events: &[String],
+        ) -> Result<Vec<Vec<u8>>, String> {
+            Err("serialized the whole batch".to_owned())
+        }
+
+        async fn serialize_to<W>(
+            &mut self,
+            completed_events: &[String],
+            writer: &mut W,
+        ) -> Result<(), SerializeToError<String>>
+        where
+            W: AsyncWrite + Unpin + Send,
+        {
+            for event in completed_events {
+                writer.write_all(event.as_bytes()).await?;
+            }
+            writer.flush().await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn streaming_serializers_write_incrementally() {
+        let mut emitter = AsyncWriterEmitter::new(CountingWriter::default());
+        let events = vec!["a".to_owned(), "bb".to_owned(), "ccc".to_owned()];
+
+        emitter
+            .serialize_into(&mut StreamingSerializer, &events)
+            .await
+            .unwrap();
+
+        let writer = emitter.into_inner();
+        assert_eq!(writer.writes, vec![b"a".to_vec(), b"bb".to_vec(), b"ccc".to_vec()]);
+        assert_eq!(writer.flushes, 1);
+    }
+
+    #[tokio::test]
+    async fn emitted_payloads_are_newline_delimited_by_default() {
+        let mut emitter = WriterEmitter::new(Vec::new());
+
+        emitter
+            .emit_event(vec![b"{\"a\":1}".to_vec(), b"{\"b\":2}".to_vec()])
+            .await
+            .unwrap();
+
+        assert_eq!(emitter.into_inner(), b"{\"a\":1}\n{\"b\":2}\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn newline_delimiting_can_be_disabled() {
+        let mut emitter = WriterEmitter::new(Vec::new()).newline_delimited(false);
+
+        emitter.emit_event(vec![b"a".to_vec(), b"b".to_vec()]).await.unwrap();
+
+        assert_eq!(emitter.into_inner(), b"ab".to_vec());
+    }
+
+    #[tokio::test]
+    async fn async_writers_get_payloads_as_is() {
+        let mut emitter = AsyncWriterEmitter::new(CountingWriter::default());
+
+        emitter.emit_event(vec![b"a".to_vec()]).await.unwrap();
+        emitter.emit_event(vec![b"b".to_vec(), b"c".to_vec()]).await.unwrap();
+
+        let writer = emitter.into_inner();
+        assert_eq!(writer.writes.concat(), b"abc".to_vec());
+        // Flushed once per emit
+        assert_eq!(writer.flushes, 2);
+    }
+}
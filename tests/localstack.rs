@@ -0,0 +1,141 @@
+//! Runs the completion handler against LocalStack. Ignored by default, run with eg:
+//! `LOCALSTACK_ENDPOINT=http://localhost:4566 cargo test --features localstack -- --ignored`
+#![cfg(feature = "localstack")]
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::{HttpClient, Region};
+use rusoto_sqs::{
+    CreateQueueRequest, GetQueueAttributesRequest, ReceiveMessageRequest, SendMessageRequest,
+    Sqs, SqsClient,
+};
+
+use sqs_lambda::cache::NopCache;
+use sqs_lambda::completion_event_serializer::CompletionEventSerializer;
+use sqs_lambda::event_emitter::EventEmitter;
+use sqs_lambda::event_handler::{Completion, OutputEvent};
+use sqs_lambda::sqs_completion_handler::{
+    CompletionPolicy, ShutdownSummary, SqsCompletionHandler, SqsCompletionHandlerActor,
+};
+
+struct BytesSerializer;
+
+impl CompletionEventSerializer for BytesSerializer {
+    type CompletedEvent = String;
+    type Output = Vec<u8>;
+    type Error = String;
+
+    fn serialize_completed_events(
+        &mut self,
+        completed_events: &[Self::CompletedEvent],
+    ) -> Result<Vec<Self::Output>, Self::Error> {
+        Ok(completed_events
+            .iter()
+            .map(|event| event.clone().into_bytes())
+            .collect())
+    }
+}
+
+struct NopEmitter;
+
+#[async_trait]
+impl EventEmitter for NopEmitter {
+    type Event = Vec<u8>;
+    type Error = String;
+
+    async fn emit_event(&mut self, _completed_events: Vec<Self::Event>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn localstack_client(endpoint: &str) -> SqsClient {
+    SqsClient::new_with(
+        HttpClient::new().expect("Failed to create HttpClient"),
+        StaticProvider::new_minimal("localstack".to_owned(), "localstack".to_owned()),
+        Region::Custom {
+            name: "us-east-1".to_owned(),
+            endpoint: endpoint.to_owned(),
+        },
+    )
+}
+
+async fn queue_depth(sqs: &SqsClient, queue_url: &str) -> HashMap<String, String> {
+    sqs.get_queue_attributes(GetQueueAttributesRequest {
+        queue_url: queue_url.to_owned(),
+        attribute_names: Some(vec![
+            "ApproximateNumberOfMessages".to_owned(),
+            "ApproximateNumberOfMessagesNotVisible".to_owned(),
+        ]),
+    })
+    .await
+    .expect("Failed to get queue attributes")
+    .attributes
+    .unwrap_or_default()
+}
+
+#[tokio::test]
+#[ignore]
+async fn completed_messages_are_deleted_from_localstack() {
+    let endpoint = match std::env::var("LOCALSTACK_ENDPOINT") {
+        Ok(endpoint) => endpoint,
+        Err(_) => return,
+    };
+
+    let sqs = localstack_client(&endpoint);
+    let queue_url = sqs
+        .create_queue(CreateQueueRequest {
+            queue_name: format!("sqs-lambda-{}", uuid::Uuid::new_v4()),
+            ..CreateQueueRequest::default()
+        })
+        .await
+        .expect("Failed to create queue")
+        .queue_url
+        .expect("Created queue has no url");
+
+    sqs.send_message(SendMessageRequest {
+        queue_url: queue_url.clone(),
+        message_body: "event".to_owned(),
+        ..SendMessageRequest::default()
+    })
+    .await
+    .expect("Failed to send message");
+
+    let received = sqs
+        .receive_message(ReceiveMessageRequest {
+            queue_url: queue_url.clone(),
+            wait_time_seconds: Some(5),
+            ..ReceiveMessageRequest::default()
+        })
+        .await
+        .expect("Failed to receive message")
+        .messages
+        .unwrap_or_default();
+    assert_eq!(received.len(), 1);
+
+    let handler = SqsCompletionHandler::new_localstack(
+        endpoint,
+        queue_url.clone(),
+        BytesSerializer,
+        NopEmitter,
+        CompletionPolicy::new(10, Duration::from_secs(60)),
+        |_: SqsCompletionHandlerActor<String, String, SqsClient>, _| {},
+        NopCache {},
+    );
+    let (actor, _) = SqsCompletionHandlerActor::new(handler);
+
+    actor
+        .mark_complete(
+            received[0].clone(),
+            OutputEvent::new(Completion::Total("event".to_owned())),
+        )
+        .await
+        .expect("Handler is gone");
+    assert_eq!(actor.shutdown().await.expect("Handler is gone"), ShutdownSummary::Flushed);
+
+    let depth = queue_depth(&sqs, &queue_url).await;
+    assert_eq!(depth["ApproximateNumberOfMessages"], "0");
+    assert_eq!(depth["ApproximateNumberOfMessagesNotVisible"], "0");
+}